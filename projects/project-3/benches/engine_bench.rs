@@ -1,8 +1,15 @@
+use async_kvs::thread_pool::RayonThreadPool;
+use async_kvs::{BTreeKvsEngine, KvsEngine as AsyncKvsEngine};
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
 use sled;
 use tempfile::TempDir;
+use tokio::prelude::*;
+
+fn open_btree(temp_dir: &TempDir) -> BTreeKvsEngine<RayonThreadPool> {
+    BTreeKvsEngine::open(temp_dir.path(), 4).unwrap()
+}
 
 fn set_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
@@ -34,6 +41,23 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    group.bench_function("btree", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (open_btree(&temp_dir), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store
+                        .set(format!("key{}", i), "value".to_string())
+                        .wait()
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
@@ -70,6 +94,25 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    for i in &vec![8, 12, 16, 20] {
+        group.bench_with_input(format!("btree_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = open_btree(&temp_dir);
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .wait()
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .wait()
+                    .unwrap();
+            })
+        });
+    }
     group.finish();
 }
 
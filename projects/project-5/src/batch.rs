@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+/// A batch of `set` and `remove` operations which is applied atomically.
+///
+/// The operations are applied in the order they are added. Removing a key that does not
+/// exist is not an error in a batch.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty `WriteBatch`.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a key to the batch.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Adds removing a key to the batch.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Adds setting the value of a string key to a string to the batch.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Adds removing a string key to the batch.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
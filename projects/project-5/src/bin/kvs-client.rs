@@ -1,7 +1,10 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{EngineStats, KvsClient, KvsError, Result, WatchEvent};
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::prelude::*;
 
@@ -23,6 +26,12 @@ enum Command {
     Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
+        #[structopt(
+            long,
+            help = "Sets the keyspace, instead of the default one",
+            value_name = "NAME"
+        )]
+        keyspace: Option<String>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -38,6 +47,18 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
+        #[structopt(
+            long,
+            help = "Expires the key after the given number of seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+        #[structopt(
+            long,
+            help = "Sets the keyspace, instead of the default one",
+            value_name = "NAME"
+        )]
+        keyspace: Option<String>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -51,6 +72,148 @@ enum Command {
     Remove {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
+        #[structopt(
+            long,
+            help = "Sets the keyspace, instead of the default one",
+            value_name = "NAME"
+        )]
+        keyspace: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the key/value pairs in a key range in key order"
+    )]
+    Scan {
+        #[structopt(long, help = "The first key of the range", value_name = "KEY")]
+        start: Option<String>,
+        #[structopt(
+            long,
+            help = "The key at which the range ends, exclusive",
+            value_name = "KEY"
+        )]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Lists the keys starting with the prefix instead of a range",
+            value_name = "PREFIX",
+            raw(conflicts_with_all = r#"&["start", "end"]"#)
+        )]
+        prefix: Option<String>,
+        #[structopt(long, help = "The maximum number of pairs to list", value_name = "N")]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            help = "Sets the keyspace, instead of the default one",
+            value_name = "NAME"
+        )]
+        keyspace: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "backup",
+        about = "Write a consistent copy of the store to a directory on the server"
+    )]
+    Backup {
+        #[structopt(
+            name = "DIR",
+            help = "An empty directory, relative to the backup directory of the server",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print the changes of the keys starting with a prefix as they are made"
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the keyspace, instead of the default one",
+            value_name = "NAME"
+        )]
+        keyspace: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Print the statistics of a keyspace of the store"
+    )]
+    Stats {
+        #[structopt(
+            long,
+            help = "Sets the keyspace, instead of the default one",
+            value_name = "NAME"
+        )]
+        keyspace: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "create-keyspace", about = "Create an empty keyspace")]
+    CreateKeyspace {
+        #[structopt(name = "NAME", help = "The name of the keyspace")]
+        name: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "drop-keyspace", about = "Drop a keyspace with all its keys")]
+    DropKeyspace {
+        #[structopt(name = "NAME", help = "The name of the keyspace")]
+        name: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "list-keyspaces", about = "List the names of the keyspaces")]
+    ListKeyspaces {
         #[structopt(
             long,
             help = "Sets the server address",
@@ -72,24 +235,163 @@ fn main() {
 
 fn run(opt: Opt) -> Result<()> {
     match opt.command {
-        Command::Get { key, addr } => {
-            let client = KvsClient::connect(addr);
+        Command::Get {
+            key,
+            keyspace,
+            addr,
+        } => {
+            let client = connect(addr, keyspace);
             if let (Some(value), _) = client.and_then(move |client| client.get(key)).wait()? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            keyspace,
+            addr,
+        } => {
+            let client = connect(addr, keyspace);
+            match ttl {
+                Some(secs) => client
+                    .and_then(move |client| {
+                        let ttl = Duration::from_secs(secs);
+                        client.set_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+                    })
+                    .wait()?,
+                None => client
+                    .and_then(move |client| client.set(key, value))
+                    .wait()?,
+            };
+        }
+        Command::Remove {
+            key,
+            keyspace,
+            addr,
+        } => {
+            let client = connect(addr, keyspace);
+            client.and_then(move |client| client.remove(key)).wait()?;
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            keyspace,
+            addr,
+        } => {
+            let client = connect(addr, keyspace);
+            let (pairs, _) = if let Some(prefix) = prefix {
+                client
+                    .and_then(move |client| client.scan_prefix(prefix.into_bytes(), limit))
+                    .wait()?
+            } else {
+                let range = (
+                    start.map_or(Bound::Unbounded, |key| Bound::Included(key.into_bytes())),
+                    end.map_or(Bound::Unbounded, |key| Bound::Excluded(key.into_bytes())),
+                );
+                client
+                    .and_then(move |client| client.scan(range, limit))
+                    .wait()?
+            };
+            for (key, value) in pairs {
+                println!(
+                    "{}\t{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+        }
+        Command::Backup { dir, addr } => {
             let client = KvsClient::connect(addr);
             client
-                .and_then(move |client| client.set(key, value))
+                .and_then(move |client| client.checkpoint(dir))
                 .wait()?;
         }
-        Command::Remove { key, addr } => {
+        Command::Watch {
+            prefix,
+            keyspace,
+            addr,
+        } => {
+            let client = connect(addr, keyspace);
+            client
+                .and_then(move |client| client.watch(prefix.into_bytes()))
+                .flatten_stream()
+                .for_each(|event| {
+                    match event {
+                        WatchEvent::Set { key, value } => println!(
+                            "set\t{}\t{}",
+                            String::from_utf8_lossy(&key),
+                            String::from_utf8_lossy(&value)
+                        ),
+                        WatchEvent::Remove { key } => {
+                            println!("rm\t{}", String::from_utf8_lossy(&key))
+                        }
+                    }
+                    Ok(())
+                })
+                .wait()?;
+        }
+        Command::Stats { keyspace, addr } => {
+            let client = connect(addr, keyspace);
+            let (stats, _) = client.and_then(|client| client.stats()).wait()?;
+            print_stats(&stats);
+        }
+        Command::CreateKeyspace { name, addr } => {
             let client = KvsClient::connect(addr);
-            client.and_then(move |client| client.remove(key)).wait()?;
+            client
+                .and_then(move |client| client.create_keyspace(name))
+                .wait()?;
+        }
+        Command::DropKeyspace { name, addr } => {
+            let client = KvsClient::connect(addr);
+            client
+                .and_then(move |client| client.drop_keyspace(name))
+                .wait()?;
+        }
+        Command::ListKeyspaces { addr } => {
+            let client = KvsClient::connect(addr);
+            let (names, _) = client.and_then(|client| client.list_keyspaces()).wait()?;
+            for name in names {
+                println!("{}", name);
+            }
         }
     }
     Ok(())
 }
+
+/// Connects to the server, making the client work on `keyspace` if it is given.
+fn connect(
+    addr: SocketAddr,
+    keyspace: Option<String>,
+) -> impl Future<Item = KvsClient, Error = KvsError> {
+    KvsClient::connect(addr).map(move |client| match keyspace {
+        Some(name) => client.use_keyspace(name),
+        None => client,
+    })
+}
+
+/// Prints the statistics the engine of the server can tell, one per line.
+fn print_stats(stats: &EngineStats) {
+    println!("keys: {}", stats.keys);
+    let counts = [
+        ("live bytes", stats.live_bytes),
+        ("uncompacted bytes", stats.uncompacted_bytes),
+        ("generations", stats.generations),
+        ("compactions", stats.compactions),
+    ];
+    for (name, value) in counts.iter() {
+        if let Some(value) = value {
+            println!("{}: {}", name, value);
+        }
+    }
+    if let Some(duration) = stats.last_compaction {
+        println!("last compaction: {} ms", duration.as_millis());
+    }
+    if let Some(size) = stats.disk_size {
+        println!("disk size: {}", size);
+    }
+}
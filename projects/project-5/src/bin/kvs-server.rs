@@ -4,20 +4,26 @@ extern crate log;
 extern crate clap;
 
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    BTreeKvsEngine, BTreeOptions, CompactionTrigger, Durability, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, KvsServer, LsmKvsEngine, LsmOptions, MemoryKvsEngine, MemoryOptions,
+    Result, SledKvsEngine,
+};
 use log::LevelFilter;
 use std::env;
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "kvs-server")]
+#[structopt(name = "kvs-server", rename_all = "kebab-case")]
 struct Opt {
     #[structopt(
         long,
@@ -34,6 +40,84 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Compacts the kvs engine when stale data takes more than the given bytes",
+        value_name = "BYTES"
+    )]
+    compaction_bytes: Option<u64>,
+    #[structopt(
+        long,
+        help = "Compacts the kvs engine when stale data exceeds the given ratio of live data",
+        value_name = "RATIO"
+    )]
+    compaction_ratio: Option<f64>,
+    #[structopt(
+        long,
+        help = "Rolls the kvs engine over to a new log file at the given size",
+        value_name = "BYTES"
+    )]
+    max_log_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Merges the kvs engine log files whose garbage exceeds the given ratio",
+        value_name = "RATIO"
+    )]
+    merge_threshold: Option<f64>,
+    #[structopt(
+        long,
+        help = "Sets how many readers of the kvs engine keep their files open",
+        value_name = "COUNT"
+    )]
+    reader_pool_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Caches up to the given bytes of values read by the kvs engine",
+        value_name = "BYTES"
+    )]
+    value_cache_size: Option<u64>,
+    #[structopt(long, help = "Compacts the kvs engine when the server starts")]
+    compact_on_open: bool,
+    #[structopt(
+        long,
+        help = "Flushes the memtable of the lsm engine to a table at the given size",
+        value_name = "BYTES"
+    )]
+    memtable_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Caches up to the given bytes of pages of the btree engine",
+        value_name = "BYTES"
+    )]
+    page_cache_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Snapshots the memory engine to disk at the given interval and loads the \
+                snapshot when the server starts",
+        value_name = "MILLISECONDS"
+    )]
+    snapshot_interval: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets when writes are synced to disk",
+        value_name = "MODE",
+        raw(possible_values = "&DurabilityMode::variants()")
+    )]
+    durability: Option<DurabilityMode>,
+    #[structopt(
+        long,
+        help = "Sets the sync interval of the periodic durability mode",
+        value_name = "MILLISECONDS",
+        default_value = "1000"
+    )]
+    sync_interval: u64,
+    #[structopt(
+        long,
+        help = "Lets clients write backups to subdirectories of the given directory",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
 }
 
 arg_enum! {
@@ -41,7 +125,21 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        btree,
+        memory
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum DurabilityMode {
+        none,
+        every_write,
+        periodic,
+        group_commit
     }
 }
 
@@ -76,22 +174,126 @@ fn run(opt: Opt) -> Result<()> {
     let concurrency = num_cpus::get() as u32;
     match engine {
         Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
-            opt.addr,
+            KvStore::<RayonThreadPool>::open_with(
+                env::current_dir()?,
+                concurrency,
+                kvs_options(&opt)?,
+            )?,
+            &opt,
         ),
         Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
+            SledKvsEngine::<RayonThreadPool>::with_durability(
                 sled::Db::start_default(env::current_dir()?)?,
                 concurrency,
+                durability(&opt).unwrap_or(Durability::EveryWrite),
+            )?,
+            &opt,
+        ),
+        Engine::lsm => run_with(
+            LsmKvsEngine::<RayonThreadPool>::open_with(
+                env::current_dir()?,
+                concurrency,
+                lsm_options(&opt),
+            )?,
+            &opt,
+        ),
+        Engine::btree => run_with(
+            BTreeKvsEngine::<RayonThreadPool>::open_with(
+                env::current_dir()?,
+                concurrency,
+                btree_options(&opt),
             )?,
-            opt.addr,
+            &opt,
         ),
+        Engine::memory => match opt.snapshot_interval {
+            Some(interval) => run_with(
+                MemoryKvsEngine::<RayonThreadPool>::open_with(
+                    env::current_dir()?,
+                    concurrency,
+                    MemoryOptions::new().snapshot_interval(Duration::from_millis(interval)),
+                )?,
+                &opt,
+            ),
+            None => run_with(MemoryKvsEngine::<RayonThreadPool>::new(concurrency)?, &opt),
+        },
+    }
+}
+
+/// Builds the options of the kvs engine from the command line.
+fn kvs_options(opt: &Opt) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new().compact_on_open(opt.compact_on_open);
+    match (opt.compaction_bytes, opt.compaction_ratio) {
+        (Some(_), Some(_)) => {
+            return Err(KvsError::StringError(
+                "--compaction-bytes and --compaction-ratio cannot be used together".to_owned(),
+            ));
+        }
+        (Some(bytes), None) => {
+            options = options.compaction_trigger(CompactionTrigger::StaleBytes(bytes));
+        }
+        (None, Some(ratio)) => {
+            options = options.compaction_trigger(CompactionTrigger::StaleRatio(ratio));
+        }
+        (None, None) => {}
+    }
+    if let Some(size) = opt.max_log_size {
+        options = options.max_log_size(size);
+    }
+    if let Some(ratio) = opt.merge_threshold {
+        options = options.merge_threshold(ratio);
+    }
+    if let Some(size) = opt.reader_pool_size {
+        options = options.reader_pool_size(size);
+    }
+    if let Some(size) = opt.value_cache_size {
+        options = options.value_cache_size(size);
+    }
+    if let Some(durability) = durability(opt) {
+        options = options.durability(durability);
+    }
+    Ok(options)
+}
+
+/// Builds the options of the lsm engine from the command line.
+fn lsm_options(opt: &Opt) -> LsmOptions {
+    let mut options = LsmOptions::new();
+    if let Some(size) = opt.memtable_size {
+        options = options.memtable_size(size);
+    }
+    if let Some(durability) = durability(opt) {
+        options = options.durability(durability);
     }
+    options
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+/// Builds the options of the btree engine from the command line.
+fn btree_options(opt: &Opt) -> BTreeOptions {
+    let mut options = BTreeOptions::new();
+    if let Some(size) = opt.page_cache_size {
+        options = options.cache_size(size);
+    }
+    if let Some(durability) = durability(opt) {
+        options = options.durability(durability);
+    }
+    options
+}
+
+/// Returns the durability mode given on the command line.
+fn durability(opt: &Opt) -> Option<Durability> {
+    opt.durability.map(|mode| match mode {
+        DurabilityMode::none => Durability::None,
+        DurabilityMode::every_write => Durability::EveryWrite,
+        DurabilityMode::periodic => Durability::Periodic(Duration::from_millis(opt.sync_interval)),
+        DurabilityMode::group_commit => Durability::GroupCommit,
+    })
+}
+
+fn run_with<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+    if let Some(ref dir) = opt.backup_dir {
+        server = server.backup_dir(dir.clone());
+    }
+    server.run(opt.addr)
 }
 
 fn current_engine() -> Result<Option<Engine>> {
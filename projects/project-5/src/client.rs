@@ -1,16 +1,25 @@
 use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::engines::{owned_range, prefix_range};
+use crate::{EngineStats, KvPairs, KvsError, Result, WatchEvent, WriteBatch};
 use std::net::SocketAddr;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::prelude::future::Loop;
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
 /// Key value store client
+///
+/// Its requests work on the default keyspace of the server unless another one is chosen
+/// with `use_keyspace`.
 pub struct KvsClient {
     read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
     write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
+    keyspace: Option<String>,
 }
 
 impl KvsClient {
@@ -26,14 +35,72 @@ impl KvsClient {
                 KvsClient {
                     read_json,
                     write_json,
+                    keyspace: None,
                 }
             })
             .map_err(|e| e.into())
     }
 
-    /// Get the value of a given key from the server.
+    /// Make the requests of the client work on the keyspace `name`.
+    pub fn use_keyspace(mut self, name: String) -> Self {
+        self.keyspace = Some(name);
+        self
+    }
+
+    /// Get the string value of a given string key from the server.
     pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
-        self.send_request(Request::Get { key })
+        self.get_bytes(key.into_bytes()).and_then(
+            |(value, client)| -> Result<(Option<String>, KvsClient)> {
+                Ok((value.map(String::from_utf8).transpose()?, client))
+            },
+        )
+    }
+
+    /// Set the value of a string key in the server.
+    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove a string key in the server.
+    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Set the value of a string key to `new` in the server if its current value equals
+    /// `expected`.
+    ///
+    /// It fails with `KvsError::ConditionFailed` carrying the current value otherwise.
+    pub fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set the value of a string key in the server only if it does not exist.
+    ///
+    /// It fails with `KvsError::ConditionFailed` carrying the current value otherwise.
+    pub fn set_if_absent(
+        self,
+        key: String,
+        value: String,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.compare_and_swap_bytes(key.into_bytes(), None, Some(value.into_bytes()))
+    }
+
+    /// Get the value of a given key from the server.
+    pub fn get_bytes(
+        self,
+        key: Vec<u8>,
+    ) -> impl Future<Item = (Option<Vec<u8>>, Self), Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Get { keyspace, key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Get(value)) => Ok((value, client)),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
@@ -42,20 +109,88 @@ impl KvsClient {
             })
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Set { key, value })
+    /// Set the value of a key in the server.
+    pub fn set_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_set(key, value, None)
+    }
+
+    /// Set the value of a key which expires after `ttl` in the server.
+    pub fn set_with_ttl(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_set(key, value, Some(ttl))
+    }
+
+    fn send_set(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Set {
+            keyspace,
+            key,
+            value,
+            ttl,
+        })
+        .and_then(move |(resp, client)| match resp {
+            Some(Response::Set) => Ok(client),
+            Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+            Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+            None => Err(KvsError::StringError("No response received".to_owned())),
+        })
+    }
+
+    /// Set the value of a key to `new` in the server if its current value equals
+    /// `expected`. `None` stands for an absent key.
+    ///
+    /// It fails with `KvsError::ConditionFailed` carrying the current value otherwise.
+    pub fn compare_and_swap_bytes(
+        self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::CompareAndSwap {
+            keyspace,
+            key,
+            expected,
+            new,
+        })
+        .and_then(move |(resp, client)| match resp {
+            Some(Response::CompareAndSwap) => Ok(client),
+            Some(Response::ConditionFailed(current)) => Err(KvsError::ConditionFailed(current)),
+            Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+            Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+            None => Err(KvsError::StringError("No response received".to_owned())),
+        })
+    }
+
+    /// Apply all the writes in `batch` atomically in the server.
+    pub fn write_batch(self, batch: WriteBatch) -> impl Future<Item = Self, Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Batch { keyspace, batch })
             .and_then(move |(resp, client)| match resp {
-                Some(Response::Set) => Ok(client),
+                Some(Response::Batch) => Ok(client),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
                 Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
                 None => Err(KvsError::StringError("No response received".to_owned())),
             })
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Remove { key })
+    /// Remove a key in the server.
+    pub fn remove_bytes(self, key: Vec<u8>) -> impl Future<Item = Self, Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Remove { keyspace, key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Remove) => Ok(client),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
@@ -64,11 +199,121 @@ impl KvsClient {
             })
     }
 
+    /// Get the key/value pairs whose keys are in the given range from the server,
+    /// in key order.
+    ///
+    /// At most `limit` pairs are returned if it is not `None`.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(
+        self,
+        range: R,
+        limit: Option<usize>,
+    ) -> impl Future<Item = (KvPairs, Self), Error = KvsError> {
+        let (start, end) = owned_range(&range);
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Scan {
+            keyspace,
+            start,
+            end,
+            limit,
+        })
+        .and_then(move |(resp, client)| match resp {
+            Some(Response::Scan(pairs)) => Ok((pairs, client)),
+            Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+            Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+            None => Err(KvsError::StringError("No response received".to_owned())),
+        })
+    }
+
+    /// Get the key/value pairs whose keys start with `prefix` from the server,
+    /// in key order.
+    ///
+    /// At most `limit` pairs are returned if it is not `None`.
+    pub fn scan_prefix(
+        self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = (KvPairs, Self), Error = KvsError> {
+        self.scan(prefix_range(prefix), limit)
+    }
+
+    /// Make the server write a consistent copy of its store to the directory `dir`.
+    ///
+    /// `dir` is a path on the server, relative to its backup directory. It cannot contain
+    /// `..` components.
+    pub fn checkpoint(self, dir: PathBuf) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Checkpoint { dir })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Checkpoint) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get the statistics of the keyspace from the server.
+    pub fn stats(self) -> impl Future<Item = (EngineStats, Self), Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Stats { keyspace })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Stats(stats)) => Ok((stats, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Watch the changes of the keys starting with `prefix` in the server.
+    ///
+    /// The connection is only used for the events until `Watch::unwatch` is called.
+    pub fn watch(self, prefix: Vec<u8>) -> impl Future<Item = Watch, Error = KvsError> {
+        let keyspace = self.keyspace.clone();
+        self.send_request(Request::Watch { keyspace, prefix })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Watch) => Ok(Watch { client }),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Create an empty keyspace in the server.
+    pub fn create_keyspace(self, name: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::CreateKeyspace { name })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::CreateKeyspace) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Drop a keyspace with all its keys in the server.
+    pub fn drop_keyspace(self, name: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::DropKeyspace { name })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::DropKeyspace) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get the names of the keyspaces of the server in name order.
+    pub fn list_keyspaces(self) -> impl Future<Item = (Vec<String>, Self), Error = KvsError> {
+        self.send_request(Request::ListKeyspaces)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::ListKeyspaces(names)) => Ok((names, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
     fn send_request(
         self,
         req: Request,
     ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
-        let read_json = self.read_json;
+        let (read_json, keyspace) = (self.read_json, self.keyspace);
         self.write_json
             .send(req)
             .and_then(move |write_json| {
@@ -78,6 +323,7 @@ impl KvsClient {
                         let client = KvsClient {
                             read_json,
                             write_json,
+                            keyspace,
                         };
                         (resp, client)
                     })
@@ -86,3 +332,58 @@ impl KvsClient {
             .map_err(|e| e.into())
     }
 }
+
+/// A stream of the changes of keys in the server, started by `KvsClient::watch`.
+pub struct Watch {
+    client: KvsClient,
+}
+
+impl Watch {
+    /// Stop watching and get the client back.
+    ///
+    /// The events sent before the server stops the watch are dropped.
+    pub fn unwatch(self) -> impl Future<Item = KvsClient, Error = KvsError> {
+        let KvsClient {
+            read_json,
+            write_json,
+            keyspace,
+        } = self.client;
+        write_json
+            .send(Request::Unwatch)
+            .map_err(KvsError::from)
+            .and_then(move |write_json| {
+                future::loop_fn(read_json, |read_json| {
+                    read_json
+                        .into_future()
+                        .map_err(|(err, _)| KvsError::from(err))
+                        .and_then(|(resp, read_json)| match resp {
+                            Some(Response::Event(_)) => Ok(Loop::Continue(read_json)),
+                            Some(Response::Unwatch) => Ok(Loop::Break(read_json)),
+                            Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                            Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                            None => Err(KvsError::StringError("No response received".to_owned())),
+                        })
+                })
+                .map(move |read_json| KvsClient {
+                    read_json,
+                    write_json,
+                    keyspace,
+                })
+            })
+    }
+}
+
+impl Stream for Watch {
+    type Item = WatchEvent;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<Option<WatchEvent>, KvsError> {
+        match self.client.read_json.poll()? {
+            Async::Ready(Some(Response::Event(event))) => Ok(Async::Ready(Some(event))),
+            Async::Ready(Some(Response::Err(msg))) => Err(KvsError::StringError(msg)),
+            Async::Ready(Some(_)) => Err(KvsError::StringError("Invalid response".to_owned())),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
@@ -1,16 +1,99 @@
+use crate::{EngineStats, KvPairs, WatchEvent, WriteBatch};
 use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::time::Duration;
 
+// `keyspace` names the keyspace a request works on, or is `None` for the default one
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        keyspace: Option<String>,
+        key: Vec<u8>,
+    },
+    Set {
+        keyspace: Option<String>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Remove {
+        keyspace: Option<String>,
+        key: Vec<u8>,
+    },
+    Scan {
+        keyspace: Option<String>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    CompareAndSwap {
+        keyspace: Option<String>,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    Batch {
+        keyspace: Option<String>,
+        batch: WriteBatch,
+    },
+    // a checkpoint written to a directory under the backup directory of the server
+    Checkpoint {
+        dir: PathBuf,
+    },
+    Stats {
+        keyspace: Option<String>,
+    },
+    // events are sent until `Unwatch` is received
+    Watch {
+        keyspace: Option<String>,
+        prefix: Vec<u8>,
+    },
+    Unwatch,
+    CreateKeyspace {
+        name: String,
+    },
+    DropKeyspace {
+        name: String,
+    },
+    ListKeyspaces,
+}
+
+impl Request {
+    /// Returns the name of the keyspace the request works on, if it is not the default one.
+    pub fn keyspace(&self) -> Option<&str> {
+        match self {
+            Request::Get { keyspace, .. }
+            | Request::Set { keyspace, .. }
+            | Request::Remove { keyspace, .. }
+            | Request::Scan { keyspace, .. }
+            | Request::CompareAndSwap { keyspace, .. }
+            | Request::Batch { keyspace, .. }
+            | Request::Stats { keyspace }
+            | Request::Watch { keyspace, .. } => keyspace.as_ref().map(String::as_str),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(Option<Vec<u8>>),
     Set,
     Remove,
+    Scan(KvPairs),
+    CompareAndSwap,
+    Batch,
+    Checkpoint,
+    Stats(EngineStats),
+    Watch,
+    Event(WatchEvent),
+    // no event follows it
+    Unwatch,
+    // the current value of the key when a conditional write is not applied
+    ConditionFailed(Option<Vec<u8>>),
+    CreateKeyspace,
+    DropKeyspace,
+    ListKeyspaces(Vec<String>),
     Err(String),
 }
@@ -0,0 +1,1687 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tokio::prelude::*;
+
+use super::keyspace::{
+    after_start, before_end, internal_key, keyspace_range, Catalog, KeyRange, Watchers,
+    KEYSPACE_ID_LEN,
+};
+use super::kvs::{le_u32, le_u64, lock_dir, read_keyspaces, write_keyspaces, DEFAULT_KEYSPACE_ID};
+use super::{
+    check_keyspace_name, create_checkpoint_dir, expiry_time, now_millis, owned_range, run_in_pool,
+    spawn_periodic, take, Durability, EngineStats, GroupCommit, KvPairs, KvsEngine, WatchEvent,
+    DEFAULT_KEYSPACE,
+};
+use crate::batch::BatchOp;
+use crate::thread_pool::ThreadPool;
+use crate::transaction::run_transaction;
+use crate::{KvsError, Result, Transaction, WriteBatch};
+
+const DATA_FILE: &str = "btree.db";
+
+const PAGE_SIZE: usize = 4096;
+// checksum (u32) of the rest of the page, page type (u8) and entry count (u16)
+const PAGE_HEADER_LEN: usize = 7;
+const PAGE_META: u8 = 1;
+const PAGE_LEAF: u8 = 2;
+const PAGE_BRANCH: u8 = 3;
+const PAGE_OVERFLOW: u8 = 4;
+
+// the first pages of the file are the meta pages, which are written in turns
+const META_PAGES: u64 = 2;
+const META_MAGIC: u64 = 0x6b76_735f_6274_7231;
+
+// Keys and inline values are limited so that an entry never takes more than a quarter of
+// a page, and the halves of a split page always fit in a page.
+const MAX_KEY_LEN: usize = 512;
+// longer values are stored in a chain of overflow pages
+const MAX_INLINE_VALUE_LEN: usize = 256;
+// an overflow page holds the next page of the chain (u64) and the data
+const OVERFLOW_DATA_LEN: usize = PAGE_SIZE - PAGE_HEADER_LEN - 8;
+
+// a node smaller than this is merged with a sibling if they fit in a page together
+const MIN_FILL: usize = PAGE_SIZE / 4;
+
+// flags of a leaf entry: it is followed by its expiry time (u64), and its value is in
+// overflow pages, given by the value length (u64) and the first page (u64) instead of
+// the value length (u16) and the value
+const ENTRY_EXPIRES: u8 = 1;
+const ENTRY_OVERFLOW: u8 = 2;
+
+// bytes of pages the page cache holds by default
+const CACHE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A B+tree storage engine.
+///
+/// The tree is stored in pages of 4 KiB in a single file. Leaves hold the keys with their
+/// values, and branches hold separator keys with the pages of their children. Values
+/// longer than 256 bytes are stored in chains of overflow pages, and keys are limited to
+/// 512 bytes.
+///
+/// Updates are copy-on-write: a commit writes the nodes it changes and their ancestors to
+/// free pages, and leaves the pages of the previous tree as they are. The new root is then
+/// recorded in one of the two meta pages at the start of the file. They are written in
+/// turns and checksummed, so if writing one is interrupted, the other one still holds the
+/// previous root. The pages freed by a commit are reused only once a later root is
+/// written.
+///
+/// With `Durability::EveryWrite` and `Durability::GroupCommit`, the pages of the tree are
+/// synced before the root is written and the meta page after it. With
+/// `Durability::Periodic`, only the periodic sync writes the root, so the writes since the
+/// last one are lost if the process crashes. With `Durability::None`, the root is written
+/// after every commit but nothing is synced, so a machine crash may leave the root
+/// pointing to pages that have not reached the disk.
+///
+/// Decoded nodes are kept in a page cache of the least recently used ones. A read works
+/// on the tree whose root is current when it starts, and the pages of that tree are not
+/// reused until it ends, so it never sees half of a commit and does not wait for one.
+/// Commits are serialized with each other.
+///
+/// Keys are prefixed with the id of their keyspace, so the keys of a keyspace are
+/// contiguous in the tree. Dropping a keyspace removes its keys in one commit.
+///
+/// Expired keys are skipped by reads, and their pages are reclaimed when they are
+/// overwritten or removed. The free pages are found by walking the tree when the file is
+/// opened.
+///
+/// ```rust
+/// # use kvs::{BTreeKvsEngine, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store: BTreeKvsEngine<RayonThreadPool> = BTreeKvsEngine::open(current_dir()?, 2)?;
+/// store.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = store.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct BTreeKvsEngine<P: ThreadPool> {
+    pool: P,
+    // id of the keyspace the handle works on
+    keyspace: u32,
+    shared: Arc<Shared>,
+    _guard: Arc<CloseGuard>,
+}
+
+impl<P: ThreadPool> BTreeKvsEngine<P> {
+    /// Opens a `BTreeKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if another process has the data directory
+    /// open.
+    ///
+    /// It returns `KvsError::CorruptedPage` if neither meta page is valid, or a page of the
+    /// tree fails its checksum.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        BTreeKvsEngine::open_with(path, concurrency, BTreeOptions::default())
+    }
+
+    /// Opens a `BTreeKvsEngine` with the given path and options.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: BTreeOptions,
+    ) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path, true)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.join(DATA_FILE))?;
+        if file.metadata()?.len() == 0 {
+            let meta = Meta {
+                generation: 0,
+                root: 0,
+                page_count: META_PAGES,
+            };
+            for slot in 0..META_PAGES {
+                write_page(&mut file, slot, &meta.encode())?;
+            }
+            file.sync_all()?;
+        }
+        let mut metas = Vec::new();
+        for slot in 0..META_PAGES {
+            if let Some(meta) = read_page(&mut file, slot)
+                .ok()
+                .and_then(|page| Meta::decode(&page))
+            {
+                metas.push((meta, slot));
+            }
+        }
+        let (meta, slot) = metas
+            .into_iter()
+            .max_by_key(|(meta, _)| meta.generation)
+            .ok_or(KvsError::CorruptedPage(0))?;
+        // the pages after the tree are left by commits whose root has not been written
+        file.set_len(meta.page_count * PAGE_SIZE as u64)?;
+
+        let (next_keyspace_id, names) = read_keyspaces(&path)?;
+        let shared = Arc::new(Shared {
+            path,
+            options: options.clone(),
+            sync_file: file.try_clone()?,
+            file: Mutex::new(file),
+            cache: Mutex::new(PageCache::new(
+                (options.cache_size / PAGE_SIZE as u64) as usize,
+            )),
+            tree: Mutex::new(Tree {
+                root: meta.root,
+                generation: meta.generation,
+                page_count: meta.page_count,
+                free: Vec::new(),
+                pending: Vec::new(),
+                readers: BTreeMap::new(),
+                written: meta.generation,
+            }),
+            writer: Mutex::new(()),
+            catalog: RwLock::new(Catalog {
+                next_id: next_keyspace_id,
+                names,
+            }),
+            watchers: Mutex::new(Watchers::default()),
+            meta: Mutex::new(META_PAGES - 1 - slot),
+            group_commit: GroupCommit::default(),
+        });
+
+        let mut used = HashSet::new();
+        shared.mark_used(meta.root, &mut used)?;
+        shared.tree.lock().unwrap().free = (META_PAGES..meta.page_count)
+            .rev()
+            .filter(|page| !used.contains(page))
+            .collect();
+
+        if let Durability::Periodic(interval) = options.durability {
+            spawn_periodic(Arc::downgrade(&shared), interval, "sync", |shared| {
+                shared.write_meta(true)
+            });
+        }
+        Ok(BTreeKvsEngine {
+            pool: P::new(concurrency)?,
+            keyspace: DEFAULT_KEYSPACE_ID,
+            _guard: Arc::new(CloseGuard {
+                shared: Arc::clone(&shared),
+                _lock: lock,
+            }),
+            shared,
+        })
+    }
+
+    /// Runs `f` on a transaction of the keyspace of the handle in the thread pool.
+    fn run_write<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut Txn) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || shared.write(keyspace, f))
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for BTreeKvsEngine<P> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |txn| txn.set(key, value, None))
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || shared.value(keyspace, &key))
+    }
+
+    /// Removes a given key.
+    ///
+    /// An expired key is removed too, but `KvsError::KeyNotFound` is returned for it.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let removed = self.run_write(move |txn| {
+            let entry = txn.remove(&key)?;
+            Ok(entry.map_or(false, |entry| !entry.expired(now_millis())))
+        });
+        Box::new(removed.and_then(|removed| {
+            if removed {
+                Ok(())
+            } else {
+                Err(KvsError::KeyNotFound)
+            }
+        }))
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = expiry_time(ttl);
+        self.run_write(move |txn| txn.set(key, value, Some(expires_at)))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |txn| {
+            let current = txn.value(&key)?;
+            if current != expected {
+                return Err(KvsError::ConditionFailed(current));
+            }
+            match new {
+                Some(value) => txn.set(key, value, None),
+                None => txn.remove(&key).map(|_| ()),
+            }
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |txn| txn.apply(batch))
+    }
+
+    /// Runs `f` in an optimistic transaction and returns its result.
+    ///
+    /// The keys the transaction has read are validated and its writes are applied in one
+    /// commit, so it is serialized with all other writes.
+    fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            let read = |key: &[u8]| shared.value(keyspace, key);
+            run_transaction(f, read, |reads, batch| {
+                shared.write(keyspace, |txn| {
+                    for (key, value) in reads {
+                        if txn.value(&key)? != value {
+                            return Ok(false);
+                        }
+                    }
+                    txn.apply(batch)?;
+                    Ok(true)
+                })
+            })
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        let range = owned_range(&range);
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            let snapshot = shared.snapshot();
+            shared.check_keyspace(keyspace)?;
+            let limit = limit.unwrap_or(usize::max_value());
+            let now = now_millis();
+            let mut pairs = Vec::new();
+            let range = keyspace_range(keyspace, range);
+            shared.visit(snapshot.root, &range, &mut |entry| {
+                if pairs.len() >= limit {
+                    return Ok(false);
+                }
+                if !entry.expired(now) {
+                    let value = read_value(&*shared, &entry.value)?;
+                    pairs.push((entry.key[KEYSPACE_ID_LEN..].to_vec(), value));
+                }
+                Ok(true)
+            })?;
+            Ok(pairs)
+        })
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`.
+    ///
+    /// The pages of the current tree are not reused while the file is copied, so writes go
+    /// on during the copy. Both meta pages of the copy hold the root of that tree.
+    fn checkpoint(&self, dest: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            create_checkpoint_dir(&dest)?;
+            let snapshot = shared.snapshot();
+            let catalog = shared.catalog.read().unwrap().clone();
+            let len = snapshot.page_count * PAGE_SIZE as u64;
+            let mut copy = File::create(dest.join(DATA_FILE))?;
+            // a file of its own, so that the copy does not hold the lock of the shared one
+            let file = File::open(shared.path.join(DATA_FILE))?;
+            io::copy(&mut file.take(len), &mut copy)?;
+            let meta = Meta {
+                generation: snapshot.generation,
+                root: snapshot.root,
+                page_count: snapshot.page_count,
+            };
+            for slot in 0..META_PAGES {
+                write_page(&mut copy, slot, &meta.encode())?;
+            }
+            // free pages at the end may not have been written yet
+            copy.set_len(len)?;
+            copy.sync_all()?;
+            write_keyspaces(&dest, catalog.next_id, &catalog.names)
+        })
+    }
+
+    /// Returns the size of the keyspace and of the file.
+    ///
+    /// The keys and live bytes are counted by scanning the keyspace.
+    fn stats(&self) -> Box<dyn Future<Item = EngineStats, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            let snapshot = shared.snapshot();
+            shared.check_keyspace(keyspace)?;
+            let now = now_millis();
+            let (mut keys, mut live_bytes) = (0, 0);
+            let range = keyspace_range(keyspace, (Bound::Unbounded, Bound::Unbounded));
+            shared.visit(snapshot.root, &range, &mut |entry| {
+                if !entry.expired(now) {
+                    keys += 1;
+                    live_bytes += (entry.key.len() - KEYSPACE_ID_LEN) as u64 + entry.value.len();
+                }
+                Ok(true)
+            })?;
+            let disk_size = shared.file.lock().unwrap().metadata()?.len();
+            Ok(EngineStats {
+                keys,
+                live_bytes: Some(live_bytes),
+                disk_size: Some(disk_size),
+                ..EngineStats::default()
+            })
+        })
+    }
+
+    /// Returns a stream of the changes of the keys starting with `prefix`.
+    ///
+    /// The events are sent by each commit, so they are in the order of the writes. Keys
+    /// that expire are not reported until they are removed. A watch that falls behind the
+    /// writes is ended with `KvsError::WatchLagged`.
+    fn watch(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send> {
+        // keyspaces are dropped by commits, which cannot run while the watch is added
+        let _writer = self.shared.writer.lock().unwrap();
+        if let Err(e) = self.shared.check_keyspace(self.keyspace) {
+            return Box::new(stream::once(Err(e)));
+        }
+        self.shared
+            .watchers
+            .lock()
+            .unwrap()
+            .add(self.keyspace, prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let keyspace = self
+            .shared
+            .catalog
+            .read()
+            .unwrap()
+            .id(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        Ok(BTreeKvsEngine {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            check_keyspace_name(&name)?;
+            let mut catalog = shared.catalog.write().unwrap();
+            if catalog.id(&name).is_some() {
+                return Err(KvsError::KeyspaceExists(name));
+            }
+            let keyspace = catalog.next_id;
+            let mut names = catalog.names.clone();
+            names.insert(keyspace, name);
+            write_keyspaces(&shared.path, keyspace + 1, &names)?;
+            *catalog = Catalog {
+                next_id: keyspace + 1,
+                names,
+            };
+            Ok(())
+        })
+    }
+
+    /// Drops a keyspace with all its keys.
+    ///
+    /// The keyspace is removed from the list of keyspaces first, so if removing its keys
+    /// fails, they are left in the tree but can no longer be reached.
+    fn drop_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            if name == DEFAULT_KEYSPACE {
+                return Err(KvsError::StringError(
+                    "The default keyspace cannot be dropped".to_owned(),
+                ));
+            }
+            let keyspace = shared
+                .catalog
+                .read()
+                .unwrap()
+                .id(&name)
+                .ok_or_else(|| KvsError::KeyspaceNotFound(name))?;
+            shared.write(keyspace, |txn| {
+                {
+                    let mut catalog = shared.catalog.write().unwrap();
+                    let mut names = catalog.names.clone();
+                    names.remove(&keyspace);
+                    write_keyspaces(&shared.path, catalog.next_id, &names)?;
+                    catalog.names = names;
+                }
+                shared.watchers.lock().unwrap().remove_keyspace(keyspace);
+
+                let mut keys = Vec::new();
+                let range = keyspace_range(keyspace, (Bound::Unbounded, Bound::Unbounded));
+                shared.visit(txn.root, &range, &mut |entry| {
+                    keys.push(entry.key[KEYSPACE_ID_LEN..].to_vec());
+                    Ok(true)
+                })?;
+                for key in keys {
+                    txn.remove(&key)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    fn list_keyspaces(&self) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            let catalog = shared.catalog.read().unwrap();
+            let mut names: Vec<String> = catalog.names.values().cloned().collect();
+            names.push(DEFAULT_KEYSPACE.to_owned());
+            names.sort();
+            Ok(names)
+        })
+    }
+}
+
+/// Options of a `BTreeKvsEngine`, set in the builder style.
+///
+/// ```rust
+/// # use kvs::BTreeOptions;
+/// let options = BTreeOptions::new().cache_size(64 * 1024 * 1024);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BTreeOptions {
+    cache_size: u64,
+    durability: Durability,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> BTreeOptions {
+        BTreeOptions {
+            cache_size: CACHE_SIZE,
+            durability: Durability::None,
+        }
+    }
+}
+
+impl BTreeOptions {
+    /// Creates the default options.
+    ///
+    /// The page cache holds up to 16 MiB of pages. Syncing the file is left to the
+    /// operating system.
+    pub fn new() -> BTreeOptions {
+        BTreeOptions::default()
+    }
+
+    /// Sets the bytes of pages the page cache holds.
+    pub fn cache_size(mut self, size: u64) -> BTreeOptions {
+        self.cache_size = size;
+        self
+    }
+
+    /// Sets when the writes are synced to disk.
+    pub fn durability(mut self, durability: Durability) -> BTreeOptions {
+        self.durability = durability;
+        self
+    }
+}
+
+/// The state shared by the handles of an engine.
+struct Shared {
+    // directory for the file of the tree and the list of keyspaces
+    path: PathBuf,
+    options: BTreeOptions,
+    file: Mutex<File>,
+    // a handle of the file for syncing without holding its lock
+    sync_file: File,
+    cache: Mutex<PageCache>,
+    tree: Mutex<Tree>,
+    // held by a commit from its first read to its end
+    writer: Mutex<()>,
+    catalog: RwLock<Catalog>,
+    watchers: Mutex<Watchers>,
+    // the meta page written next, held while it is written
+    meta: Mutex<u64>,
+    group_commit: GroupCommit,
+}
+
+impl Shared {
+    /// Returns `KvsError::KeyspaceDropped` if the keyspace has been dropped.
+    fn check_keyspace(&self, keyspace: u32) -> Result<()> {
+        if self.catalog.read().unwrap().contains(keyspace) {
+            Ok(())
+        } else {
+            Err(KvsError::KeyspaceDropped)
+        }
+    }
+
+    /// Returns the current tree for a read. Its pages are not reused until it is dropped.
+    fn snapshot(&self) -> Snapshot<'_> {
+        let mut tree = self.tree.lock().unwrap();
+        let generation = tree.generation;
+        *tree.readers.entry(generation).or_insert(0) += 1;
+        Snapshot {
+            shared: self,
+            root: tree.root,
+            generation,
+            page_count: tree.page_count,
+        }
+    }
+
+    /// Returns the value of a key of the keyspace, unless it has expired.
+    fn value(&self, keyspace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let snapshot = self.snapshot();
+        self.check_keyspace(keyspace)?;
+        live_value(self, snapshot.root, &internal_key(keyspace, key))
+    }
+
+    /// Calls `f` on the entries of a range of internal keys in the subtree at `page`, in
+    /// key order, until it returns `false`. Returns `false` if `f` has stopped it or the
+    /// end of the range is reached.
+    fn visit<F>(&self, page: u64, range: &KeyRange, f: &mut F) -> Result<bool>
+    where
+        F: FnMut(&LeafEntry) -> Result<bool>,
+    {
+        if page == 0 {
+            return Ok(true);
+        }
+        match &*self.node(page)? {
+            Node::Leaf(entries) => {
+                for entry in entries {
+                    if !after_start(&entry.key, &range.0) {
+                        continue;
+                    }
+                    if !before_end(&entry.key, &range.1) || !f(entry)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Node::Branch { keys, children } => {
+                let first = match &range.0 {
+                    Bound::Included(start) | Bound::Excluded(start) => child_index(keys, start),
+                    Bound::Unbounded => 0,
+                };
+                for &child in &children[first..] {
+                    if !self.visit(child, range, f)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// Adds the pages of the subtree at `page` to `used`.
+    fn mark_used(&self, page: u64, used: &mut HashSet<u64>) -> Result<()> {
+        if page == 0 {
+            return Ok(());
+        }
+        // a page reached twice means the tree is damaged
+        if !used.insert(page) {
+            return Err(KvsError::CorruptedPage(page));
+        }
+        match &*self.node(page)? {
+            Node::Branch { children, .. } => {
+                for &child in children {
+                    self.mark_used(child, used)?;
+                }
+            }
+            Node::Leaf(entries) => {
+                for entry in entries {
+                    if let Value::Overflow { len, page } = entry.value {
+                        let (chain, _) = read_overflow_value(self, page, len)?;
+                        for page in chain {
+                            if !used.insert(page) {
+                                return Err(KvsError::CorruptedPage(page));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on a transaction of the keyspace and commits it, unless `f` fails. The
+    /// root is then written as the durability mode requires.
+    fn write<F, T>(&self, keyspace: u32, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Txn) -> Result<T>,
+    {
+        let (res, ticket) = {
+            let _writer = self.writer.lock().unwrap();
+            self.check_keyspace(keyspace)?;
+            let mut txn = Txn::new(self, keyspace);
+            let res = f(&mut txn).and_then(|res| {
+                txn.write_pages()?;
+                Ok(res)
+            });
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    txn.abort();
+                    return Err(e);
+                }
+            };
+            if !txn.commit() {
+                return Ok(res);
+            }
+            (res, self.group_commit.record_write())
+        };
+        match self.options.durability {
+            Durability::None => self.write_meta(false)?,
+            Durability::EveryWrite => self.write_meta(true)?,
+            Durability::GroupCommit => {
+                self.group_commit.commit(ticket, || self.write_meta(true))?
+            }
+            // the root is written by the periodic sync
+            Durability::Periodic(_) => {}
+        }
+        Ok(res)
+    }
+
+    /// Writes the current root to the meta page not holding the last root written. If
+    /// `sync` is set, the pages of the tree are synced before, and the meta page after.
+    ///
+    /// The pages freed by the commits up to the root are free for reuse afterwards, once
+    /// no read works on an older tree.
+    fn write_meta(&self, sync: bool) -> Result<()> {
+        let mut next = self.meta.lock().unwrap();
+        let meta = {
+            let tree = self.tree.lock().unwrap();
+            if tree.generation == tree.written {
+                return Ok(());
+            }
+            Meta {
+                generation: tree.generation,
+                root: tree.root,
+                page_count: tree.page_count,
+            }
+        };
+        if sync {
+            self.sync_file.sync_data()?;
+        }
+        write_page(&mut self.file.lock().unwrap(), *next, &meta.encode())?;
+        if sync {
+            self.sync_file.sync_data()?;
+        }
+        *next = META_PAGES - 1 - *next;
+        let mut tree = self.tree.lock().unwrap();
+        tree.written = meta.generation;
+        tree.release();
+        Ok(())
+    }
+}
+
+/// The tree a read works on.
+struct Snapshot<'a> {
+    shared: &'a Shared,
+    root: u64,
+    generation: u64,
+    // number of pages of the file
+    page_count: u64,
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        let mut tree = self.shared.tree.lock().unwrap();
+        let readers = tree
+            .readers
+            .get_mut(&self.generation)
+            .expect("snapshot not counted");
+        *readers -= 1;
+        if *readers == 0 {
+            tree.readers.remove(&self.generation);
+            tree.release();
+        }
+    }
+}
+
+/// Writes the last root when the last handle of the engine is dropped, and holds the
+/// lock of the data directory until it is written.
+struct CloseGuard {
+    shared: Arc<Shared>,
+    _lock: File,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        // the last commits may not have their root written in the periodic mode
+        if let Err(e) = self.shared.write_meta(true) {
+            error!("Failed to write the root of the tree: {}", e);
+        }
+    }
+}
+
+/// Where the nodes and the overflow pages of a tree are read from.
+trait Pages {
+    /// Returns the node of a page.
+    fn node(&self, page: u64) -> Result<Arc<Node>>;
+
+    /// Returns the next page and the data of an overflow page.
+    fn overflow(&self, page: u64) -> Result<(u64, Vec<u8>)>;
+}
+
+impl Pages for Shared {
+    /// Returns the node of a page from the page cache, or reads it from the file.
+    fn node(&self, page: u64) -> Result<Arc<Node>> {
+        if let Some(node) = self.cache.lock().unwrap().get(page) {
+            return Ok(node);
+        }
+        let data = read_page(&mut self.file.lock().unwrap(), page)?;
+        let node = Arc::new(Node::decode(page, &data)?);
+        self.cache.lock().unwrap().insert(page, Arc::clone(&node));
+        Ok(node)
+    }
+
+    fn overflow(&self, page: u64) -> Result<(u64, Vec<u8>)> {
+        let data = read_page(&mut self.file.lock().unwrap(), page)?;
+        decode_overflow(page, &data)
+    }
+}
+
+/// The root of the tree and the allocation of its pages.
+struct Tree {
+    // 0 if the tree is empty
+    root: u64,
+    // number of commits
+    generation: u64,
+    // number of pages of the file
+    page_count: u64,
+    free: Vec<u64>,
+    // the pages freed by the commits whose root has not been written yet, or which are
+    // still in the trees of reads, with their generations
+    pending: Vec<(u64, Vec<u64>)>,
+    // number of reads working on the tree of each generation
+    readers: BTreeMap<u64, usize>,
+    // generation of the last root written
+    written: u64,
+}
+
+impl Tree {
+    /// Frees the pages freed by the commits up to the last root written for reuse, unless
+    /// a read works on a tree before them.
+    fn release(&mut self) {
+        let oldest_read = self.readers.keys().next().cloned();
+        let last = cmp::min(self.written, oldest_read.unwrap_or(u64::max_value()));
+        while let Some(&(freed_by, _)) = self.pending.first() {
+            if freed_by > last {
+                break;
+            }
+            let (_, pages) = self.pending.remove(0);
+            self.free.extend(pages);
+        }
+    }
+}
+
+/// The content of a meta page.
+struct Meta {
+    generation: u64,
+    root: u64,
+    page_count: u64,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for field in &[META_MAGIC, self.generation, self.root, self.page_count] {
+            body.extend_from_slice(&field.to_le_bytes());
+        }
+        encode_page(PAGE_META, 0, &body)
+    }
+
+    /// Decodes a page whose checksum has been checked, or returns `None` if it is not a
+    /// meta page.
+    fn decode(page: &[u8]) -> Option<Meta> {
+        let field = |i: usize| le_u64(&page[PAGE_HEADER_LEN + 8 * i..]);
+        if page[4] != PAGE_META || field(0) != META_MAGIC {
+            return None;
+        }
+        Some(Meta {
+            generation: field(1),
+            root: field(2),
+            page_count: field(3),
+        })
+    }
+}
+
+/// The first key of a node split off to the right and its page.
+type Split = (Vec<u8>, u64);
+
+/// The changes of a commit.
+///
+/// The nodes it changes are copied to pages it allocates, and the pages of the tree it
+/// replaces are freed once a later root is written. Pages it has allocated and freed
+/// itself can be reused at once.
+struct Txn<'a> {
+    shared: &'a Shared,
+    keyspace: u32,
+    // root of the tree the transaction started from
+    base: u64,
+    root: u64,
+    // nodes and overflow pages written by the transaction, by page
+    nodes: HashMap<u64, Arc<Node>>,
+    overflow_pages: HashMap<u64, Vec<u8>>,
+    // pages of the tree replaced by the transaction
+    freed: Vec<u64>,
+    // free pages of the tree allocated by the transaction
+    taken: Vec<u64>,
+    // pages allocated and freed by the transaction
+    reusable: Vec<u64>,
+    // number of pages of the file with the pages allocated by the transaction
+    page_count: u64,
+    events: Vec<WatchEvent>,
+}
+
+impl<'a> Txn<'a> {
+    /// Starts a transaction on the current tree. The caller holds the lock of the writer.
+    fn new(shared: &'a Shared, keyspace: u32) -> Txn<'a> {
+        let tree = shared.tree.lock().unwrap();
+        Txn {
+            shared,
+            keyspace,
+            base: tree.root,
+            root: tree.root,
+            page_count: tree.page_count,
+            nodes: HashMap::new(),
+            overflow_pages: HashMap::new(),
+            freed: Vec::new(),
+            taken: Vec::new(),
+            reusable: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Returns the value of a key of the keyspace, unless it has expired.
+    fn value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        live_value(self, self.root, &internal_key(self.keyspace, key))
+    }
+
+    /// Sets the value of a key of the keyspace.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::StringError(format!(
+                "Key of {} bytes exceeds the limit of {} bytes",
+                key.len(),
+                MAX_KEY_LEN
+            )));
+        }
+        if self.watches(&key) {
+            self.events.push(WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+        let entry = LeafEntry {
+            key: internal_key(self.keyspace, &key),
+            value: self.store_value(value),
+            expires_at,
+        };
+        if self.root == 0 {
+            self.root = self.add(Node::Leaf(vec![entry]));
+            return Ok(());
+        }
+        let (root, split) = self.insert_into(self.root, entry)?;
+        self.root = match split {
+            Some((key, right)) => self.add(Node::Branch {
+                keys: vec![key],
+                children: vec![root, right],
+            }),
+            None => root,
+        };
+        Ok(())
+    }
+
+    /// Removes a key of the keyspace and returns its entry, or `None` if it does not
+    /// exist.
+    fn remove(&mut self, key: &[u8]) -> Result<Option<LeafEntry>> {
+        if self.root == 0 {
+            return Ok(None);
+        }
+        let (entry, root) = match self.remove_from(self.root, &internal_key(self.keyspace, key))? {
+            Some(removed) => removed,
+            None => return Ok(None),
+        };
+        // a root branch left with a single child is replaced by the child
+        let mut root = root.unwrap_or(0);
+        while root != 0 {
+            let child = match &*self.node(root)? {
+                Node::Branch { children, .. } if children.len() == 1 => children[0],
+                _ => break,
+            };
+            self.free_page(root);
+            root = child;
+        }
+        self.root = root;
+        if self.watches(key) {
+            self.events.push(WatchEvent::Remove { key: key.to_vec() });
+        }
+        Ok(Some(entry))
+    }
+
+    fn apply(&mut self, batch: WriteBatch) -> Result<()> {
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => self.set(key, value, None)?,
+                BatchOp::Remove { key } => {
+                    self.remove(&key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn watches(&self, key: &[u8]) -> bool {
+        self.shared
+            .watchers
+            .lock()
+            .unwrap()
+            .watches(self.keyspace, key)
+    }
+
+    /// Inserts an entry into the subtree at `page`, replacing the entry of its key.
+    ///
+    /// Returns the new page of the subtree, with the first key and the page of its new
+    /// right sibling if it has been split.
+    fn insert_into(&mut self, page: u64, entry: LeafEntry) -> Result<(u64, Option<Split>)> {
+        let node = match Node::clone(&*self.node(page)?) {
+            Node::Leaf(mut entries) => {
+                match entries.binary_search_by(|other| other.key.cmp(&entry.key)) {
+                    Ok(i) => {
+                        let old = mem::replace(&mut entries[i], entry);
+                        self.free_value(&old.value)?;
+                    }
+                    Err(i) => entries.insert(i, entry),
+                }
+                Node::Leaf(entries)
+            }
+            Node::Branch {
+                mut keys,
+                mut children,
+            } => {
+                let i = child_index(&keys, &entry.key);
+                let (child, split) = self.insert_into(children[i], entry)?;
+                children[i] = child;
+                if let Some((key, right)) = split {
+                    keys.insert(i, key);
+                    children.insert(i + 1, right);
+                }
+                Node::Branch { keys, children }
+            }
+        };
+        if node.encoded_len() <= PAGE_SIZE {
+            return Ok((self.put(page, node), None));
+        }
+        let (left, key, right) = node.split();
+        let left = self.put(page, left);
+        Ok((left, Some((key, self.add(right)))))
+    }
+
+    /// Removes an internal key from the subtree at `page`.
+    ///
+    /// Returns the removed entry with the new page of the subtree, which is `None` if the
+    /// subtree has become empty, or `None` if the key does not exist.
+    fn remove_from(&mut self, page: u64, key: &[u8]) -> Result<Option<(LeafEntry, Option<u64>)>> {
+        match Node::clone(&*self.node(page)?) {
+            Node::Leaf(mut entries) => {
+                let i = match entries.binary_search_by(|entry| entry.key.as_slice().cmp(key)) {
+                    Ok(i) => i,
+                    Err(_) => return Ok(None),
+                };
+                let entry = entries.remove(i);
+                self.free_value(&entry.value)?;
+                if entries.is_empty() {
+                    self.free_page(page);
+                    return Ok(Some((entry, None)));
+                }
+                Ok(Some((entry, Some(self.put(page, Node::Leaf(entries))))))
+            }
+            Node::Branch {
+                mut keys,
+                mut children,
+            } => {
+                let i = child_index(&keys, key);
+                let (entry, child) = match self.remove_from(children[i], key)? {
+                    Some(removed) => removed,
+                    None => return Ok(None),
+                };
+                match child {
+                    Some(child) => {
+                        children[i] = child;
+                        self.merge_child(&mut keys, &mut children, i)?;
+                    }
+                    None => {
+                        children.remove(i);
+                        if !keys.is_empty() {
+                            keys.remove(i.saturating_sub(1));
+                        }
+                    }
+                }
+                if children.is_empty() {
+                    self.free_page(page);
+                    return Ok(Some((entry, None)));
+                }
+                let node = Node::Branch { keys, children };
+                Ok(Some((entry, Some(self.put(page, node)))))
+            }
+        }
+    }
+
+    /// Merges the child `i` of a branch with a sibling if it has shrunk below a quarter
+    /// of a page and they fit in a page together.
+    fn merge_child(
+        &mut self,
+        keys: &mut Vec<Vec<u8>>,
+        children: &mut Vec<u64>,
+        i: usize,
+    ) -> Result<()> {
+        if children.len() < 2 || self.node(children[i])?.encoded_len() >= MIN_FILL {
+            return Ok(());
+        }
+        let left = i.saturating_sub(1);
+        let merged = match (
+            Node::clone(&*self.node(children[left])?),
+            Node::clone(&*self.node(children[left + 1])?),
+        ) {
+            (Node::Leaf(mut entries), Node::Leaf(right)) => {
+                entries.extend(right);
+                Node::Leaf(entries)
+            }
+            (
+                Node::Branch {
+                    keys: mut left_keys,
+                    children: mut left_children,
+                },
+                Node::Branch {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            ) => {
+                left_keys.push(keys[left].clone());
+                left_keys.extend(right_keys);
+                left_children.extend(right_children);
+                Node::Branch {
+                    keys: left_keys,
+                    children: left_children,
+                }
+            }
+            // all the leaves are at the same depth
+            _ => return Err(KvsError::CorruptedPage(children[left])),
+        };
+        if merged.encoded_len() > PAGE_SIZE {
+            return Ok(());
+        }
+        let right = children.remove(left + 1);
+        keys.remove(left);
+        self.free_page(right);
+        children[left] = self.put(children[left], merged);
+        Ok(())
+    }
+
+    /// Returns how a value is stored, writing it to overflow pages if it is too long to
+    /// be inline.
+    fn store_value(&mut self, value: Vec<u8>) -> Value {
+        if value.len() <= MAX_INLINE_VALUE_LEN {
+            return Value::Inline(value);
+        }
+        let pages: Vec<u64> = value
+            .chunks(OVERFLOW_DATA_LEN)
+            .map(|_| self.alloc())
+            .collect();
+        for (i, chunk) in value.chunks(OVERFLOW_DATA_LEN).enumerate() {
+            let next = pages.get(i + 1).cloned().unwrap_or(0);
+            self.overflow_pages
+                .insert(pages[i], encode_overflow(next, chunk));
+        }
+        Value::Overflow {
+            len: value.len() as u64,
+            page: pages[0],
+        }
+    }
+
+    /// Frees the overflow pages of a replaced or removed value.
+    fn free_value(&mut self, value: &Value) -> Result<()> {
+        if let Value::Overflow { len, page } = *value {
+            let (chain, _) = read_overflow_value(self, page, len)?;
+            for page in chain {
+                self.free_page(page);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `node` in place of the node at `page` and returns its page. A node of the
+    /// tree is never overwritten, and the new one goes to another page.
+    fn put(&mut self, page: u64, node: Node) -> u64 {
+        if let Some(written) = self.nodes.get_mut(&page) {
+            *written = Arc::new(node);
+            return page;
+        }
+        self.freed.push(page);
+        self.add(node)
+    }
+
+    /// Writes `node` to a new page and returns it.
+    fn add(&mut self, node: Node) -> u64 {
+        let page = self.alloc();
+        self.nodes.insert(page, Arc::new(node));
+        page
+    }
+
+    fn alloc(&mut self) -> u64 {
+        if let Some(page) = self.reusable.pop() {
+            return page;
+        }
+        if let Some(page) = self.shared.tree.lock().unwrap().free.pop() {
+            self.taken.push(page);
+            return page;
+        }
+        self.page_count += 1;
+        self.page_count - 1
+    }
+
+    fn free_page(&mut self, page: u64) {
+        if self.nodes.remove(&page).is_some() || self.overflow_pages.remove(&page).is_some() {
+            self.reusable.push(page);
+        } else {
+            self.freed.push(page);
+        }
+    }
+
+    /// Writes the pages of the transaction to the file.
+    fn write_pages(&mut self) -> Result<()> {
+        let mut file = self.shared.file.lock().unwrap();
+        for (&page, node) in &self.nodes {
+            write_page(&mut file, page, &node.encode())?;
+        }
+        for (&page, data) in &self.overflow_pages {
+            write_page(&mut file, page, data)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the tree written by the transaction the current one and sends its events.
+    /// Returns whether it has changed anything.
+    fn commit(self) -> bool {
+        {
+            let mut watchers = self.shared.watchers.lock().unwrap();
+            for event in &self.events {
+                watchers.send(self.keyspace, event);
+            }
+        }
+        if self.root == self.base && self.nodes.is_empty() && self.freed.is_empty() {
+            self.abort();
+            return false;
+        }
+        {
+            let mut cache = self.shared.cache.lock().unwrap();
+            for &page in &self.freed {
+                cache.remove(page);
+            }
+            for (page, node) in self.nodes {
+                cache.insert(page, node);
+            }
+        }
+        let mut tree = self.shared.tree.lock().unwrap();
+        tree.root = self.root;
+        tree.generation += 1;
+        tree.page_count = self.page_count;
+        tree.free.extend(self.reusable);
+        let generation = tree.generation;
+        tree.pending.push((generation, self.freed));
+        true
+    }
+
+    /// Gives back the free pages taken by the transaction.
+    fn abort(self) {
+        self.shared.tree.lock().unwrap().free.extend(self.taken);
+    }
+}
+
+impl<'a> Pages for Txn<'a> {
+    fn node(&self, page: u64) -> Result<Arc<Node>> {
+        match self.nodes.get(&page) {
+            Some(node) => Ok(Arc::clone(node)),
+            None => self.shared.node(page),
+        }
+    }
+
+    fn overflow(&self, page: u64) -> Result<(u64, Vec<u8>)> {
+        match self.overflow_pages.get(&page) {
+            Some(data) => decode_overflow(page, data),
+            None => self.shared.overflow(page),
+        }
+    }
+}
+
+/// A node of the tree.
+#[derive(Clone)]
+enum Node {
+    // entries sorted by key
+    Leaf(Vec<LeafEntry>),
+    // `children[i]` holds the keys before `keys[i]`, and at or after `keys[i - 1]`
+    Branch {
+        keys: Vec<Vec<u8>>,
+        children: Vec<u64>,
+    },
+}
+
+impl Node {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Node::Leaf(entries) => {
+                PAGE_HEADER_LEN + entries.iter().map(LeafEntry::encoded_len).sum::<usize>()
+            }
+            Node::Branch { keys, .. } => {
+                PAGE_HEADER_LEN + 8 + keys.iter().map(|key| branch_key_len(key)).sum::<usize>()
+            }
+        }
+    }
+
+    /// Splits an oversized node into halves of about the same size, and returns them
+    /// with the first key of the right one.
+    fn split(self) -> (Node, Vec<u8>, Node) {
+        match self {
+            Node::Leaf(mut entries) => {
+                let sizes: Vec<usize> = entries.iter().map(LeafEntry::encoded_len).collect();
+                let right = entries.split_off(split_point(&sizes));
+                let key = right[0].key.clone();
+                (Node::Leaf(entries), key, Node::Leaf(right))
+            }
+            Node::Branch {
+                mut keys,
+                mut children,
+            } => {
+                let sizes: Vec<usize> = keys.iter().map(|key| branch_key_len(key)).collect();
+                let at = split_point(&sizes);
+                // the key at the split point moves up to the parent
+                let right_keys = keys.split_off(at + 1);
+                let key = keys.pop().expect("split point out of range");
+                let right_children = children.split_off(at + 1);
+                let left = Node::Branch { keys, children };
+                let right = Node::Branch {
+                    keys: right_keys,
+                    children: right_children,
+                };
+                (left, key, right)
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Node::Leaf(entries) => {
+                for entry in entries {
+                    put_short_bytes(&mut body, &entry.key);
+                    let mut flags = 0;
+                    if entry.expires_at.is_some() {
+                        flags |= ENTRY_EXPIRES;
+                    }
+                    if let Value::Overflow { .. } = entry.value {
+                        flags |= ENTRY_OVERFLOW;
+                    }
+                    body.push(flags);
+                    if let Some(expires_at) = entry.expires_at {
+                        body.extend_from_slice(&expires_at.to_le_bytes());
+                    }
+                    match &entry.value {
+                        Value::Inline(value) => put_short_bytes(&mut body, value),
+                        Value::Overflow { len, page } => {
+                            body.extend_from_slice(&len.to_le_bytes());
+                            body.extend_from_slice(&page.to_le_bytes());
+                        }
+                    }
+                }
+                encode_page(PAGE_LEAF, entries.len(), &body)
+            }
+            Node::Branch { keys, children } => {
+                body.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    put_short_bytes(&mut body, key);
+                    body.extend_from_slice(&child.to_le_bytes());
+                }
+                encode_page(PAGE_BRANCH, keys.len(), &body)
+            }
+        }
+    }
+
+    /// Decodes the node of a page whose checksum has been checked.
+    fn decode(page: u64, data: &[u8]) -> Result<Node> {
+        let count = le_u16(&data[5..]) as usize;
+        let mut body = &data[PAGE_HEADER_LEN..];
+        let node = match data[4] {
+            PAGE_LEAF => (0..count)
+                .map(|_| decode_leaf_entry(&mut body))
+                .collect::<Option<Vec<_>>>()
+                .map(Node::Leaf),
+            PAGE_BRANCH => decode_branch(&mut body, count),
+            _ => None,
+        };
+        node.ok_or(KvsError::CorruptedPage(page))
+    }
+}
+
+/// A key with its value.
+#[derive(Clone)]
+struct LeafEntry {
+    key: Vec<u8>,
+    value: Value,
+    // expiry time in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+}
+
+impl LeafEntry {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+
+    fn encoded_len(&self) -> usize {
+        let expiry_len = if self.expires_at.is_some() { 8 } else { 0 };
+        let value_len = match &self.value {
+            Value::Inline(value) => 2 + value.len(),
+            Value::Overflow { .. } => 16,
+        };
+        2 + self.key.len() + 1 + expiry_len + value_len
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Inline(Vec<u8>),
+    // stored in a chain of overflow pages starting at `page`
+    Overflow { len: u64, page: u64 },
+}
+
+impl Value {
+    fn len(&self) -> u64 {
+        match self {
+            Value::Inline(value) => value.len() as u64,
+            Value::Overflow { len, .. } => *len,
+        }
+    }
+}
+
+fn decode_leaf_entry(data: &mut &[u8]) -> Option<LeafEntry> {
+    let key = take_short_bytes(data)?.to_vec();
+    let flags = take(data, 1)?[0];
+    let expires_at = if flags & ENTRY_EXPIRES != 0 {
+        Some(le_u64(take(data, 8)?))
+    } else {
+        None
+    };
+    let value = if flags & ENTRY_OVERFLOW != 0 {
+        Value::Overflow {
+            len: le_u64(take(data, 8)?),
+            page: le_u64(take(data, 8)?),
+        }
+    } else {
+        Value::Inline(take_short_bytes(data)?.to_vec())
+    };
+    Some(LeafEntry {
+        key,
+        value,
+        expires_at,
+    })
+}
+
+fn decode_branch(data: &mut &[u8], count: usize) -> Option<Node> {
+    let mut keys = Vec::with_capacity(count);
+    let mut children = vec![le_u64(take(data, 8)?)];
+    for _ in 0..count {
+        keys.push(take_short_bytes(data)?.to_vec());
+        children.push(le_u64(take(data, 8)?));
+    }
+    Some(Node::Branch { keys, children })
+}
+
+fn branch_key_len(key: &[u8]) -> usize {
+    2 + key.len() + 8
+}
+
+/// Returns the index of the child of a branch that holds `key`.
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    match keys.binary_search_by(|separator| separator.as_slice().cmp(key)) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+/// Returns where to split entries of the given sizes so that the first part is about
+/// half of them, leaving at least one entry on each side.
+fn split_point(sizes: &[usize]) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut size = 0;
+    let mut at = 0;
+    while at < sizes.len() && size < half {
+        size += sizes[at];
+        at += 1;
+    }
+    cmp::min(cmp::max(at, 1), sizes.len() - 1)
+}
+
+/// Returns the entry of an internal key in the tree at `root`.
+fn find_entry<S: Pages>(pages: &S, root: u64, key: &[u8]) -> Result<Option<LeafEntry>> {
+    let mut page = root;
+    while page != 0 {
+        match &*pages.node(page)? {
+            Node::Branch { keys, children } => page = children[child_index(keys, key)],
+            Node::Leaf(entries) => {
+                let entry = entries
+                    .binary_search_by(|entry| entry.key.as_slice().cmp(key))
+                    .ok()
+                    .map(|i| entries[i].clone());
+                return Ok(entry);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the value of an internal key in the tree at `root`, unless it has expired.
+fn live_value<S: Pages>(pages: &S, root: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match find_entry(pages, root, key)? {
+        Some(ref entry) if !entry.expired(now_millis()) => {
+            Ok(Some(read_value(pages, &entry.value)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn read_value<S: Pages>(pages: &S, value: &Value) -> Result<Vec<u8>> {
+    match value {
+        Value::Inline(value) => Ok(value.clone()),
+        Value::Overflow { len, page } => Ok(read_overflow_value(pages, *page, *len)?.1),
+    }
+}
+
+/// Reads a value of `len` bytes from the chain of overflow pages starting at `first`, and
+/// returns the pages of the chain with the value.
+fn read_overflow_value<S: Pages>(pages: &S, first: u64, len: u64) -> Result<(Vec<u64>, Vec<u8>)> {
+    let mut chain = Vec::new();
+    let mut value = Vec::with_capacity(len as usize);
+    let mut page = first;
+    while (value.len() as u64) < len {
+        if page == 0 {
+            return Err(KvsError::CorruptedPage(first));
+        }
+        let (next, data) = pages.overflow(page)?;
+        let data_len = cmp::min(data.len() as u64, len - value.len() as u64) as usize;
+        value.extend_from_slice(&data[..data_len]);
+        chain.push(page);
+        page = next;
+    }
+    Ok((chain, value))
+}
+
+fn encode_overflow(next: u64, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(8 + data.len());
+    body.extend_from_slice(&next.to_le_bytes());
+    body.extend_from_slice(data);
+    encode_page(PAGE_OVERFLOW, 0, &body)
+}
+
+/// Returns the next page and the data of an overflow page whose checksum has been
+/// checked.
+fn decode_overflow(page: u64, data: &[u8]) -> Result<(u64, Vec<u8>)> {
+    if data[4] != PAGE_OVERFLOW {
+        return Err(KvsError::CorruptedPage(page));
+    }
+    let body = &data[PAGE_HEADER_LEN..];
+    Ok((le_u64(body), body[8..].to_vec()))
+}
+
+/// Builds a page of the given type from its body, with the header and the checksum.
+fn encode_page(page_type: u8, count: usize, body: &[u8]) -> Vec<u8> {
+    let mut page = vec![0; PAGE_SIZE];
+    page[4] = page_type;
+    page[5..PAGE_HEADER_LEN].copy_from_slice(&(count as u16).to_le_bytes());
+    page[PAGE_HEADER_LEN..PAGE_HEADER_LEN + body.len()].copy_from_slice(body);
+    let checksum = crc32fast::hash(&page[4..]);
+    page[..4].copy_from_slice(&checksum.to_le_bytes());
+    page
+}
+
+/// Reads a page and checks its checksum.
+fn read_page(file: &mut File, page: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0; PAGE_SIZE];
+    file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+    match file.read_exact(&mut data) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(KvsError::CorruptedPage(page));
+        }
+        res => res?,
+    }
+    if le_u32(&data) != crc32fast::hash(&data[4..]) {
+        return Err(KvsError::CorruptedPage(page));
+    }
+    Ok(data)
+}
+
+fn write_page(file: &mut File, page: u64, data: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Appends `bytes` prefixed with their length (u16).
+fn put_short_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Splits bytes prefixed with their length (u16) off `data`.
+fn take_short_bytes<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = le_u16(take(data, 2)?) as usize;
+    take(data, len)
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+/// The nodes of the least recently used pages.
+struct PageCache {
+    // number of nodes the cache holds
+    capacity: usize,
+    // node and last use of each page
+    nodes: HashMap<u64, (Arc<Node>, u64)>,
+    // pages by their last use
+    order: BTreeMap<u64, u64>,
+    next_use: u64,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            nodes: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+        }
+    }
+
+    /// Returns the node of a page and marks it as the most recently used.
+    fn get(&mut self, page: u64) -> Option<Arc<Node>> {
+        let entry = self.nodes.get_mut(&page)?;
+        self.order.remove(&entry.1);
+        entry.1 = self.next_use;
+        self.order.insert(self.next_use, page);
+        self.next_use += 1;
+        Some(Arc::clone(&entry.0))
+    }
+
+    /// Caches the node of a page, evicting the least recently used one if the cache is
+    /// full.
+    fn insert(&mut self, page: u64, node: Arc<Node>) {
+        self.remove(page);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.nodes.len() >= self.capacity {
+            if let Some(oldest) = self.order.values().next().cloned() {
+                self.remove(oldest);
+            }
+        }
+        self.order.insert(self.next_use, page);
+        self.nodes.insert(page, (node, self.next_use));
+        self.next_use += 1;
+    }
+
+    fn remove(&mut self, page: u64) {
+        if let Some((_, last_use)) = self.nodes.remove(&page) {
+            self.order.remove(&last_use);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeInclusive};
+
+use tokio::prelude::*;
+
+use super::kvs::{Change, DEFAULT_KEYSPACE_ID};
+use super::{watch_channel, WatchEvent, WatchSender, DEFAULT_KEYSPACE};
+use crate::KvsError;
+
+// the keys are stored prefixed with the id (big-endian u32) of their keyspace, so that the
+// keys of a keyspace are contiguous
+pub(super) const KEYSPACE_ID_LEN: usize = 4;
+
+// a key range with owned bounds
+pub(super) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+pub(super) type ChangeStream = Box<dyn Stream<Item = Change, Error = KvsError> + Send>;
+
+/// The keyspaces other than the default one, by id.
+#[derive(Clone)]
+pub(super) struct Catalog {
+    pub(super) next_id: u32,
+    pub(super) names: BTreeMap<u32, String>,
+}
+
+impl Catalog {
+    pub(super) fn id(&self, name: &str) -> Option<u32> {
+        if name == DEFAULT_KEYSPACE {
+            return Some(DEFAULT_KEYSPACE_ID);
+        }
+        self.names
+            .iter()
+            .find(|(_, keyspace_name)| *keyspace_name == name)
+            .map(|(id, _)| *id)
+    }
+
+    pub(super) fn contains(&self, keyspace: u32) -> bool {
+        keyspace == DEFAULT_KEYSPACE_ID || self.names.contains_key(&keyspace)
+    }
+
+    /// Returns whether any of the keyspaces with ids in the range exists.
+    pub(super) fn contains_any(&self, keyspaces: RangeInclusive<u32>) -> bool {
+        keyspaces.contains(&DEFAULT_KEYSPACE_ID) || self.names.range(keyspaces).next().is_some()
+    }
+}
+
+/// The channels of the watches and the change feeds on an engine, with the ids of the
+/// keyspaces they follow.
+#[derive(Default)]
+pub(super) struct Watchers {
+    // watches with the prefixes they watch
+    senders: Vec<(u32, Vec<u8>, WatchSender<WatchEvent>)>,
+    feeds: Vec<(u32, WatchSender<Change>)>,
+}
+
+impl Watchers {
+    pub(super) fn add(
+        &mut self,
+        keyspace: u32,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send> {
+        let (sender, events) = watch_channel();
+        self.senders.push((keyspace, prefix, sender));
+        events
+    }
+
+    pub(super) fn add_feed(&mut self, keyspace: u32) -> ChangeStream {
+        let (sender, changes) = watch_channel();
+        self.feeds.push((keyspace, sender));
+        changes
+    }
+
+    /// Returns whether any watch is interested in the key of the keyspace.
+    pub(super) fn watches(&self, keyspace: u32, key: &[u8]) -> bool {
+        self.senders
+            .iter()
+            .any(|(id, prefix, _)| *id == keyspace && key.starts_with(prefix))
+    }
+
+    /// Returns whether a write of the key has to be published, because a watch is
+    /// interested in it or there is a change feed of its keyspace.
+    pub(super) fn wants(&self, keyspace: u32, key: &[u8]) -> bool {
+        self.feeds.iter().any(|(id, _)| *id == keyspace) || self.watches(keyspace, key)
+    }
+
+    /// Sends the events of the write with sequence number `seq` to the watches of their
+    /// keys, and the write to the change feeds of the keyspace. Feeds whose streams have
+    /// been dropped or which have fallen behind are removed.
+    pub(super) fn publish(&mut self, keyspace: u32, seq: u64, events: Vec<WatchEvent>) {
+        for event in &events {
+            self.send(keyspace, event);
+        }
+        if self.feeds.is_empty() {
+            return;
+        }
+        let change = Change { seq, events };
+        self.feeds = self
+            .feeds
+            .drain(..)
+            .filter_map(|(id, mut feed)| {
+                if id == keyspace && !feed.send(change.clone()) {
+                    None
+                } else {
+                    Some((id, feed))
+                }
+            })
+            .collect();
+    }
+
+    /// Sends an event to the watches of its key. Watches whose streams have been dropped
+    /// or which have fallen behind are removed.
+    pub(super) fn send(&mut self, keyspace: u32, event: &WatchEvent) {
+        let key = event.key();
+        self.senders = self
+            .senders
+            .drain(..)
+            .filter_map(|(id, prefix, mut sender)| {
+                if id == keyspace && key.starts_with(&prefix) && !sender.send(event.clone()) {
+                    None
+                } else {
+                    Some((id, prefix, sender))
+                }
+            })
+            .collect();
+    }
+
+    /// Drops the watches and the change feeds of a dropped keyspace, which ends their
+    /// streams.
+    pub(super) fn remove_keyspace(&mut self, keyspace: u32) {
+        self.senders.retain(|(id, _, _)| *id != keyspace);
+        self.feeds.retain(|(id, _)| *id != keyspace);
+    }
+}
+
+pub(super) fn internal_key(keyspace: u32, key: &[u8]) -> Vec<u8> {
+    let mut internal = Vec::with_capacity(KEYSPACE_ID_LEN + key.len());
+    internal.extend_from_slice(&keyspace.to_be_bytes());
+    internal.extend_from_slice(key);
+    internal
+}
+
+pub(super) fn keyspace_of(key: &[u8]) -> u32 {
+    let mut id = [0; KEYSPACE_ID_LEN];
+    id.copy_from_slice(&key[..KEYSPACE_ID_LEN]);
+    u32::from_be_bytes(id)
+}
+
+/// Returns the range of internal keys of a range of keys of the keyspace.
+pub(super) fn keyspace_range(keyspace: u32, range: KeyRange) -> KeyRange {
+    let bound = |bound: Bound<Vec<u8>>, unbounded: Bound<Vec<u8>>| match bound {
+        Bound::Included(key) => Bound::Included(internal_key(keyspace, &key)),
+        Bound::Excluded(key) => Bound::Excluded(internal_key(keyspace, &key)),
+        Bound::Unbounded => unbounded,
+    };
+    let end = match keyspace.checked_add(1) {
+        Some(next) => Bound::Excluded(internal_key(next, &[])),
+        None => Bound::Unbounded,
+    };
+    (
+        bound(range.0, Bound::Included(internal_key(keyspace, &[]))),
+        bound(range.1, end),
+    )
+}
+
+/// Returns whether `key` is at or after the start of a range.
+pub(super) fn after_start(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match start {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Returns whether `key` is before the end of a range.
+pub(super) fn before_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
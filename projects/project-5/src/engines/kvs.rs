@@ -1,31 +1,162 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crossbeam::queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
-use serde::{Deserialize, Serialize};
+use fs2::FileExt;
+use memmap::Mmap;
+use serde::Deserialize;
 use serde_json::Deserializer;
 use tokio::prelude::*;
-use tokio::sync::oneshot;
 
-use super::KvsEngine;
+use super::keyspace::{ChangeStream, Watchers};
+use super::{
+    check_keyspace_name, create_checkpoint_dir, expiry_time, now_millis, owned_range, prefix_range,
+    run_in_pool, spawn_periodic, Durability, EngineStats, GroupCommit, KvPairs, KvsEngine,
+    WatchEvent, DEFAULT_KEYSPACE,
+};
+use crate::batch::BatchOp;
 use crate::thread_pool::ThreadPool;
-use crate::{KvsError, Result};
+use crate::transaction::run_transaction;
+use crate::{KvsError, Result, Transaction, WriteBatch};
 
+// stale bytes that trigger a compaction by default
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// garbage ratio above which a log is merged by default
+const MERGE_THRESHOLD: f64 = 0.5;
 
-/// The `KvStore` stores string key/value pairs.
+// bytes counted for each cached value besides the value itself
+const CACHE_ENTRY_OVERHEAD: u64 = 64;
+// the value cache is split into up to this many shards with locks of their own, each
+// holding at least `MIN_CACHE_SHARD_SIZE` bytes
+const MAX_CACHE_SHARDS: u64 = 16;
+const MIN_CACHE_SHARD_SIZE: u64 = 64 * 1024;
+
+// how often expired keys are removed from the index
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// expired keys removed by one write, so that the writer lock is not held for long
+const EXPIRY_SWEEP_CHUNK: usize = 1000;
+
+// length (u32) + CRC32 checksum (u32) of the payload
+pub(super) const RECORD_HEADER_LEN: usize = 8;
+
+// op type (u8) + key length (u32) + value length (u32)
+const COMMAND_HEADER_LEN: usize = 9;
+const OP_SET: u8 = 1;
+const OP_REMOVE: u8 = 2;
+// followed by the expiry time (u64) before the key and the value
+const OP_SET_WITH_EXPIRY: u8 = 3;
+// followed by the number of commands (u32) and each command prefixed with its length (u32)
+const OP_BATCH: u8 = 4;
+const BATCH_HEADER_LEN: usize = 5;
+// followed by the sequence number (u64) of the first write in the log it starts
+const OP_SEQUENCE: u8 = 5;
+const SEQUENCE_LEN: usize = 9;
+// set in the op type of a command of a keyspace other than the default one, which has the
+// id (u32) of its keyspace right after the header
+const KEYSPACE_FLAG: u8 = 0x80;
+
+// name of the file recording the on-disk format version of the data directory
+const FORMAT_FILE: &str = "FORMAT";
+// name of the file locked by the processes that have the data directory open
+const LOCK_FILE: &str = "LOCK";
+// name of the file listing the keyspaces of the data directory
+const KEYSPACES_FILE: &str = "KEYSPACES";
+// id of the default keyspace, which is not listed in the `KEYSPACES` file
+pub(super) const DEFAULT_KEYSPACE_ID: u32 = 0;
+// records are JSON-serialized commands written one after another without frames
+const LEGACY_FORMAT_VERSION: u32 = 1;
+// what a log of JSON-serialized commands starts with
+const LEGACY_LOG_PREFIXES: [&[u8]; 2] = [b"{\"Set\":", b"{\"Remove\":"];
+// records are commands in the binary encoding
+const FIRST_BINARY_FORMAT_VERSION: u32 = 2;
+// Later versions only add op types to the binary encoding:
+// 3: `Set` commands may carry an expiry time
+// 4: a record may hold a batch of commands
+// 5: a log may start with the sequence number of its first write
+// 6: a command may belong to a keyspace other than the default one
+const FORMAT_VERSION: u32 = 6;
+
+// Version of the hint file layout. Hint files of other versions are ignored.
+const HINT_VERSION: u32 = 4;
+
+/// The `KvStore` stores binary-safe key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A skip list in memory stores the keys and the value locations for fast query.
+/// The active log can be rolled over to a new generation when it reaches a maximum size.
+///
+/// A store takes an exclusive lock of the `LOCK` file in the data directory, so no other
+/// process can open the directory while it is open. `KvStore::open_read_only` takes a
+/// shared lock instead.
+///
+/// Every command in the log is framed with its length and a CRC32 checksum. A record
+/// torn by a crash at the end of the newest log is truncated when the store is opened;
+/// a damaged record anywhere else is reported as `KvsError::CorruptedLog`.
+/// Writes are synced to disk as the `Durability` set in `KvStoreOptions` requires.
+///
+/// Commands are encoded in a compact binary format. The format version of the data
+/// directory is recorded in the `FORMAT` file. Logs written in the legacy format, a
+/// stream of JSON-serialized commands without frames, can still be read, and they are
+/// rewritten in the binary format by the next compaction.
+///
+/// A `WriteBatch` is written as a single record, and the commands in it are located
+/// inside that record until a compaction rewrites them as records of their own.
+///
+/// A key can be set with a time-to-live. Its expiry time is saved in the log, and it is
+/// invisible as soon as it expires. The compactor thread periodically removes expired keys
+/// in chunks, writing a `Remove` command for each. Compaction drops the expired commands
+/// from the log and removes the keys it finds in the same way.
+///
+/// Every write gets a sequence number, one more than the write before it. Each log written
+/// by the store starts with the sequence number of its first write, and every later record
+/// in it is one write, so the sequence numbers outlive the process. `KvStore::changes_since`
+/// streams the writes in sequence order from the logs that compaction has not merged yet.
+///
+/// The keys of a store are split into named keyspaces, which share the logs and the writer
+/// while each has its own index and live bytes. A command of a keyspace other than the
+/// default one carries the id of its keyspace, and the `KEYSPACES` file maps the names of
+/// the keyspaces to their ids. `KvStore::open` returns a handle of the default keyspace,
+/// and `KvsEngine::keyspace` a handle of another one.
+///
+/// A snapshot sees the store as it was when the snapshot was taken. The versions a write
+/// supersedes are kept in memory while a snapshot may still read them. A log file that
+/// such versions point to is renamed with a `stale` extension name by compaction instead
+/// of being deleted, until the snapshots are dropped.
+///
+/// The live and dead bytes of every log are tracked, and `KvStore::generation_stats`
+/// reports them. Compaction runs on a background thread. When the stale commands exceed
+/// the trigger set in `KvStoreOptions`, the logs whose garbage ratio exceeds the merge
+/// threshold are merged: the active log is sealed and new writes go to a fresh log,
+/// while the live commands of the merged logs are copied to a compaction file. A removal
+/// that may hide a value in an older log that is kept is copied as well. The index is
+/// then switched to the copies under the writer lock, except for keys written again in
+/// the meantime, and the merged logs are deleted.
+///
+/// Values can be cached in memory by an LRU cache whose size is set in `KvStoreOptions`.
+/// The cache is keyed by the locations of the values in the logs, so a write never makes
+/// a cached value stale.
+///
+/// A checkpoint of a live store hard-links the sealed logs into the checkpoint directory
+/// and copies the active log up to its length at that moment. Logs are never modified
+/// once written, so the checkpoint is consistent without stopping writes.
+///
+/// Compaction also writes a hint file with a `hint` extension name next to the log it
+/// produces. It holds only the keys and value locations of that log, and the keys it
+/// removes, so opening the store reads it instead of replaying the whole log.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -45,11 +176,15 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 pub struct KvStore<P: ThreadPool> {
     // directory for the log and other data
     path: Arc<PathBuf>,
-    // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    // id of the keyspace the handle works on
+    keyspace: u32,
+    // index of the keyspace
+    index: Arc<Index>,
+    // keyspaces by their names, which handles are looked up in without the writer lock
+    names: Arc<KeyspaceNames>,
     writer: Arc<Mutex<KvStoreWriter>>,
     thread_pool: P,
-    reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    reader_pool: Arc<ReaderPool>,
 }
 
 impl<P: ThreadPool> KvStore<P> {
@@ -61,406 +196,3391 @@ impl<P: ThreadPool> KvStore<P> {
     ///
     /// # Errors
     ///
-    /// It propagates I/O or deserialization errors during the log replay.
+    /// It returns `KvsError::DirectoryLocked` if another process has the data directory
+    /// open.
+    ///
+    /// It returns `KvsError::CorruptedLog` if a record fails its checksum and it is not
+    /// the torn tail of the newest log file.
+    ///
+    /// It returns `KvsError::UnsupportedFormat` if the data directory is written in a
+    /// newer format.
+    ///
+    /// It propagates I/O errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        KvStore::open_with(path, concurrency, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// It returns the same errors as `KvStore::open`.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
+        let lock = lock_dir(&path, true)?;
+        remove_unfinished_compactions(&path)?;
 
-        let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        let LoadedDir {
+            gen_list,
+            format_version,
+            next_keyspace_id,
+            mut keyspaces,
+            mut seq_ranges,
+        } = load_dir(&path, true)?;
+        let index = Arc::clone(&keyspaces[&DEFAULT_KEYSPACE_ID].index);
 
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
-
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
-            readers.insert(gen, reader);
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let next_seq = seq_ranges.values().map(|seqs| seqs.end).max().unwrap_or(1);
+        let writer = new_log_file(&path, current_gen, next_seq)?;
+        seq_ranges.insert(current_gen, next_seq..next_seq);
+        let mut gen_stats = BTreeMap::new();
+        for &gen in gen_list.iter().chain(Some(&current_gen)) {
+            let size = match fs::metadata(log_path(&path, gen)) {
+                Ok(metadata) => metadata.len(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+            // the sequence number the active log starts with is not garbage
+            let live = if gen == current_gen { size } else { 0 };
+            gen_stats.insert(gen, GenerationStats { gen, size, live });
+        }
+        for keyspace in keyspaces.values_mut() {
+            for entry in keyspace.index.iter() {
+                mark_live(&mut gen_stats, *entry.value());
+                keyspace.live += entry.value().len;
+                if let Some(expires_at) = entry.value().expires_at {
+                    keyspace.expiring.insert((expires_at, entry.key().clone()));
+                }
+            }
         }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
-        let safe_point = Arc::new(AtomicU64::new(0));
+        let names = Arc::new(RwLock::new(
+            keyspaces
+                .iter()
+                .map(|(&keyspace, space)| {
+                    (space.name.clone(), (keyspace, Arc::clone(&space.index)))
+                })
+                .collect(),
+        ));
 
+        let active_log = Arc::new(Mutex::new(writer.writer.get_ref().try_clone()?));
+        if let Durability::Periodic(interval) = options.durability {
+            spawn_periodic(Arc::downgrade(&active_log), interval, "sync", |file| {
+                Ok(file.lock().unwrap().sync_data()?)
+            });
+        }
         let reader = KvStoreReader {
             path: Arc::clone(&path),
-            safe_point,
+            merges: Arc::new(AtomicU64::new(0)),
+            seen_merges: Cell::new(0),
+            cache: options
+                .value_cache_size
+                .map(|size| Arc::new(ValueCache::new(size))),
+            sealed: Arc::new(SealedLogs::new(current_gen)),
             readers: RefCell::new(BTreeMap::new()),
+            mmaps: RefCell::new(BTreeMap::new()),
+        };
+
+        let compactor = Compactor {
+            path: Arc::clone(&path),
         };
+        let (compactions, tasks) = mpsc::channel();
 
         let writer = KvStoreWriter {
             reader: reader.clone(),
             writer,
             current_gen,
-            uncompacted,
+            gen_stats,
+            format_version,
             path: Arc::clone(&path),
-            index: Arc::clone(&index),
+            keyspaces,
+            names: Arc::clone(&names),
+            next_keyspace_id,
+            next_seq,
+            seq_ranges,
+            snapshots: BTreeMap::new(),
+            pinned_gens: Vec::new(),
+            compacting: None,
+            last_compaction: None,
+            watchers: Watchers::default(),
+            compactions: Some(compactions),
+            compactor: None,
+            compaction_trigger: options.compaction_trigger,
+            max_log_size: options.max_log_size,
+            merge_threshold: options.merge_threshold,
+            change_retention: options.change_retention,
+            durability: options.durability,
+            group_commit: Arc::new(GroupCommit::default()),
+            active_log: Arc::clone(&active_log),
+            _lock: lock,
         };
 
         let thread_pool = P::new(concurrency)?;
-        let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
-        for _ in 1..concurrency {
-            reader_pool.push(reader.clone()).unwrap();
+        let pool_size = options
+            .reader_pool_size
+            .unwrap_or(concurrency as usize)
+            .max(1);
+        let reader_pool = Arc::new(ReaderPool {
+            readers: ArrayQueue::new(pool_size),
+            path: Arc::clone(&path),
+            merges: Arc::clone(&reader.merges),
+            cache: reader.cache.clone(),
+            sealed: Arc::clone(&reader.sealed),
+        });
+        for _ in 1..pool_size {
+            reader_pool.readers.push(reader.clone()).unwrap();
+        }
+        reader_pool.readers.push(reader).unwrap();
+
+        let writer = Arc::new(Mutex::new(writer));
+        let compactor = spawn_compactor(compactor, Arc::downgrade(&writer), tasks);
+        {
+            let mut writer = writer.lock().unwrap();
+            writer.compactor = Some(compactor);
+            if options.compact_on_open && !gen_list.is_empty() {
+                writer.compact()?;
+            }
         }
-        reader_pool.push(reader).unwrap();
 
         Ok(KvStore {
             path,
+            keyspace: DEFAULT_KEYSPACE_ID,
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            names,
+            writer,
             thread_pool,
             reader_pool,
         })
     }
-}
 
-impl<P: ThreadPool> KvsEngine for KvStore<P> {
-    /// Sets the value of a string key to a string.
+    /// Opens the store at the given path for reading only.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// The store is read as a snapshot of the data directory, and nothing in the
+    /// directory is changed. It takes a shared lock of the directory, so read-only
+    /// stores can be opened by any number of processes, but not while a `KvStore` has
+    /// the directory open. Only the default keyspace is read.
     ///
-    /// # Errors
+    /// It returns the same errors as `KvStore::open`.
+    pub fn open_read_only(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+    ) -> Result<KvStoreSnapshot<P>> {
+        let path = Arc::new(path.into());
+        let lock = lock_dir(&path, false)?;
+        let keyspace = load_dir(&path, false)?
+            .keyspaces
+            .remove(&DEFAULT_KEYSPACE_ID)
+            .expect("no default keyspace");
+
+        // readers are created on demand
+        let reader_pool = ReaderPool {
+            readers: ArrayQueue::new(concurrency.max(1) as usize),
+            path: Arc::clone(&path),
+            merges: Arc::new(AtomicU64::new(0)),
+            cache: None,
+            // nothing is written, so all the logs are sealed
+            sealed: Arc::new(SealedLogs::new(u64::max_value())),
+        };
+        Ok(KvStoreSnapshot {
+            seq: u64::max_value(),
+            now: now_millis(),
+            index: keyspace.index,
+            versions: keyspace.versions,
+            thread_pool: P::new(concurrency)?,
+            reader_pool: Arc::new(reader_pool),
+            _guard: None,
+            _lock: Some(Arc::new(lock)),
+        })
+    }
+
+    /// Takes a snapshot of the store.
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+    /// The snapshot keeps seeing the keys and values at the time it is taken, while the
+    /// store keeps accepting writes.
+    pub fn snapshot(&self) -> KvStoreSnapshot<P> {
+        let mut writer = self.writer.lock().unwrap();
+        let seq = writer.register_snapshot();
+        // a dropped keyspace has no versions to keep
+        let versions = writer.keyspaces.get(&self.keyspace).map_or_else(
+            || Arc::new(SkipMap::new()),
+            |keyspace| keyspace.versions.clone(),
+        );
+        KvStoreSnapshot {
+            seq,
+            now: now_millis(),
+            index: self.index.clone(),
+            versions,
+            thread_pool: self.thread_pool.clone(),
+            reader_pool: self.reader_pool.clone(),
+            _guard: Some(Arc::new(SnapshotGuard {
+                seq,
+                writer: self.writer.clone(),
+            })),
+            _lock: None,
+        }
     }
 
-    /// Gets the string value of a given string key.
+    /// Returns the size and live bytes of each log file, in generation order.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
-        let reader_pool = self.reader_pool.clone();
-        let index = self.index.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
-                    reader_pool.push(reader).unwrap();
-                    res
-                } else {
-                    Ok(None)
-                }
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+    /// Logs whose garbage ratio exceeds the merge threshold are merged by the next
+    /// compaction.
+    pub fn generation_stats(&self) -> Vec<GenerationStats> {
+        let writer = self.writer.lock().unwrap();
+        writer.gen_stats.values().cloned().collect()
     }
 
-    /// Removes a given key.
+    /// Returns the counters of the value cache, or `None` if the store has no cache.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.reader_pool.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Returns a stream of the writes made after the write with sequence number `seq`, in
+    /// commit order.
     ///
-    /// # Error
+    /// Sequence numbers start at 1, so `changes_since(0)` starts from the first write still
+    /// in the logs. The writes in the logs are read first, one log at a time as the stream
+    /// is polled, then the stream goes on with new writes as they are made. A consumer can
+    /// resume from the last sequence number it has seen, even after the store is reopened.
+    /// Keys that expire are removed by `Remove` commands, which are in the stream like
+    /// other writes. Only the writes to the keyspace of the handle are in the stream,
+    /// while the sequence numbers are shared by all the keyspaces.
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+    /// The stream fails with `KvsError::ChangesTooOld` if compaction has already dropped
+    /// some of the writes after `seq`. `KvStoreOptions::change_retention` keeps the latest
+    /// writes from compaction for consumers that fall behind.
+    ///
+    /// The new writes are buffered while the logs are read and the consumer catches up.
+    /// If too many of them pile up, the stream fails with `KvsError::WatchLagged`, and the
+    /// consumer can resume from the last sequence number it has seen.
+    pub fn changes_since(
+        &self,
+        seq: u64,
+    ) -> Box<dyn Stream<Item = Change, Error = KvsError> + Send> {
+        let keyspace = self.keyspace;
+        let feed = self.writer.lock().unwrap().follow_changes(keyspace, seq);
+        let (logs, new_changes) = match feed {
+            Ok(feed) => feed,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+        let thread_pool = self.thread_pool.clone();
+        let logged_changes = stream::iter_ok(logs)
+            .and_then(move |log| {
+                run_in_pool(&thread_pool, move || read_changes(log, keyspace, seq))
+            })
+            .map(stream::iter_ok)
+            .flatten();
+        let new_changes = new_changes.filter(move |change| change.seq > seq);
+        Box::new(logged_changes.chain(new_changes))
     }
 }
 
-/// A single thread reader.
-///
-/// Each `KvStore` instance has its own `KvStoreReader` and
-/// `KvStoreReader`s open the same files separately. So the user
-/// can read concurrently through multiple `KvStore`s in different
-/// threads.
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    // generation of the latest compaction file
-    safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+/// A write to a `KvStore`, as streamed by `KvStore::changes_since`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The sequence number of the write.
+    pub seq: u64,
+    /// The keys set and removed by the write, in order. A write batch has an event for
+    /// each of its commands.
+    pub events: Vec<WatchEvent>,
 }
 
-impl KvStoreReader {
-    /// Close file handles with generation number less than safe_point.
-    ///
-    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than safe_point.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    fn close_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
-            }
-            readers.remove(&first_gen);
-        }
-    }
-
-    /// Read the log file at the given `CommandPos`.
-    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
-    where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
-    {
-        self.close_stale_handles();
-
-        let mut readers = self.readers.borrow_mut();
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
-        }
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
-    }
+/// The size and live bytes of a log file of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationStats {
+    /// The generation number of the log file.
+    pub gen: u64,
+    /// The size of the log file in bytes.
+    pub size: u64,
+    /// The bytes taken by the commands that the index points to.
+    pub live: u64,
+}
 
-    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
-    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
-        })
+impl GenerationStats {
+    /// Returns the bytes taken by stale commands, which a merge would free.
+    pub fn dead(&self) -> u64 {
+        self.size.saturating_sub(self.live)
     }
-}
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> KvStoreReader {
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            safe_point: Arc::clone(&self.safe_point),
-            // don't use other KvStoreReader's readers
-            readers: RefCell::new(BTreeMap::new()),
+    /// Returns the ratio of the dead bytes to the size of the log file.
+    ///
+    /// It is 0 for an empty log file.
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.size == 0 {
+            0.0
+        } else {
+            self.dead() as f64 / self.size as f64
         }
     }
 }
 
-struct KvStoreWriter {
-    reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
-    current_gen: u64,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction
-    uncompacted: u64,
-    path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+/// When a `KvStore` compacts its logs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionTrigger {
+    /// Compacts when the stale commands take more than the given number of bytes.
+    StaleBytes(u64),
+    /// Compacts when the stale commands take more than the given ratio of the bytes taken
+    /// by the live ones.
+    StaleRatio(f64),
 }
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
-            }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
-        }
+/// Options for opening a `KvStore` with `KvStore::open_with`.
+///
+/// ```rust
+/// # use kvs::{CompactionTrigger, KvStoreOptions};
+/// let options = KvStoreOptions::new()
+///     .compaction_trigger(CompactionTrigger::StaleRatio(0.5))
+///     .max_log_size(64 * 1024 * 1024)
+///     .merge_threshold(0.3)
+///     .compact_on_open(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    compaction_trigger: CompactionTrigger,
+    max_log_size: Option<u64>,
+    merge_threshold: f64,
+    reader_pool_size: Option<usize>,
+    value_cache_size: Option<u64>,
+    compact_on_open: bool,
+    change_retention: u64,
+    durability: Durability,
+}
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_trigger: CompactionTrigger::StaleBytes(COMPACTION_THRESHOLD),
+            max_log_size: None,
+            merge_threshold: MERGE_THRESHOLD,
+            reader_pool_size: None,
+            value_cache_size: None,
+            compact_on_open: false,
+            change_retention: 0,
+            durability: Durability::None,
         }
-        Ok(())
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
-            }
+impl KvStoreOptions {
+    /// Creates the default options.
+    ///
+    /// The store is compacted when its stale commands take more than 1 MiB, and the logs
+    /// that are more than half garbage are merged. The active log grows without limit.
+    /// Syncing the logs is left to the operating system.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
-        }
+    /// Sets when the store is compacted.
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> KvStoreOptions {
+        self.compaction_trigger = trigger;
+        self
     }
 
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+    /// Sets the size in bytes at which the active log is rolled over to a new generation.
+    pub fn max_log_size(mut self, size: u64) -> KvStoreOptions {
+        self.max_log_size = Some(size);
+        self
+    }
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+    /// Sets the garbage ratio above which a log is merged by a compaction.
+    ///
+    /// The live commands of the merged logs are rewritten to a new log and the merged
+    /// logs are deleted. The other logs are left untouched.
+    pub fn merge_threshold(mut self, ratio: f64) -> KvStoreOptions {
+        self.merge_threshold = ratio;
+        self
+    }
 
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
-        }
-        compaction_writer.flush()?;
+    /// Sets how many readers keep their log files open. It is at least one.
+    ///
+    /// It defaults to the concurrency of the store. With fewer readers, a read that
+    /// finds none available opens the log files itself.
+    pub fn reader_pool_size(mut self, size: usize) -> KvStoreOptions {
+        self.reader_pool_size = Some(size);
+        self
+    }
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
+    /// Sets the size in bytes of the cache of values shared by all readers.
+    ///
+    /// The cache is split into up to 16 shards of at least 64 KiB, which readers lock
+    /// separately and which evict their least recently used values on their own. There is
+    /// no cache by default.
+    pub fn value_cache_size(mut self, size: u64) -> KvStoreOptions {
+        self.value_cache_size = Some(size);
+        self
+    }
 
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
+    /// Sets whether to compact the existing logs as soon as the store is opened.
+    pub fn compact_on_open(mut self, compact_on_open: bool) -> KvStoreOptions {
+        self.compact_on_open = compact_on_open;
+        self
+    }
 
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
-            }
-        }
-        self.uncompacted = 0;
+    /// Sets how many of the latest writes compaction keeps in the logs for
+    /// `KvStore::changes_since`.
+    ///
+    /// A log holding any of them is not merged, so a consumer that falls behind by no more
+    /// than that many writes can still resume. There is no such history by default.
+    pub fn change_retention(mut self, writes: u64) -> KvStoreOptions {
+        self.change_retention = writes;
+        self
+    }
 
-        Ok(())
+    /// Sets when the writes are synced to disk.
+    pub fn durability(mut self, durability: Durability) -> KvStoreOptions {
+        self.durability = durability;
+        self
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// A read-only, point-in-time view of a `KvStore`.
 ///
-/// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    Ok(writer)
-}
-
-/// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
-    gen_list.sort_unstable();
-    Ok(gen_list)
+/// It is created by `KvStore::snapshot`, or by `KvStore::open_read_only` for a store
+/// that is not open. The versions it reads are kept until the snapshot and all its
+/// clones are dropped.
+#[derive(Clone)]
+pub struct KvStoreSnapshot<P: ThreadPool> {
+    // sequence number of the last write the snapshot sees
+    seq: u64,
+    // time when the snapshot was taken, which decides whether keys have expired
+    now: u64,
+    index: Arc<Index>,
+    versions: Arc<VersionMap>,
+    thread_pool: P,
+    reader_pool: Arc<ReaderPool>,
+    _guard: Option<Arc<SnapshotGuard>>,
+    // shared lock of the data directory of a read-only snapshot. The guard of a snapshot
+    // of a store keeps its writer, which holds the lock.
+    _lock: Option<Arc<File>>,
 }
 
-/// Load the whole log file and store value locations in the index map.
-///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+impl<P: ThreadPool> KvStoreSnapshot<P> {
+    /// Gets the value of a given key in the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let (seq, now) = (self.seq, self.now);
+        let index = self.index.clone();
+        let versions = self.versions.clone();
+        let reader_pool = self.reader_pool.clone();
+        run_in_pool(&self.thread_pool, move || {
+            match snapshot_lookup(&index, &versions, &key, seq, now) {
+                Some(cmd_pos) => {
+                    with_reader(&reader_pool, |reader| reader.read_value(cmd_pos).map(Some))
                 }
-                index.insert(key, (gen, pos..new_pos).into());
+                None => Ok(None),
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+        })
+    }
+
+    /// Gets the string value of a given string key in the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| -> Result<Option<String>> {
+                    Ok(value.map(String::from_utf8).transpose()?)
+                }),
+        )
+    }
+
+    /// Returns the key/value pairs in the given key range in the snapshot, in key order.
+    ///
+    /// At most `limit` pairs are returned if it is not `None`.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        let (seq, now) = (self.seq, self.now);
+        let index = self.index.clone();
+        let versions = self.versions.clone();
+        let reader_pool = self.reader_pool.clone();
+        let range = owned_range(&range);
+        run_in_pool(&self.thread_pool, move || {
+            // Keys that the snapshot may see are either in the index or in the versions
+            // superseded after the snapshot was taken. Both are walked in key order.
+            let mut latest = index.range(range.clone()).peekable();
+            let mut superseded = versions.range(version_range(&range)).peekable();
+            let limit = limit.unwrap_or(usize::max_value());
+            let mut pairs = Vec::new();
+            with_reader(&reader_pool, |reader| {
+                while pairs.len() < limit {
+                    let key = match (latest.peek(), superseded.peek()) {
+                        (Some(a), Some(b)) => cmp::min(a.key(), &b.key().0).clone(),
+                        (Some(a), None) => a.key().clone(),
+                        (None, Some(b)) => b.key().0.clone(),
+                        (None, None) => break,
+                    };
+                    while latest.peek().map_or(false, |entry| *entry.key() == key) {
+                        latest.next();
+                    }
+                    while superseded
+                        .peek()
+                        .map_or(false, |entry| entry.key().0 == key)
+                    {
+                        superseded.next();
+                    }
+                    if let Some(cmd_pos) = snapshot_lookup(&index, &versions, &key, seq, now) {
+                        let value = reader.read_value(cmd_pos)?;
+                        pairs.push((key, value));
+                    }
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
-            }
-        }
-        pos = new_pos;
+                Ok(())
+            })?;
+            Ok(pairs)
+        })
     }
-    Ok(uncompacted)
-}
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+    /// Returns the key/value pairs whose keys start with `prefix` in the snapshot, in
+    /// key order.
+    ///
+    /// At most `limit` pairs are returned if it is not `None`.
+    pub fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        self.scan(prefix_range(prefix), limit)
+    }
 }
 
-/// Struct representing a command
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+/// Releases the versions kept for a snapshot when the snapshot is dropped.
+struct SnapshotGuard {
+    seq: u64,
+    writer: Arc<Mutex<KvStoreWriter>>,
 }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            writer.release_snapshot(self.seq);
+        }
     }
+}
+
+/// Memory maps of the sealed logs, shared by all readers of a store.
+///
+/// Logs other than the active one are never modified, so each of them is mapped once and
+/// commands are decoded straight from the mapping, without file handles or system calls.
+/// The active log grows while it is read, so it is still read through buffered handles.
+struct SealedLogs {
+    // generation of the active log. The logs before it are sealed.
+    active_gen: AtomicU64,
+    maps: Mutex<BTreeMap<u64, Arc<Mmap>>>,
+}
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+impl SealedLogs {
+    fn new(active_gen: u64) -> SealedLogs {
+        SealedLogs {
+            active_gen: AtomicU64::new(active_gen),
+            maps: Mutex::new(BTreeMap::new()),
+        }
     }
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+/// Hit, miss and eviction counters of the value cache of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// The number of reads served by the cache.
+    pub hits: u64,
+    /// The number of reads that missed the cache and read the log.
+    pub misses: u64,
+    /// The number of values evicted to make room for others.
+    pub evictions: u64,
+    /// The bytes taken by the cached values, counting a fixed overhead for each value.
+    pub size: u64,
+}
+
+/// A size-bounded LRU cache of values, keyed by their locations in the logs.
+///
+/// A location always holds the same value, so a cached value never goes stale. The values
+/// at superseded locations are dropped so that they do not crowd out live ones, and the
+/// values moved by a compaction are moved in the cache as well.
+///
+/// The locations are spread over shards by their hashes, so that readers of different
+/// values rarely wait for each other. Each shard evicts its own least recently used
+/// values.
+struct ValueCache {
+    // capacity of each shard
+    shard_capacity: u64,
+    shards: Vec<Mutex<Lru>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ValueCache {
+    fn new(capacity: u64) -> ValueCache {
+        let shards = match capacity / MIN_CACHE_SHARD_SIZE {
+            0 => 1,
+            shards => cmp::min(shards, MAX_CACHE_SHARDS),
+        };
+        ValueCache {
+            shard_capacity: capacity / shards,
+            shards: (0..shards).map(|_| Mutex::new(Lru::default())).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the shard holding the given location.
+    fn shard(&self, loc: (u64, u64)) -> &Mutex<Lru> {
+        let mut hasher = DefaultHasher::new();
+        loc.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Returns the cached value at the given location.
+    fn get(&self, cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        let loc = (cmd_pos.gen, cmd_pos.pos);
+        let value = self.shard(loc).lock().unwrap().touch(loc);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches the value at the given location, evicting the least recently used values
+    /// of its shard if the shard is full. A value larger than a shard is not cached.
+    fn insert(&self, cmd_pos: CommandPos, value: Vec<u8>) {
+        self.insert_at((cmd_pos.gen, cmd_pos.pos), value);
+    }
+
+    fn insert_at(&self, loc: (u64, u64), value: Vec<u8>) {
+        let size = cache_entry_size(&value);
+        if size > self.shard_capacity {
+            return;
+        }
+        let mut lru = self.shard(loc).lock().unwrap();
+        lru.remove(loc);
+        while lru.size + size > self.shard_capacity {
+            let oldest = match lru.order.values().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            lru.remove(oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        lru.insert(loc, value);
+    }
+
+    /// Drops the value at a superseded location.
+    fn remove(&self, cmd_pos: CommandPos) {
+        let loc = (cmd_pos.gen, cmd_pos.pos);
+        self.shard(loc).lock().unwrap().remove(loc);
+    }
+
+    /// Moves the value at `old` to `new`, where a compaction has copied it.
+    fn remap(&self, old: CommandPos, new: CommandPos) {
+        let old = (old.gen, old.pos);
+        // the shard of `old` is unlocked before the one of `new` is locked
+        let value = self.shard(old).lock().unwrap().remove(old);
+        if let Some(value) = value {
+            self.insert_at((new.gen, new.pos), value);
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            size: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().size)
+                .sum(),
+        }
+    }
+}
+
+/// Cached values keyed by their generations and positions, in the order of their last use.
+#[derive(Default)]
+struct Lru {
+    // value and last use of each location
+    entries: HashMap<(u64, u64), (Vec<u8>, u64)>,
+    // locations by their last use
+    order: BTreeMap<u64, (u64, u64)>,
+    size: u64,
+    next_use: u64,
+}
+
+impl Lru {
+    /// Returns the value at the given location and marks it as the most recently used.
+    fn touch(&mut self, loc: (u64, u64)) -> Option<Vec<u8>> {
+        let entry = self.entries.get_mut(&loc)?;
+        self.order.remove(&entry.1);
+        entry.1 = self.next_use;
+        self.order.insert(self.next_use, loc);
+        self.next_use += 1;
+        Some(entry.0.clone())
+    }
+
+    fn insert(&mut self, loc: (u64, u64), value: Vec<u8>) {
+        self.size += cache_entry_size(&value);
+        self.order.insert(self.next_use, loc);
+        self.entries.insert(loc, (value, self.next_use));
+        self.next_use += 1;
+    }
+
+    fn remove(&mut self, loc: (u64, u64)) -> Option<Vec<u8>> {
+        let (value, last_use) = self.entries.remove(&loc)?;
+        self.order.remove(&last_use);
+        self.size -= cache_entry_size(&value);
+        Some(value)
+    }
+}
+
+fn cache_entry_size(value: &[u8]) -> u64 {
+    value.len() as u64 + CACHE_ENTRY_OVERHEAD
+}
+
+/// The keys of a keyspace with the locations of their commands.
+type Index = SkipMap<Vec<u8>, CommandPos>;
+
+/// The keys of a keyspace of a `KvStore`.
+struct Keyspace {
+    name: String,
+    index: Arc<Index>,
+    // superseded versions that live snapshots may still read
+    versions: Arc<VersionMap>,
+    // bytes taken by the commands that the index points to
+    live: u64,
+    // keys with an expiry time in the index, ordered by the time
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl Keyspace {
+    fn new(name: String) -> Keyspace {
+        Keyspace {
+            name,
+            index: Arc::new(SkipMap::new()),
+            versions: Arc::new(SkipMap::new()),
+            live: 0,
+            expiring: BTreeSet::new(),
+        }
+    }
+}
+
+/// The ids and the indexes of the keyspaces of a store by their names.
+type KeyspaceNames = RwLock<BTreeMap<String, (u32, Arc<Index>)>>;
+
+/// Superseded versions of keys, keyed by the key and the sequence number of the version.
+type VersionMap = SkipMap<(Vec<u8>, u64), Version>;
+
+/// A range of the keys of a `VersionMap`.
+type VersionRange = (Bound<(Vec<u8>, u64)>, Bound<(Vec<u8>, u64)>);
+
+/// A version of a key that has been overwritten or removed.
+#[derive(Debug, Clone, Copy)]
+struct Version {
+    cmd_pos: CommandPos,
+    // sequence number of the write that superseded this version
+    superseded_at: u64,
+}
+
+/// Keeps the version `old` of `key` superseded at `superseded_at` if a live snapshot can
+/// see it.
+fn record_version(
+    versions: &VersionMap,
+    snapshots: &BTreeMap<u64, usize>,
+    key: &[u8],
+    old: CommandPos,
+    superseded_at: u64,
+) {
+    if snapshots.range(old.seq..superseded_at).next().is_some() {
+        let version = Version {
+            cmd_pos: old,
+            superseded_at,
+        };
+        versions.insert((key.to_vec(), old.seq), version);
+    }
+}
+
+/// Counts a command that the index points to as live in its log.
+fn mark_live(gen_stats: &mut BTreeMap<u64, GenerationStats>, cmd_pos: CommandPos) {
+    if let Some(stats) = gen_stats.get_mut(&cmd_pos.gen) {
+        stats.live += cmd_pos.len;
+    }
+}
+
+/// Stops counting a command that the index no longer points to as live in its log.
+fn mark_dead(gen_stats: &mut BTreeMap<u64, GenerationStats>, cmd_pos: CommandPos) {
+    if let Some(stats) = gen_stats.get_mut(&cmd_pos.gen) {
+        stats.live = stats.live.saturating_sub(cmd_pos.len);
+    }
+}
+
+/// Returns the location of the value of `key` seen by a snapshot at sequence number `seq`
+/// taken at time `now`.
+fn snapshot_lookup(
+    index: &Index,
+    versions: &VersionMap,
+    key: &Vec<u8>,
+    seq: u64,
+    now: u64,
+) -> Option<CommandPos> {
+    // A version is saved before the write superseding it changes the index, so a version
+    // that is newer than the snapshot in the index means the one seen is in `versions`.
+    let cmd_pos = match index.get(key) {
+        Some(ref entry) if entry.value().seq <= seq => Some(*entry.value()),
+        _ => versions
+            .range((key.clone(), 0)..=(key.clone(), seq))
+            .next_back()
+            .map(|entry| *entry.value())
+            .filter(|version| seq < version.superseded_at)
+            .map(|version| version.cmd_pos),
+    };
+    cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now))
+}
+
+/// Converts a key range to the range of their versions in a `VersionMap`.
+fn version_range(range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> VersionRange {
+    let start = match range.0 {
+        Bound::Included(ref key) => Bound::Included((key.clone(), 0)),
+        Bound::Excluded(ref key) => Bound::Excluded((key.clone(), u64::max_value())),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let end = match range.1 {
+        Bound::Included(ref key) => Bound::Included((key.clone(), u64::max_value())),
+        Bound::Excluded(ref key) => Bound::Excluded((key.clone(), 0)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+impl<P: ThreadPool> KvsEngine for KvStore<P> {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        run_in_pool(&self.thread_pool, move || {
+            with_writer(&writer, |writer| writer.set(keyspace, key, value, None))
+        })
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        run_in_pool(&self.thread_pool, move || {
+            let cmd_pos = match index.get(&key) {
+                Some(ref entry) if !entry.value().is_expired(now_millis()) => *entry.value(),
+                _ => return Ok(None),
+            };
+            with_reader(&reader_pool, |reader| reader.read_value(cmd_pos).map(Some))
+        })
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        run_in_pool(&self.thread_pool, move || {
+            with_writer(&writer, |writer| writer.remove(keyspace, key))
+        })
+    }
+
+    /// Sets the value of a key to `new` if its current value equals `expected`.
+    ///
+    /// The comparison and the write are done while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` with the current value if it does not equal
+    /// `expected`.
+    ///
+    /// It propagates I/O errors during reading and writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        run_in_pool(&self.thread_pool, move || {
+            with_writer(&writer, |writer| {
+                writer.compare_and_swap(keyspace, key, expected, new)
+            })
+        })
+    }
+
+    /// Applies a batch of writes atomically.
+    ///
+    /// The batch is written as a single log record, so it is either replayed entirely or
+    /// not at all after a crash. Concurrent readers may observe part of the batch while
+    /// it is being applied to the index.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        run_in_pool(&self.thread_pool, move || {
+            with_writer(&writer, |writer| writer.write_batch(keyspace, batch))
+        })
+    }
+
+    /// Runs `f` in an optimistic transaction and returns its result.
+    ///
+    /// The transaction reads through the index without locking. At commit time, the
+    /// values it has read are compared with the current ones while holding the writer
+    /// lock, and its writes are written as a single batch record.
+    fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        run_in_pool(&self.thread_pool, move || {
+            with_reader(&reader_pool, |reader| {
+                let read = |key: &[u8]| -> Result<Option<Vec<u8>>> {
+                    let cmd_pos = match index.get(key) {
+                        Some(ref entry) if !entry.value().is_expired(now_millis()) => {
+                            *entry.value()
+                        }
+                        _ => return Ok(None),
+                    };
+                    reader.read_value(cmd_pos).map(Some)
+                };
+                run_transaction(f, read, |reads, batch| {
+                    with_writer(&writer, |writer| writer.commit(keyspace, reads, batch))
+                })
+            })
+        })
+    }
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        let expires_at = expiry_time(ttl);
+        run_in_pool(&self.thread_pool, move || {
+            with_writer(&writer, |writer| {
+                writer.set(keyspace, key, value, Some(expires_at))
+            })
+        })
+    }
+
+    /// Returns the key/value pairs in the given key range in key order.
+    ///
+    /// The pairs are collected while the store keeps accepting writes, so a write
+    /// that happens during the scan may or may not be seen.
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let range = owned_range(&range);
+        run_in_pool(&self.thread_pool, move || {
+            let now = now_millis();
+            with_reader(&reader_pool, |reader| {
+                index
+                    .range(range)
+                    .filter(|entry| !entry.value().is_expired(now))
+                    .take(limit.unwrap_or(usize::max_value()))
+                    .map(|entry| -> Result<_> {
+                        Ok((entry.key().clone(), reader.read_value(*entry.value())?))
+                    })
+                    .collect()
+            })
+        })
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`.
+    ///
+    /// The sealed logs are hard-linked, or copied if `dest` is on another file system,
+    /// while holding the writer lock. The active log is copied up to its length at that
+    /// moment after the lock is released.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if `dest` is not empty.
+    ///
+    /// It propagates I/O errors during copying the logs.
+    fn checkpoint(&self, dest: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        run_in_pool(&self.thread_pool, move || {
+            create_checkpoint_dir(&dest)?;
+            let (gen, active_log, len) = writer.lock().unwrap().start_checkpoint(&dest)?;
+            let mut copy = File::create(log_path(&dest, gen))?;
+            io::copy(&mut active_log.take(len), &mut copy)?;
+            // the linked logs may not have been synced yet either
+            for entry in fs::read_dir(&dest)? {
+                File::open(entry?.path())?.sync_all()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the statistics of the store.
+    ///
+    /// The live and uncompacted bytes are those of the commands in the logs, including
+    /// their framing. The disk size also counts hint files. The keys and the live bytes
+    /// are those of the keyspace, and the rest is shared by all the keyspaces.
+    fn stats(&self) -> Box<dyn Future<Item = EngineStats, Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyspace = self.keyspace;
+        let path = self.path.clone();
+        run_in_pool(&self.thread_pool, move || {
+            let mut stats = writer.lock().unwrap().stats(keyspace)?;
+            let mut disk_size = 0;
+            for entry in fs::read_dir(&*path)? {
+                disk_size += entry?.metadata()?.len();
+            }
+            stats.disk_size = Some(disk_size);
+            Ok(stats)
+        })
+    }
+
+    /// Returns a stream of the changes of the keys starting with `prefix`.
+    ///
+    /// The events are sent by the writer after each write, so they are in the order of
+    /// the writes. Expired keys are reported as removed when they are swept from the
+    /// index. A watch that falls behind the writes is ended with `KvsError::WatchLagged`.
+    fn watch(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.keyspace(self.keyspace) {
+            return Box::new(stream::once(Err(e)));
+        }
+        writer.watchers.add(self.keyspace, prefix)
+    }
+
+    /// Returns a handle of the keyspace `name`.
+    ///
+    /// The handle shares the logs, the writer and the readers of the store.
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let (keyspace, index) = self
+            .names
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|(keyspace, index)| (*keyspace, Arc::clone(index)))
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        Ok(KvStore {
+            keyspace,
+            index,
+            ..self.clone()
+        })
+    }
+
+    /// Creates an empty keyspace and records it in the `KEYSPACES` file.
+    fn create_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        run_in_pool(&self.thread_pool, move || {
+            writer.lock().unwrap().create_keyspace(name)
+        })
+    }
+
+    /// Drops a keyspace and removes its keys from the index.
+    ///
+    /// The commands of its keys become garbage for compaction. The handles and the
+    /// snapshots of the keyspace see it empty, and writes through its handles fail with
+    /// `KvsError::KeyspaceDropped`.
+    fn drop_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        run_in_pool(&self.thread_pool, move || {
+            writer.lock().unwrap().drop_keyspace(&name)
+        })
+    }
+
+    fn list_keyspaces(&self) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let names = Arc::clone(&self.names);
+        run_in_pool(&self.thread_pool, move || {
+            Ok(names.read().unwrap().keys().cloned().collect())
+        })
+    }
+}
+
+/// Locks the writer to run `f`.
+///
+/// In `Durability::GroupCommit` mode, it waits for the writes to be synced after
+/// releasing the lock, so the writers queued behind it can share the sync.
+fn with_writer<F, R>(writer: &Mutex<KvStoreWriter>, f: F) -> Result<R>
+where
+    F: FnOnce(&mut KvStoreWriter) -> Result<R>,
+{
+    let mut guard = writer.lock().unwrap();
+    let res = f(&mut *guard)?;
+    if guard.durability != Durability::GroupCommit {
+        return Ok(res);
+    }
+    let ticket = guard.group_commit.last_write();
+    let group_commit = Arc::clone(&guard.group_commit);
+    let active_log = Arc::clone(&guard.active_log);
+    drop(guard);
+    group_commit.commit(ticket, || Ok(active_log.lock().unwrap().sync_data()?))?;
+    Ok(res)
+}
+
+/// Readers shared by the threads that read the store.
+///
+/// A reader keeps its log files open between reads. When all of them are taken, a new one
+/// is created for a single read and its files are closed afterwards.
+struct ReaderPool {
+    readers: ArrayQueue<KvStoreReader>,
+    path: Arc<PathBuf>,
+    merges: Arc<AtomicU64>,
+    cache: Option<Arc<ValueCache>>,
+    sealed: Arc<SealedLogs>,
+}
+
+/// Takes a reader from the pool to run `f` and puts it back afterwards.
+fn with_reader<F, R>(reader_pool: &ReaderPool, f: F) -> Result<R>
+where
+    F: FnOnce(&KvStoreReader) -> Result<R>,
+{
+    let reader = reader_pool.readers.pop().unwrap_or_else(|_| KvStoreReader {
+        path: Arc::clone(&reader_pool.path),
+        merges: Arc::clone(&reader_pool.merges),
+        seen_merges: Cell::new(reader_pool.merges.load(Ordering::SeqCst)),
+        cache: reader_pool.cache.clone(),
+        sealed: Arc::clone(&reader_pool.sealed),
+        readers: RefCell::new(BTreeMap::new()),
+        mmaps: RefCell::new(BTreeMap::new()),
+    });
+    let res = f(&reader);
+    // the pool is full if the reader was created above
+    let _ = reader_pool.readers.push(reader);
+    res
+}
+
+/// A single thread reader.
+///
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // number of merges finished so far
+    merges: Arc<AtomicU64>,
+    // number of merges when the file handles were last closed
+    seen_merges: Cell<u64>,
+    // cache of values shared by all readers
+    cache: Option<Arc<ValueCache>>,
+    sealed: Arc<SealedLogs>,
+    // handles of the active log, or of logs that were active when they were opened
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    // memory maps of the sealed logs taken from `sealed`, so that reading a sealed log
+    // does not lock the maps shared by all readers. It also keeps the maps that are not
+    // shared, of stale logs and of logs merged since the handles were closed, until the
+    // handles are closed again.
+    mmaps: RefCell<BTreeMap<u64, Arc<Mmap>>>,
+}
+
+impl KvStoreReader {
+    /// Close all file handles if a merge has finished since they were opened.
+    ///
+    /// `merges` is incremented after a merge has switched the index to the merged log.
+    /// The index contains no entries in the logs that were merged, so we can safely
+    /// close their file handles and the stale files can be deleted. The handles of the
+    /// other logs are simply opened again when they are read.
+    fn close_stale_handles(&self) {
+        let merges = self.merges.load(Ordering::SeqCst);
+        if merges != self.seen_merges.get() {
+            self.readers.borrow_mut().clear();
+            self.mmaps.borrow_mut().clear();
+            self.seen_merges.set(merges);
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    ///
+    /// A sealed log is read from its memory map, and the active log through a buffered
+    /// file handle.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut dyn Read) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        if cmd_pos.gen < self.sealed.active_gen.load(Ordering::SeqCst) {
+            let mmap = self.mmap(cmd_pos.gen)?;
+            let start = cmd_pos.pos as usize;
+            let mut cmd_reader =
+                mmap.get(start..start + cmd_pos.len as usize)
+                    .ok_or(KvsError::CorruptedLog {
+                        gen: cmd_pos.gen,
+                        offset: cmd_pos.pos,
+                    })?;
+            return f(&mut cmd_reader);
+        }
+
+        let mut readers = self.readers.borrow_mut();
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        // We don't use entry API here because we want the errors to be propogated.
+        if !readers.contains_key(&cmd_pos.gen) {
+            // A stale generation that snapshots still read has been renamed.
+            let file = match File::open(log_path(&self.path, cmd_pos.gen)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    File::open(stale_log_path(&self.path, cmd_pos.gen))?
+                }
+                res => res?,
+            };
+            readers.insert(cmd_pos.gen, BufReaderWithPos::new(file)?);
+        }
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut cmd_reader = reader.take(cmd_pos.len);
+        f(&mut cmd_reader)
+    }
+
+    /// Returns the memory map of a sealed log, mapping it if no reader has done so yet.
+    fn mmap(&self, gen: u64) -> Result<Arc<Mmap>> {
+        if let Some(mmap) = self.mmaps.borrow().get(&gen) {
+            return Ok(Arc::clone(mmap));
+        }
+        let shared = self.sealed.maps.lock().unwrap().get(&gen).cloned();
+        let mmap = match shared {
+            Some(mmap) => mmap,
+            None => {
+                let (file, stale) = match File::open(log_path(&self.path, gen)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        (File::open(stale_log_path(&self.path, gen))?, true)
+                    }
+                    res => (res?, false),
+                };
+                // Safe as long as the log is not modified, which is never done to a
+                // sealed log.
+                let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+                let mut maps = self.sealed.maps.lock().unwrap();
+                // A log merged since the handles were closed is no longer shared, and
+                // neither is a stale log, so that they are unmapped when no reader
+                // reads them.
+                if !stale && self.merges.load(Ordering::SeqCst) == self.seen_merges.get() {
+                    let shared = maps.entry(gen).or_insert(mmap);
+                    Arc::clone(shared)
+                } else {
+                    mmap
+                }
+            }
+        };
+        self.mmaps.borrow_mut().insert(gen, Arc::clone(&mmap));
+        Ok(mmap)
+    }
+
+    // Read the value of the `Set` command at the given `CommandPos`, from the cache if
+    // it is there.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(cmd_pos)) {
+            return Ok(value);
+        }
+        if let Command::Set { value, .. } = self.read_command(cmd_pos)? {
+            if let Some(ref cache) = self.cache {
+                cache.insert(cmd_pos, value.clone());
+            }
+            Ok(value)
+        } else {
+            Err(KvsError::UnexpectedCommandType)
+        }
+    }
+
+    // Read the log file at the given `CommandPos`, verify its checksum and decode
+    // it to `Command`.
+    //
+    // A command in a batch has no frame of its own. The checksum of the batch record was
+    // verified when it was loaded. Neither has a command in a legacy log, which has no
+    // checksum at all.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        let corrupted = || KvsError::CorruptedLog {
+            gen: cmd_pos.gen,
+            offset: cmd_pos.pos,
+        };
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            let payload = if cmd_pos.unframed {
+                let mut payload = Vec::with_capacity(cmd_pos.len as usize);
+                cmd_reader.read_to_end(&mut payload)?;
+                payload
+            } else {
+                match read_frame(&mut cmd_reader, cmd_pos.len)? {
+                    Frame::Record(payload) => payload,
+                    _ => return Err(corrupted()),
+                }
+            };
+            match Command::decode(&payload) {
+                Some(Command::Batch(_)) | Some(Command::Sequence(_)) | None => Err(corrupted()),
+                Some(cmd) => Ok(cmd),
+            }
+        })
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            merges: Arc::clone(&self.merges),
+            seen_merges: Cell::new(self.seen_merges.get()),
+            cache: self.cache.clone(),
+            sealed: Arc::clone(&self.sealed),
+            // don't use other KvStoreReader's readers
+            readers: RefCell::new(BTreeMap::new()),
+            mmaps: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    // size and live bytes of each log
+    gen_stats: BTreeMap<u64, GenerationStats>,
+    // format version of the data directory. Logs in an older format are
+    // rewritten in the current format during a compaction.
+    format_version: u32,
+    path: Arc<PathBuf>,
+    // keyspaces by their ids
+    keyspaces: BTreeMap<u32, Keyspace>,
+    // keyspaces by their names, shared with the handles and kept in step with `keyspaces`
+    names: Arc<KeyspaceNames>,
+    // id of the next keyspace to create. Ids are never reused.
+    next_keyspace_id: u32,
+    // sequence number of the next write
+    next_seq: u64,
+    // sequence numbers of the writes in each log that starts with one
+    seq_ranges: BTreeMap<u64, Range<u64>>,
+    // number of live snapshots taken at each sequence number
+    snapshots: BTreeMap<u64, usize>,
+    // stale generations kept with a `stale` extension name for the versions
+    pinned_gens: Vec<u64>,
+    // when the compactor started working on sealed generations, if it is
+    compacting: Option<Instant>,
+    // how long the last finished compaction took
+    last_compaction: Option<Duration>,
+    watchers: Watchers,
+    compactions: Option<mpsc::Sender<CompactionTask>>,
+    compactor: Option<thread::JoinHandle<()>>,
+    compaction_trigger: CompactionTrigger,
+    // size at which the active log is rolled over to a new generation
+    max_log_size: Option<u64>,
+    // garbage ratio above which a log is merged
+    merge_threshold: f64,
+    // number of the latest writes whose logs are not merged
+    change_retention: u64,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    // another handle of the active log, which is synced without the writer lock
+    active_log: Arc<Mutex<File>>,
+    // lock of the data directory. It is released when the writer is dropped, after the
+    // compactor has stopped, so no thread that can still write runs without it.
+    _lock: File,
+}
+
+impl KvStoreWriter {
+    fn set(
+        &mut self,
+        keyspace: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let index = Arc::clone(&self.keyspace(keyspace)?.index);
+        let seq = self.next_seq;
+        let cmd = Command::set(keyspace, key, value, expires_at);
+        let pos = self.writer.pos;
+        write_frame(&mut self.writer, &cmd.encode())?;
+        self.flush_log()?;
+        if let Command::Set { key, value, .. } = cmd {
+            if let Some(old_cmd) = index.get(&key) {
+                self.supersede(keyspace, &key, *old_cmd.value());
+            }
+            let cmd_pos = CommandPos {
+                seq,
+                expires_at,
+                ..CommandPos::from((self.current_gen, pos..self.writer.pos))
+            };
+            self.add_live(keyspace, cmd_pos);
+            if let Some(expires_at) = expires_at {
+                if let Some(space) = self.keyspaces.get_mut(&keyspace) {
+                    space.expiring.insert((expires_at, key.clone()));
+                }
+            }
+            let event = if self.watchers.wants(keyspace, &key) {
+                Some(WatchEvent::Set {
+                    key: key.clone(),
+                    value,
+                })
+            } else {
+                None
+            };
+            index.insert(key, cmd_pos);
+            if let Some(event) = event {
+                self.watchers.publish(keyspace, seq, vec![event]);
+            }
+        }
+        self.advance_seq();
+
+        self.after_write()
+    }
+
+    fn remove(&mut self, keyspace: u32, key: Vec<u8>) -> Result<()> {
+        let index = Arc::clone(&self.keyspace(keyspace)?.index);
+        let exists = index
+            .get(&key)
+            .map_or(false, |entry| !entry.value().is_expired(now_millis()));
+        if exists {
+            // the "remove" command itself is never live, so it can be deleted by a merge
+            let cmd = Command::remove(keyspace, key);
+            write_frame(&mut self.writer, &cmd.encode())?;
+            self.flush_log()?;
+            if let Command::Remove { key, .. } = cmd {
+                let old_cmd = *index.get(&key).expect("key not found").value();
+                self.supersede(keyspace, &key, old_cmd);
+                index.remove(&key);
+                if self.watchers.wants(keyspace, &key) {
+                    let seq = self.next_seq;
+                    let event = WatchEvent::Remove { key };
+                    self.watchers.publish(keyspace, seq, vec![event]);
+                }
+            }
+            self.advance_seq();
+
+            self.after_write()
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        keyspace: u32,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let current = self.current_value(keyspace, &key)?;
+        if current != expected {
+            return Err(KvsError::ConditionFailed(current));
+        }
+        match new {
+            Some(value) => self.set(keyspace, key, value, None),
+            None if current.is_some() => self.remove(keyspace, key),
+            None => Ok(()),
+        }
+    }
+
+    fn write_batch(&mut self, keyspace: u32, batch: WriteBatch) -> Result<()> {
+        let index = Arc::clone(&self.keyspace(keyspace)?.index);
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(keyspace, key, value, None),
+                BatchOp::Remove { key } => Command::remove(keyspace, key),
+            })
+            .collect();
+        let cmd = Command::Batch(cmds);
+        let pos = self.writer.pos;
+        write_frame(&mut self.writer, &cmd.encode())?;
+        self.flush_log()?;
+        if let Command::Batch(cmds) = cmd {
+            let watchers = &self.watchers;
+            let events: Vec<WatchEvent> = cmds
+                .iter()
+                .filter_map(|cmd| match cmd {
+                    Command::Set { key, value, .. } if watchers.wants(keyspace, key) => {
+                        Some(WatchEvent::Set {
+                            key: key.clone(),
+                            value: value.clone(),
+                        })
+                    }
+                    Command::Remove { key, .. } if watchers.wants(keyspace, key) => {
+                        Some(WatchEvent::Remove { key: key.clone() })
+                    }
+                    _ => None,
+                })
+                .collect();
+            // all the commands in a batch share one sequence number
+            let seq = self.next_seq;
+            let space = self
+                .keyspaces
+                .get_mut(&keyspace)
+                .expect("keyspace not found");
+            let (versions, expiring) = (&space.versions, &mut space.expiring);
+            let snapshots = &self.snapshots;
+            let gen_stats = &mut self.gen_stats;
+            let cache = &self.reader.cache;
+            let mut dead = 0;
+            let live = index_batch(
+                &index,
+                self.current_gen,
+                pos..self.writer.pos,
+                cmds,
+                seq,
+                |key, old_cmd| {
+                    record_version(versions, snapshots, key, old_cmd, seq);
+                    if let Some(expires_at) = old_cmd.expires_at {
+                        expiring.remove(&(expires_at, key.to_vec()));
+                    }
+                    mark_dead(gen_stats, old_cmd);
+                    dead += old_cmd.len;
+                    if let Some(ref cache) = cache {
+                        cache.remove(old_cmd);
+                    }
+                },
+            );
+            space.live = (space.live + live).saturating_sub(dead);
+            if let Some(stats) = self.gen_stats.get_mut(&self.current_gen) {
+                stats.live += live;
+            }
+            self.watchers.publish(keyspace, seq, events);
+        }
+        self.advance_seq();
+
+        self.after_write()
+    }
+
+    /// Applies the writes of a transaction if the values it has read are unchanged.
+    ///
+    /// Returns `false` without writing anything if any of them has changed.
+    fn commit(
+        &mut self,
+        keyspace: u32,
+        reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        for (key, value) in reads {
+            if self.current_value(keyspace, &key)? != value {
+                return Ok(false);
+            }
+        }
+        self.write_batch(keyspace, batch)?;
+        Ok(true)
+    }
+
+    /// Reads the current value of a key of a keyspace.
+    fn current_value(&self, keyspace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.keyspace(keyspace)?.index.get(key) {
+            Some(ref entry) if !entry.value().is_expired(now_millis()) => {
+                self.reader.read_value(*entry.value()).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes up to `EXPIRY_SWEEP_CHUNK` expired keys of each keyspace.
+    ///
+    /// The keys are removed by a batch of `Remove` commands, so the removal is logged,
+    /// watched and sent to the change feed like any other write.
+    ///
+    /// Returns whether more keys may have expired already.
+    fn remove_expired(&mut self) -> Result<bool> {
+        let now = now_millis();
+        let mut more = false;
+        let keyspaces: Vec<u32> = self.keyspaces.keys().cloned().collect();
+        for keyspace in keyspaces {
+            let expired: Vec<Vec<u8>> = match self.keyspaces.get(&keyspace) {
+                Some(space) => space
+                    .expiring
+                    .iter()
+                    .take_while(|(expires_at, _)| *expires_at <= now)
+                    .take(EXPIRY_SWEEP_CHUNK)
+                    .map(|(_, key)| key.clone())
+                    .collect(),
+                None => continue,
+            };
+            if expired.is_empty() {
+                continue;
+            }
+            more |= expired.len() == EXPIRY_SWEEP_CHUNK;
+            let mut batch = WriteBatch::new();
+            for key in expired {
+                batch.remove_bytes(key);
+            }
+            self.write_batch(keyspace, batch)?;
+        }
+        Ok(more)
+    }
+
+    /// Rolls the active log over if it is full, and starts a compaction if the stale
+    /// commands exceed the compaction trigger.
+    fn after_write(&mut self) -> Result<()> {
+        if self.needs_compaction() {
+            self.compact()?;
+        }
+        // a compaction that merges nothing leaves the active log as it is
+        if self
+            .max_log_size
+            .map_or(false, |size| self.writer.pos >= size)
+        {
+            self.current_gen += 1;
+            self.switch_log(self.current_gen)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes a write to the active log, and syncs it if every write has to be synced.
+    fn flush_log(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.durability == Durability::EveryWrite {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        self.group_commit.record_write();
+        if let Some(stats) = self.gen_stats.get_mut(&self.current_gen) {
+            stats.size = self.writer.pos;
+        }
+        Ok(())
+    }
+
+    /// Switches the active log to a new log of the given generation.
+    ///
+    /// Unless syncing is left to the operating system, the previous log is synced first,
+    /// so that syncing the new log covers all the writes so far.
+    fn switch_log(&mut self, gen: u64) -> Result<()> {
+        self.writer = new_log_file(&self.path, gen, self.next_seq)?;
+        self.seq_ranges.insert(gen, self.next_seq..self.next_seq);
+        let file = self.writer.writer.get_ref().try_clone()?;
+        let mut active_log = self.active_log.lock().unwrap();
+        if self.durability != Durability::None {
+            active_log.sync_data()?;
+        }
+        *active_log = file;
+        self.reader.sealed.active_gen.store(gen, Ordering::SeqCst);
+        self.gen_stats.insert(
+            gen,
+            // the sequence number the log starts with is not garbage
+            GenerationStats {
+                gen,
+                size: self.writer.pos,
+                live: self.writer.pos,
+            },
+        );
+        Ok(())
+    }
+
+    /// Moves on to the next sequence number once a write is in the active log.
+    fn advance_seq(&mut self) {
+        self.next_seq += 1;
+        if let Some(seqs) = self.seq_ranges.get_mut(&self.current_gen) {
+            seqs.end = self.next_seq;
+        }
+    }
+
+    /// Returns the sequence number of the oldest write from which all the writes are
+    /// still in the logs.
+    fn oldest_change(&self) -> u64 {
+        let mut oldest = self.next_seq;
+        for seqs in self.seq_ranges.values().rev() {
+            if seqs.end != oldest {
+                break;
+            }
+            oldest = seqs.start;
+        }
+        oldest
+    }
+
+    /// Opens the logs that hold the writes after `since`, and registers a feed of the
+    /// writes to the keyspace to come.
+    fn follow_changes(
+        &mut self,
+        keyspace: u32,
+        since: u64,
+    ) -> Result<(Vec<ChangeLog>, ChangeStream)> {
+        self.keyspace(keyspace)?;
+        let oldest = self.oldest_change();
+        if since.saturating_add(1) < oldest {
+            return Err(KvsError::ChangesTooOld {
+                requested: since,
+                oldest,
+            });
+        }
+        let mut logs = Vec::new();
+        for (&gen, seqs) in &self.seq_ranges {
+            if seqs.start < oldest || seqs.end <= since.saturating_add(1) {
+                continue;
+            }
+            // The handle can be read even if the log is merged and deleted in the meantime.
+            let file = File::open(log_path(&self.path, gen))?;
+            let len = if gen == self.current_gen {
+                self.writer.pos
+            } else {
+                file.metadata()?.len()
+            };
+            logs.push(ChangeLog {
+                gen,
+                file,
+                len,
+                first_seq: seqs.start,
+            });
+        }
+        Ok((logs, self.watchers.add_feed(keyspace)))
+    }
+
+    /// Returns the statistics of a keyspace. Only the keys and the live bytes are its own.
+    fn stats(&self, keyspace: u32) -> Result<EngineStats> {
+        let space = self.keyspace(keyspace)?;
+        let dead = self.gen_stats.values().map(GenerationStats::dead).sum();
+        Ok(EngineStats {
+            keys: space.index.len() as u64,
+            live_bytes: Some(space.live),
+            uncompacted_bytes: Some(dead),
+            generations: Some(self.gen_stats.len() as u64),
+            compactions: Some(self.reader.merges.load(Ordering::SeqCst)),
+            last_compaction: self.last_compaction,
+            disk_size: None,
+        })
+    }
+
+    /// Returns the keyspace with the given id.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceDropped` if the keyspace has been dropped.
+    fn keyspace(&self, keyspace: u32) -> Result<&Keyspace> {
+        self.keyspaces
+            .get(&keyspace)
+            .ok_or(KvsError::KeyspaceDropped)
+    }
+
+    /// Returns the id of the keyspace with the given name.
+    fn keyspace_id(&self, name: &str) -> Result<u32> {
+        self.keyspaces
+            .iter()
+            .find(|(_, space)| space.name == name)
+            .map(|(&keyspace, _)| keyspace)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))
+    }
+
+    /// Returns the names of the keyspaces listed in the `KEYSPACES` file, by their ids.
+    fn catalog(&self) -> BTreeMap<u32, String> {
+        self.keyspaces
+            .iter()
+            .filter(|(&keyspace, _)| keyspace != DEFAULT_KEYSPACE_ID)
+            .map(|(&keyspace, space)| (keyspace, space.name.clone()))
+            .collect()
+    }
+
+    /// Creates an empty keyspace with a new id.
+    fn create_keyspace(&mut self, name: String) -> Result<()> {
+        check_keyspace_name(&name)?;
+        if self.keyspace_id(&name).is_ok() {
+            return Err(KvsError::KeyspaceExists(name));
+        }
+        let keyspace = self.next_keyspace_id;
+        let mut catalog = self.catalog();
+        catalog.insert(keyspace, name.clone());
+        write_keyspaces(&self.path, keyspace + 1, &catalog)?;
+        self.next_keyspace_id = keyspace + 1;
+        let space = Keyspace::new(name.clone());
+        let index = Arc::clone(&space.index);
+        self.keyspaces.insert(keyspace, space);
+        self.names.write().unwrap().insert(name, (keyspace, index));
+        Ok(())
+    }
+
+    /// Drops a keyspace. The commands its index points to become stale.
+    ///
+    /// Once the keyspace is no longer in the `KEYSPACES` file, the commands with its id
+    /// are ignored by the next open, so nothing has to be written to the log.
+    fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        let keyspace = self.keyspace_id(name)?;
+        if keyspace == DEFAULT_KEYSPACE_ID {
+            return Err(KvsError::StringError(
+                "The default keyspace cannot be dropped".to_owned(),
+            ));
+        }
+        let mut catalog = self.catalog();
+        catalog.remove(&keyspace);
+        write_keyspaces(&self.path, self.next_keyspace_id, &catalog)?;
+        let space = self
+            .keyspaces
+            .remove(&keyspace)
+            .expect("keyspace not found");
+        self.names.write().unwrap().remove(name);
+        for entry in space.index.iter() {
+            mark_dead(&mut self.gen_stats, *entry.value());
+            if let Some(ref cache) = self.reader.cache {
+                cache.remove(*entry.value());
+            }
+        }
+        // the handles and the snapshots of the keyspace share them
+        space.index.clear();
+        space.versions.clear();
+        self.watchers.remove_keyspace(keyspace);
+
+        if self.needs_compaction() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        let dead: u64 = self.gen_stats.values().map(GenerationStats::dead).sum();
+        match self.compaction_trigger {
+            CompactionTrigger::StaleBytes(bytes) => dead > bytes,
+            CompactionTrigger::StaleRatio(ratio) => {
+                let live: u64 = self.gen_stats.values().map(|stats| stats.live).sum();
+                dead > 0 && dead as f64 > live as f64 * ratio
+            }
+        }
+    }
+
+    /// Counts a command that the index of a keyspace now points to as live.
+    fn add_live(&mut self, keyspace: u32, cmd_pos: CommandPos) {
+        mark_live(&mut self.gen_stats, cmd_pos);
+        if let Some(space) = self.keyspaces.get_mut(&keyspace) {
+            space.live += cmd_pos.len;
+        }
+    }
+
+    /// Marks a command of a keyspace superseded by the next write as dead, drops its value
+    /// from the cache, and keeps its version if a live snapshot can see it.
+    fn supersede(&mut self, keyspace: u32, key: &[u8], old_cmd: CommandPos) {
+        if let Some(space) = self.keyspaces.get_mut(&keyspace) {
+            record_version(
+                &space.versions,
+                &self.snapshots,
+                key,
+                old_cmd,
+                self.next_seq,
+            );
+            space.live = space.live.saturating_sub(old_cmd.len);
+            if let Some(expires_at) = old_cmd.expires_at {
+                space.expiring.remove(&(expires_at, key.to_vec()));
+            }
+        }
+        mark_dead(&mut self.gen_stats, old_cmd);
+        if let Some(ref cache) = self.reader.cache {
+            cache.remove(old_cmd);
+        }
+    }
+
+    /// Links the sealed logs into the checkpoint directory `dest`.
+    ///
+    /// Returns the generation, a handle and the current length of the active log. The
+    /// handle can be read even if the log is merged and deleted in the meantime.
+    fn start_checkpoint(&self, dest: &Path) -> Result<(u64, File, u64)> {
+        write_format_version(dest, self.format_version)?;
+        write_keyspaces(dest, self.next_keyspace_id, &self.catalog())?;
+        // The log of a running compaction is not in `gen_stats` yet, while the logs it
+        // merges are still there.
+        let current_gen = self.current_gen;
+        for &gen in self.gen_stats.keys().filter(|&&gen| gen != current_gen) {
+            link_or_copy(&log_path(&self.path, gen), &log_path(dest, gen))?;
+            let hint = hint_path(&self.path, gen);
+            if hint.exists() {
+                link_or_copy(&hint, &hint_path(dest, gen))?;
+            }
+        }
+        let active_log = File::open(log_path(&self.path, self.current_gen))?;
+        Ok((self.current_gen, active_log, self.writer.pos))
+    }
+
+    /// Registers a snapshot that sees all the writes so far.
+    ///
+    /// Returns the sequence number of the last write.
+    fn register_snapshot(&mut self) -> u64 {
+        let seq = self.next_seq - 1;
+        *self.snapshots.entry(seq).or_insert(0) += 1;
+        seq
+    }
+
+    /// Unregisters a snapshot, and drops the versions and the stale log files that no
+    /// live snapshot can read any more.
+    fn release_snapshot(&mut self, seq: u64) {
+        if let Some(count) = self.snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+
+        let snapshots = &self.snapshots;
+        for space in self.keyspaces.values() {
+            let unused: Vec<_> = space
+                .versions
+                .iter()
+                .filter(|entry| {
+                    let range = entry.key().1..entry.value().superseded_at;
+                    snapshots.range(range).next().is_none()
+                })
+                .map(|entry| entry.key().clone())
+                .collect();
+            for key in unused {
+                space.versions.remove(&key);
+            }
+        }
+
+        let pinned = self.pinned_gens();
+        let path = &self.path;
+        self.pinned_gens.retain(|&gen| {
+            if pinned.contains(&gen) {
+                return true;
+            }
+            let file_path = stale_log_path(path, gen);
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+            false
+        });
+    }
+
+    /// Returns the generations that the kept versions are in.
+    fn pinned_gens(&self) -> BTreeSet<u64> {
+        self.keyspaces
+            .values()
+            .flat_map(|space| space.versions.iter())
+            .map(|entry| entry.value().cmd_pos.gen)
+            .collect()
+    }
+
+    /// Seals the active log and hands the logs worth merging over to the compactor.
+    ///
+    /// A log is merged if its garbage ratio exceeds the merge threshold, or if the data
+    /// directory is in an older format, in which case all the logs are rewritten. Logs
+    /// holding any of the writes kept by the change retention are never merged. New
+    /// writes go to a fresh log while the compactor copies the live commands of the merged
+    /// logs in the background. Nothing happens if a compaction is already running or no
+    /// log is worth merging.
+    fn compact(&mut self) -> Result<()> {
+        if self.compacting.is_some() {
+            return Ok(());
+        }
+        let upgrade = self.format_version < FORMAT_VERSION;
+        let threshold = self.merge_threshold;
+        let retained_from = self.next_seq.saturating_sub(self.change_retention);
+        let seq_ranges = &self.seq_ranges;
+        let merged: Vec<u64> = self
+            .gen_stats
+            .values()
+            .filter(|stats| upgrade || stats.live == 0 || stats.garbage_ratio() > threshold)
+            .filter(|stats| {
+                seq_ranges.get(&stats.gen).map_or(true, |seqs| {
+                    seqs.end <= retained_from || seqs.start == seqs.end
+                })
+            })
+            .map(|stats| stats.gen)
+            .collect();
+        if merged.is_empty() {
+            return Ok(());
+        }
+        let oldest_kept = self
+            .gen_stats
+            .keys()
+            .cloned()
+            .find(|gen| !merged.contains(gen));
+
+        // increase current gen by 2. current_gen + 1 is for the compaction file
+        let compaction_gen = self.current_gen + 1;
+        self.current_gen += 2;
+        self.switch_log(self.current_gen)?;
+
+        let task = CompactionTask {
+            gen: compaction_gen,
+            merged,
+            oldest_kept,
+            format_version: self.format_version,
+            indexes: self
+                .keyspaces
+                .iter()
+                .map(|(&keyspace, space)| (keyspace, Arc::clone(&space.index)))
+                .collect(),
+        };
+        if let Some(ref compactions) = self.compactions {
+            self.compacting = compactions.send(task).ok().map(|_| Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Swaps in the positions written by a finished compaction and removes the merged logs.
+    ///
+    /// An entry is only moved if it has not been overwritten or removed since the
+    /// compactor read it, and its keyspace has not been dropped. Otherwise the copy in the
+    /// compaction file is stale, and it is counted as dead.
+    fn finish_compaction(&mut self, output: CompactionOutput) -> Result<()> {
+        self.last_compaction = self.compacting.take().map(|started| started.elapsed());
+
+        // All live commands are in the current format now.
+        if output.format_version < FORMAT_VERSION && self.format_version < FORMAT_VERSION {
+            write_format_version(&self.path, FORMAT_VERSION)?;
+            self.format_version = FORMAT_VERSION;
+        }
+
+        // The removals still hide values in older logs, so they are not garbage.
+        let mut stats = GenerationStats {
+            gen: output.gen,
+            size: output.size,
+            live: output.removals,
+        };
+        for (keyspace, key, old_cmd, new_cmd) in output.moved {
+            let space = match self.keyspaces.get_mut(&keyspace) {
+                Some(space) => space,
+                None => continue,
+            };
+            let unchanged = space
+                .index
+                .get(&key)
+                .map_or(false, |entry| entry.value().is_at(old_cmd));
+            if unchanged {
+                space.index.insert(key, new_cmd);
+                space.live = space.live + new_cmd.len - old_cmd.len;
+                stats.live += new_cmd.len;
+                if let Some(ref cache) = self.reader.cache {
+                    cache.remap(old_cmd, new_cmd);
+                }
+            }
+        }
+        // The expired keys that have not been written since are removed like the sweeper
+        // does, once the merged logs are gone.
+        let mut expired: BTreeMap<u32, WriteBatch> = BTreeMap::new();
+        for (keyspace, key, old_cmd) in output.expired {
+            let unchanged = self.keyspaces.get(&keyspace).map_or(false, |space| {
+                space
+                    .index
+                    .get(&key)
+                    .map_or(false, |entry| entry.value().is_at(old_cmd))
+            });
+            if unchanged {
+                expired.entry(keyspace).or_default().remove_bytes(key);
+            }
+        }
+        self.gen_stats.insert(output.gen, stats);
+
+        self.reader.merges.fetch_add(1, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // remove merged log files
+        // Note that actually these files are not deleted immediately because `KvStoreReader`s
+        // still keep open file handles and memory maps. When `KvStoreReader` is used next
+        // time, it will clear its stale file handles and maps. On Unix, the files will be
+        // deleted after all the handles are closed. On Windows, the deletions below will
+        // fail and stale files are expected to be deleted in the next compaction.
+
+        let pinned = self.pinned_gens();
+        for merged_gen in output.merged {
+            self.gen_stats.remove(&merged_gen);
+            self.seq_ranges.remove(&merged_gen);
+            self.reader.sealed.maps.lock().unwrap().remove(&merged_gen);
+            let file_path = log_path(&self.path, merged_gen);
+            if pinned.contains(&merged_gen) {
+                // Snapshots still read it. It is deleted when they are released.
+                let stale_path = stale_log_path(&self.path, merged_gen);
+                if let Err(e) = fs::rename(&file_path, &stale_path) {
+                    error!("{:?} cannot be renamed: {}", file_path, e);
+                }
+                self.pinned_gens.push(merged_gen);
+            } else if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+            let hint_path = hint_path(&self.path, merged_gen);
+            if hint_path.exists() {
+                if let Err(e) = fs::remove_file(&hint_path) {
+                    error!("{:?} cannot be deleted: {}", hint_path, e);
+                }
+            }
+        }
+
+        for (keyspace, batch) in expired {
+            self.write_batch(keyspace, batch)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        // Closing the channel stops the compactor after the compaction in progress.
+        self.compactions.take();
+        if let Some(compactor) = self.compactor.take() {
+            // The compactor itself may hold the last reference to the writer.
+            if compactor.thread().id() != thread::current().id() && compactor.join().is_err() {
+                error!("Compactor thread panicked");
+            }
+        }
+    }
+}
+
+/// A merge of the logs of the generations in `merged` into the log of generation `gen`.
+struct CompactionTask {
+    gen: u64,
+    merged: Vec<u64>,
+    // oldest generation that is not merged
+    oldest_kept: Option<u64>,
+    // format version of the data directory when the logs were sealed
+    format_version: u32,
+    // indexes of the keyspaces by their ids. The commands of the others are dropped.
+    indexes: BTreeMap<u32, Arc<Index>>,
+}
+
+/// What a compaction has written, to be applied to the index by the writer.
+struct CompactionOutput {
+    gen: u64,
+    // size of the compaction file
+    size: u64,
+    // bytes taken by the removals copied to the compaction file
+    removals: u64,
+    merged: Vec<u64>,
+    format_version: u32,
+    // keys with their keyspaces and their positions in the merged logs and in the
+    // compaction file
+    moved: Vec<(u32, Vec<u8>, CommandPos, CommandPos)>,
+    // expired keys with their keyspaces and their positions in the merged logs
+    expired: Vec<(u32, Vec<u8>, CommandPos)>,
+}
+
+/// Merges sealed logs into compaction files.
+///
+/// It only reads the logs and the indexes, so it runs without holding the writer lock.
+struct Compactor {
+    path: Arc<PathBuf>,
+}
+
+impl Compactor {
+    /// Writes the live commands in the logs of `task.merged` to the log of `task.gen`.
+    ///
+    /// Keys that have expired are dropped, and so are the commands of dropped keyspaces.
+    /// A key removed by a merged log may still be set by an older log that is kept, so its
+    /// removal is written as well in that case.
+    fn compact(&self, task: &CompactionTask) -> Result<CompactionOutput> {
+        let compaction_gen = task.gen;
+
+        // The compaction file is written under a temporary name and renamed when it is
+        // complete, so a crash in the middle never leaves a half-written generation behind.
+        let tmp_path = compaction_tmp_path(&self.path, compaction_gen);
+        let mut compaction_writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+        )?;
+
+        let now = now_millis();
+        let mut expired = Vec::new();
+        let mut moved = Vec::new();
+        let mut new_entries = Vec::new();
+        let mut removed = BTreeSet::new();
+        let mut removals = 0;
+        for &gen in &task.merged {
+            let shadows_kept = task.oldest_kept.map_or(false, |kept| kept < gen);
+            for_each_command(&self.path, gen, task.format_version, |cmd, cmd_pos| {
+                let (keyspace, key) = match cmd {
+                    Command::Set {
+                        keyspace, ref key, ..
+                    }
+                    | Command::Remove { keyspace, ref key } => (keyspace, key.clone()),
+                    Command::Batch(_) | Command::Sequence(_) => return Ok(()),
+                };
+                let index = match task.indexes.get(&keyspace) {
+                    Some(index) => index,
+                    None => return Ok(()),
+                };
+                match index.get(&key).map(|entry| *entry.value()) {
+                    // superseded by a later command
+                    Some(ref old_cmd) if !old_cmd.is_at(cmd_pos) => return Ok(()),
+                    Some(old_cmd) if old_cmd.is_expired(now) => {
+                        expired.push((keyspace, key.clone(), old_cmd));
+                    }
+                    Some(old_cmd) => {
+                        // commands in a batch are rewritten as records of their own
+                        let payload = cmd.encode();
+                        let pos = compaction_writer.pos;
+                        write_frame(&mut compaction_writer, &payload)?;
+                        // the expiry time and the sequence number stay the same
+                        let new_cmd = CommandPos {
+                            gen: compaction_gen,
+                            pos,
+                            len: compaction_writer.pos - pos,
+                            unframed: false,
+                            ..old_cmd
+                        };
+                        new_entries.push((keyspace, key.clone(), Some(new_cmd)));
+                        moved.push((keyspace, key, old_cmd, new_cmd));
+                        return Ok(());
+                    }
+                    None => {}
+                }
+                if shadows_kept && removed.insert((keyspace, key.clone())) {
+                    let pos = compaction_writer.pos;
+                    write_frame(
+                        &mut compaction_writer,
+                        &Command::remove(keyspace, key.clone()).encode(),
+                    )?;
+                    removals += compaction_writer.pos - pos;
+                    new_entries.push((keyspace, key, None));
+                }
+                Ok(())
+            })?;
+        }
+        let size = compaction_writer.pos;
+        compaction_writer.flush()?;
+        compaction_writer.writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, log_path(&self.path, compaction_gen))?;
+        // The hint file only speeds up the next open, so failing to write it is not fatal.
+        if let Err(e) = write_hint_file(&self.path, compaction_gen, size, &new_entries) {
+            error!(
+                "Hint file for generation {} cannot be written: {}",
+                compaction_gen, e
+            );
+        }
+
+        Ok(CompactionOutput {
+            gen: compaction_gen,
+            size,
+            removals,
+            merged: task.merged.clone(),
+            format_version: task.format_version,
+            moved,
+            expired,
+        })
+    }
+}
+
+/// Runs compactions on a dedicated thread until the writer is dropped.
+///
+/// The result of a compaction is applied while holding the writer lock, so readers and
+/// writers see the index switch to the compaction file at once. Between compactions, the
+/// thread removes expired keys every `EXPIRY_SWEEP_INTERVAL`.
+fn spawn_compactor(
+    compactor: Compactor,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    tasks: mpsc::Receiver<CompactionTask>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut next_sweep = Instant::now() + EXPIRY_SWEEP_INTERVAL;
+        loop {
+            let now = Instant::now();
+            let timeout = if next_sweep > now {
+                next_sweep - now
+            } else {
+                Duration::from_secs(0)
+            };
+            let task = match tasks.recv_timeout(timeout) {
+                Ok(task) => task,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    match writer.upgrade() {
+                        Some(writer) => sweep_expired(&writer),
+                        None => return,
+                    }
+                    next_sweep = Instant::now() + EXPIRY_SWEEP_INTERVAL;
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            let res = compactor.compact(&task);
+            let writer = match writer.upgrade() {
+                Some(writer) => writer,
+                None => return,
+            };
+            let mut guard = writer.lock().unwrap();
+            let res = res.and_then(|output| guard.finish_compaction(output));
+            if let Err(e) = res {
+                error!("Compaction of generation {} failed: {}", task.gen, e);
+                guard.compacting = None;
+            }
+        }
+    })
+}
+
+/// Removes the expired keys one chunk at a time, releasing the writer lock in between.
+fn sweep_expired(writer: &Mutex<KvStoreWriter>) {
+    loop {
+        match writer.lock().unwrap().remove_expired() {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                error!("Failed to remove expired keys: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Create a new log file with given generation number and add the reader to the readers map.
+///
+/// The log starts with `first_seq`, the sequence number of the first write to it.
+///
+/// Returns the writer to the log.
+fn new_log_file(path: &Path, gen: u64, first_seq: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(&path, gen);
+    let mut writer = BufWriterWithPos::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(true)
+            .open(&path)?,
+    )?;
+    write_frame(&mut writer, &Command::Sequence(first_seq).encode())?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Returns sorted generation numbers in the given directory
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Returns the format version of the data directory.
+///
+/// A directory without a `FORMAT` file is in the legacy format if it already has logs.
+/// Otherwise it is a new directory and the current version is recorded in it, unless it
+/// is opened read-only.
+fn read_format_version(path: &Path, has_logs: bool, writable: bool) -> Result<u32> {
+    let version = match fs::read_to_string(path.join(FORMAT_FILE)) {
+        Ok(content) => content.trim().parse().map_err(|_| {
+            KvsError::StringError(format!("Invalid format file content: {:?}", content))
+        })?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if has_logs {
+                LEGACY_FORMAT_VERSION
+            } else {
+                if writable {
+                    write_format_version(path, FORMAT_VERSION)?;
+                }
+                FORMAT_VERSION
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormat(version));
+    }
+    // Newer binary versions only add op types, so existing logs need no rewriting.
+    if writable && version >= FIRST_BINARY_FORMAT_VERSION && version < FORMAT_VERSION {
+        write_format_version(path, FORMAT_VERSION)?;
+        return Ok(FORMAT_VERSION);
+    }
+    Ok(version)
+}
+
+/// Atomically replaces the `FORMAT` file of the data directory.
+fn write_format_version(path: &Path, version: u32) -> Result<()> {
+    let tmp_path = path.join(format!("{}.compacting", FORMAT_FILE));
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", version)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path.join(FORMAT_FILE))?;
+    Ok(())
+}
+
+/// Removes compaction files left behind by a crash before they were renamed into place.
+///
+/// Stale log files kept for snapshots are removed as well, since snapshots do not outlive
+/// the process.
+fn remove_unfinished_compactions(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(&path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compacting".as_ref()) {
+            warn!("Removing unfinished compaction file {:?}", path);
+            fs::remove_file(&path)?;
+        } else if path.is_file() && path.extension() == Some("stale".as_ref()) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// A data directory loaded by `load_dir`.
+struct LoadedDir {
+    // sorted generation numbers of the logs
+    gen_list: Vec<u64>,
+    format_version: u32,
+    // id of the next keyspace to create
+    next_keyspace_id: u32,
+    // keyspaces by their ids, with the keys loaded into their indexes
+    keyspaces: BTreeMap<u32, Keyspace>,
+    // sequence numbers of the writes in each log that starts with one
+    seq_ranges: BTreeMap<u64, Range<u64>>,
+}
+
+/// Loads the logs in the data directory into the indexes of its keyspaces.
+///
+/// If the store is not `writable`, nothing in the directory is changed.
+fn load_dir(path: &Path, writable: bool) -> Result<LoadedDir> {
+    let mut seq_ranges = BTreeMap::new();
+    let gen_list = sorted_gen_list(path)?;
+    let format_version = read_format_version(path, !gen_list.is_empty(), writable)?;
+    let (next_keyspace_id, catalog) = read_keyspaces(path)?;
+    let mut keyspaces = BTreeMap::new();
+    keyspaces.insert(
+        DEFAULT_KEYSPACE_ID,
+        Keyspace::new(DEFAULT_KEYSPACE.to_owned()),
+    );
+    for (keyspace, name) in catalog {
+        keyspaces.insert(keyspace, Keyspace::new(name));
+    }
+
+    for &gen in &gen_list {
+        let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+        let log_len = reader.seek(SeekFrom::End(0))?;
+        match read_hint_file(path, gen, log_len)? {
+            Some(entries) => load_hint(entries, &keyspaces),
+            None => {
+                let is_newest = Some(&gen) == gen_list.last();
+                if is_legacy_log(&mut reader, format_version)? {
+                    load_legacy(path, gen, &mut reader, &keyspaces, is_newest, writable)?;
+                    continue;
+                }
+                let seqs = load(path, gen, &mut reader, &keyspaces, is_newest, writable)?;
+                if let Some(seqs) = seqs {
+                    seq_ranges.insert(gen, seqs);
+                }
+            }
+        }
+    }
+    Ok(LoadedDir {
+        gen_list,
+        format_version,
+        next_keyspace_id,
+        keyspaces,
+        seq_ranges,
+    })
+}
+
+/// Reads the `KEYSPACES` file of the data directory.
+///
+/// Returns the id of the next keyspace to create and the names of the keyspaces other
+/// than the default one by their ids. A directory without the file has no such keyspaces.
+pub(super) fn read_keyspaces(path: &Path) -> Result<(u32, BTreeMap<u32, String>)> {
+    let content = match fs::read_to_string(path.join(KEYSPACES_FILE)) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok((DEFAULT_KEYSPACE_ID + 1, BTreeMap::new()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let invalid =
+        || KvsError::StringError(format!("Invalid keyspaces file content: {:?}", content));
+    let mut lines = content.lines();
+    let next_keyspace_id = lines
+        .next()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut catalog = BTreeMap::new();
+    for line in lines {
+        let mut fields = line.splitn(2, ' ');
+        let keyspace = fields.next().and_then(|id| id.parse().ok());
+        match (keyspace, fields.next()) {
+            (Some(keyspace), Some(name)) => catalog.insert(keyspace, name.to_owned()),
+            _ => return Err(invalid()),
+        };
+    }
+    Ok((next_keyspace_id, catalog))
+}
+
+/// Atomically replaces the `KEYSPACES` file of the data directory.
+///
+/// The first line holds the id of the next keyspace to create, and each following line
+/// the id and the name of a keyspace other than the default one. Ids are never reused,
+/// so the commands left in the logs by a dropped keyspace never show up in a new one.
+pub(super) fn write_keyspaces(
+    path: &Path,
+    next_keyspace_id: u32,
+    catalog: &BTreeMap<u32, String>,
+) -> Result<()> {
+    let tmp_path = path.join(format!("{}.tmp", KEYSPACES_FILE));
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", next_keyspace_id)?;
+    for (keyspace, name) in catalog {
+        writeln!(file, "{} {}", keyspace, name)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, path.join(KEYSPACES_FILE))?;
+    Ok(())
+}
+
+/// Takes the lock of the data directory, exclusive for a writable store and shared
+/// otherwise.
+///
+/// The lock is released when the returned file is closed.
+pub(super) fn lock_dir(path: &Path, exclusive: bool) -> Result<File> {
+    let lock_path = path.join(LOCK_FILE);
+    let file = match File::open(&lock_path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => File::create(&lock_path)?,
+        res => res?,
+    };
+    let res = if exclusive {
+        FileExt::try_lock_exclusive(&file)
+    } else {
+        FileExt::try_lock_shared(&file)
+    };
+    match res {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvsError::DirectoryLocked(path.to_owned()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load the whole log file and store value locations in the indexes of their keyspaces.
+/// The commands of keyspaces that have been dropped are skipped.
+///
+/// If the last record of the newest log file is incomplete or fails its checksum,
+/// it is the remains of an interrupted write and the file is truncated before it,
+/// unless the store is read-only.
+///
+/// Returns the sequence numbers of the writes in the log if it starts with one.
+fn load(
+    path: &Path,
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    keyspaces: &BTreeMap<u32, Keyspace>,
+    is_newest: bool,
+    writable: bool,
+) -> Result<Option<Range<u64>>> {
+    let mut seqs: Option<Range<u64>> = None;
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    // To make sure we read from the beginning of the file
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    loop {
+        let corrupted = KvsError::CorruptedLog { gen, offset: pos };
+        let cmd = match read_frame(reader, file_len - pos)? {
+            Frame::Record(payload) => Command::decode(&payload).ok_or(corrupted)?,
+            Frame::Eof => break,
+            Frame::Torn if is_newest => {
+                drop_torn_tail(path, gen, pos, writable)?;
+                break;
+            }
+            Frame::Torn | Frame::Corrupted => return Err(corrupted),
+        };
+        let new_pos = reader.pos;
+        if let Command::Sequence(seq) = cmd {
+            seqs = Some(seq..seq);
+        } else if let Some(ref mut seqs) = seqs {
+            // every record after the sequence number is a write
+            seqs.end += 1;
+        }
+        let space = cmd.keyspace().and_then(|keyspace| keyspaces.get(&keyspace));
+        match (cmd, space) {
+            (
+                Command::Set {
+                    key, expires_at, ..
+                },
+                Some(space),
+            ) => {
+                let cmd_pos = CommandPos::from((gen, pos..new_pos));
+                space.index.insert(key, cmd_pos.expiring_at(expires_at));
+            }
+            (Command::Remove { key, .. }, Some(space)) => {
+                space.index.remove(&key);
+            }
+            (Command::Batch(cmds), Some(space)) => {
+                index_batch(&space.index, gen, pos..new_pos, cmds, 0, |_, _| {});
+            }
+            _ => {}
+        }
+        pos = new_pos;
+    }
+    Ok(seqs)
+}
+
+/// Returns whether a log is in the legacy format, a stream of JSON-serialized commands
+/// without frames.
+///
+/// Only a data directory in the legacy format has such logs, but it gets framed logs as
+/// well once it is written to. A framed log starts with the length of its first record,
+/// which is never as large as the bytes of a JSON command would make it.
+fn is_legacy_log<R: Read + Seek>(reader: &mut R, format_version: u32) -> Result<bool> {
+    if format_version != LEGACY_FORMAT_VERSION {
+        return Ok(false);
+    }
+    let mut start = [0; 10];
+    reader.seek(SeekFrom::Start(0))?;
+    let len = read_full(reader, &mut start)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(LEGACY_LOG_PREFIXES
+        .iter()
+        .any(|prefix| start[..len].starts_with(prefix)))
+}
+
+/// Loads a legacy log into the index of the default keyspace, the only one it can have.
+///
+/// Its commands are located without a frame and decoded from JSON when they are read.
+/// A command cut short at the end of the newest log is dropped like a torn record.
+fn load_legacy(
+    path: &Path,
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    keyspaces: &BTreeMap<u32, Keyspace>,
+    is_newest: bool,
+    writable: bool,
+) -> Result<()> {
+    let index = &keyspaces[&DEFAULT_KEYSPACE_ID].index;
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+    while let Some(cmd) = stream.next() {
+        let new_pos = stream.byte_offset() as u64;
+        match cmd {
+            Ok(LegacyCommand::Set { key, .. }) => {
+                let cmd_pos = CommandPos {
+                    unframed: true,
+                    ..CommandPos::from((gen, pos..new_pos))
+                };
+                index.insert(key.into_bytes(), cmd_pos);
+            }
+            Ok(LegacyCommand::Remove { key }) => {
+                index.remove(key.as_bytes());
+            }
+            Err(ref e) if e.is_eof() && is_newest => {
+                return drop_torn_tail(path, gen, pos, writable);
+            }
+            Err(_) => return Err(KvsError::CorruptedLog { gen, offset: pos }),
+        }
+        pos = new_pos;
+    }
+    Ok(())
+}
+
+/// Truncates the newest log before the torn record at `pos`, or only ignores the record
+/// if the store is read-only.
+fn drop_torn_tail(path: &Path, gen: u64, pos: u64, writable: bool) -> Result<()> {
+    if !writable {
+        warn!(
+            "Ignoring torn record at the end of {:?} from offset {}",
+            log_path(path, gen),
+            pos
+        );
+        return Ok(());
+    }
+    warn!(
+        "Truncating torn record at the end of {:?} from offset {}",
+        log_path(path, gen),
+        pos
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(log_path(path, gen))?
+        .set_len(pos)?;
+    Ok(())
+}
+
+/// A log holding writes that a change feed reads, opened when the feed started.
+struct ChangeLog {
+    gen: u64,
+    file: File,
+    // length of the log when the feed started. Later writes are sent to the feed.
+    len: u64,
+    // sequence number of the first write in the log
+    first_seq: u64,
+}
+
+/// Reads the writes to a keyspace after `since` in a log.
+fn read_changes(log: ChangeLog, keyspace: u32, since: u64) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    let mut reader = BufReaderWithPos::new(log.file)?;
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut seq = log.first_seq;
+    // the log may have grown since the feed started, with writes the feed is sent
+    while pos < log.len {
+        let corrupted = KvsError::CorruptedLog {
+            gen: log.gen,
+            offset: pos,
+        };
+        let cmd = match read_frame(&mut reader, log.len - pos)? {
+            Frame::Record(payload) => Command::decode(&payload).ok_or(corrupted)?,
+            Frame::Eof => return Ok(changes),
+            Frame::Torn | Frame::Corrupted => return Err(corrupted),
+        };
+        pos = reader.pos;
+        if let Command::Sequence(_) = cmd {
+            continue;
+        }
+        if seq > since && cmd.keyspace() == Some(keyspace) {
+            let events = match cmd {
+                Command::Batch(cmds) => cmds.into_iter().filter_map(Command::into_event).collect(),
+                cmd => cmd.into_event().into_iter().collect(),
+            };
+            changes.push(Change { seq, events });
+        }
+        seq += 1;
+    }
+    Ok(changes)
+}
+
+/// Calls `f` with every command in the log of the given generation and its location.
+///
+/// The commands in a batch record are located like `index_batch` locates them, and the
+/// commands in a legacy log like `load_legacy` does.
+fn for_each_command<F>(path: &Path, gen: u64, format_version: u32, mut f: F) -> Result<()>
+where
+    F: FnMut(Command, CommandPos) -> Result<()>,
+{
+    let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+    if is_legacy_log(&mut reader, format_version)? {
+        let mut pos = 0;
+        let mut stream = Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+        while let Some(cmd) = stream.next() {
+            let cmd = cmd.map_err(|_| KvsError::CorruptedLog { gen, offset: pos })?;
+            let new_pos = stream.byte_offset() as u64;
+            let location = CommandPos {
+                unframed: true,
+                ..CommandPos::from((gen, pos..new_pos))
+            };
+            f(Command::from(cmd), location)?;
+            pos = new_pos;
+        }
+        return Ok(());
+    }
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    loop {
+        let corrupted = KvsError::CorruptedLog { gen, offset: pos };
+        let cmd = match read_frame(&mut reader, file_len - pos)? {
+            Frame::Record(payload) => Command::decode(&payload).ok_or(corrupted)?,
+            Frame::Eof => return Ok(()),
+            Frame::Torn | Frame::Corrupted => return Err(corrupted),
+        };
+        let new_pos = reader.pos;
+        if let Command::Batch(cmds) = cmd {
+            let mut cmd_pos = pos + (RECORD_HEADER_LEN + BATCH_HEADER_LEN) as u64;
+            for cmd in cmds {
+                let len = cmd.encoded_len() as u64;
+                cmd_pos += 4; // length of the command
+                let location = CommandPos {
+                    unframed: true,
+                    ..CommandPos::from((gen, cmd_pos..cmd_pos + len))
+                };
+                f(cmd, location)?;
+                cmd_pos += len;
+            }
+        } else {
+            f(cmd, CommandPos::from((gen, pos..new_pos)))?;
+        }
+        pos = new_pos;
+    }
+}
+
+/// Stores the value locations of the commands in a batch record in the index map.
+///
+/// Each `Set` command in the batch is located by its own position and length in the
+/// record. Everything else in the record is dead.
+///
+/// Returns the number of bytes taken by the `Set` commands, which are live.
+fn index_batch<F>(
+    index: &Index,
+    gen: u64,
+    range: Range<u64>,
+    cmds: Vec<Command>,
+    seq: u64,
+    mut supersede: F,
+) -> u64
+where
+    F: FnMut(&[u8], CommandPos),
+{
+    let mut live = 0;
+    let mut pos = range.start + (RECORD_HEADER_LEN + BATCH_HEADER_LEN) as u64;
+    for cmd in cmds {
+        let len = cmd.encoded_len() as u64;
+        pos += 4; // length of the command
+        match cmd {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                if let Some(old_cmd) = index.get(&key) {
+                    supersede(&key, *old_cmd.value());
+                }
+                let cmd_pos = CommandPos {
+                    seq,
+                    expires_at,
+                    unframed: true,
+                    ..CommandPos::from((gen, pos..pos + len))
+                };
+                index.insert(key, cmd_pos);
+                live += len;
+            }
+            Command::Remove { key, .. } => {
+                if let Some(old_cmd) = index.get(&key) {
+                    supersede(&key, *old_cmd.value());
+                }
+                index.remove(&key);
+            }
+            Command::Batch(_) | Command::Sequence(_) => unreachable!("nested batch"),
+        }
+        pos += len;
+    }
+    live
+}
+
+/// Load the key locations saved in a hint file into the indexes of their keyspaces.
+///
+/// A key without a location is removed. The keys of dropped keyspaces are skipped.
+fn load_hint(entries: Vec<HintEntry>, keyspaces: &BTreeMap<u32, Keyspace>) {
+    for (keyspace, key, cmd_pos) in entries {
+        let index = match keyspaces.get(&keyspace) {
+            Some(space) => &space.index,
+            None => continue,
+        };
+        match cmd_pos {
+            Some(cmd_pos) => {
+                index.insert(key, cmd_pos);
+            }
+            None => {
+                index.remove(&key);
+            }
+        }
+    }
+}
+
+/// A key of a keyspace in a hint file with its location in the log, or `None` if the log
+/// removes it.
+type HintEntry = (u32, Vec<u8>, Option<CommandPos>);
+
+/// Writes the hint file of a compaction generation.
+///
+/// The first record holds the length of the log file and the hint version. Each following
+/// record holds the position, length, expiry time, keyspace id and key of one command in
+/// the log, where a length of 0 stands for a removal. Like the compaction file, it is
+/// renamed into place only after it is completely written.
+fn write_hint_file(path: &Path, gen: u64, log_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let tmp_path = path.join(format!("{}.hint.compacting", gen));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut header = log_len.to_le_bytes().to_vec();
+    header.extend_from_slice(&HINT_VERSION.to_le_bytes());
+    write_frame(&mut writer, &header)?;
+    for (keyspace, key, cmd_pos) in entries {
+        // 0 means the key never expires
+        let (pos, len, expires_at) = match cmd_pos {
+            Some(cmd_pos) => (cmd_pos.pos, cmd_pos.len, cmd_pos.expires_at.unwrap_or(0)),
+            None => (0, 0, 0),
+        };
+        let mut payload = Vec::with_capacity(28 + key.len());
+        payload.extend_from_slice(&pos.to_le_bytes());
+        payload.extend_from_slice(&len.to_le_bytes());
+        payload.extend_from_slice(&expires_at.to_le_bytes());
+        payload.extend_from_slice(&keyspace.to_le_bytes());
+        payload.extend_from_slice(key);
+        write_frame(&mut writer, &payload)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, hint_path(path, gen))?;
+    Ok(())
+}
+
+/// Reads the hint file of the given generation.
+///
+/// Returns `None` if there is no hint file, or if it is damaged or does not belong to
+/// a log file of length `log_len`. The log must be replayed in that case.
+fn read_hint_file(path: &Path, gen: u64, log_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let file = match File::open(hint_path(path, gen)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut remaining = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let invalid = || {
+        warn!("Ignoring invalid hint file of generation {}", gen);
+        Ok(None)
+    };
+    match read_frame(&mut reader, remaining)? {
+        Frame::Record(ref payload)
+            if payload.len() == 12
+                && le_u64(payload) == log_len
+                && le_u32(&payload[8..]) == HINT_VERSION =>
+        {
+            remaining -= RECORD_HEADER_LEN as u64 + 12;
+        }
+        _ => return invalid(),
+    }
+
+    let mut entries = Vec::new();
+    loop {
+        let payload = match read_frame(&mut reader, remaining)? {
+            Frame::Record(payload) => payload,
+            Frame::Eof => return Ok(Some(entries)),
+            Frame::Torn | Frame::Corrupted => return invalid(),
+        };
+        remaining -= (RECORD_HEADER_LEN + payload.len()) as u64;
+        if payload.len() < 28 {
+            return invalid();
+        }
+        let cmd_pos = CommandPos {
+            gen,
+            pos: le_u64(&payload[..8]),
+            len: le_u64(&payload[8..16]),
+            expires_at: Some(le_u64(&payload[16..24])).filter(|&expires_at| expires_at != 0),
+            unframed: false,
+            seq: 0,
+        };
+        let cmd_pos = Some(cmd_pos).filter(|cmd_pos| cmd_pos.len != 0);
+        entries.push((le_u32(&payload[24..28]), payload[28..].to_vec(), cmd_pos));
+    }
+}
+
+/// Hard-links `src` to `dst`, or copies it if they are on different file systems.
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+fn compaction_tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.compacting", gen))
+}
+
+fn stale_log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.stale", gen))
+}
+
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Result of reading one framed record.
+pub(super) enum Frame {
+    /// A complete record whose checksum matches.
+    Record(Vec<u8>),
+    /// There is nothing left to read.
+    Eof,
+    /// The record is cut short by the end of the data, or it is the last record
+    /// and fails its checksum. This is what an interrupted write leaves behind.
+    Torn,
+    /// The record fails its checksum but more data follows it.
+    Corrupted,
+}
+
+/// Writes `payload` framed with its length and CRC32 checksum.
+pub(super) fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    header[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[4..].copy_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads a framed record. `remaining` is the number of bytes left in the underlying data.
+pub(super) fn read_frame<R: Read>(reader: &mut R, remaining: u64) -> Result<Frame> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    let header_len = read_full(reader, &mut header)?;
+    if header_len == 0 {
+        return Ok(Frame::Eof);
+    } else if header_len < RECORD_HEADER_LEN {
+        return Ok(Frame::Torn);
+    }
+
+    let len = u64::from(le_u32(&header[..4]));
+    let checksum = le_u32(&header[4..]);
+
+    let frame_len = RECORD_HEADER_LEN as u64 + len;
+    if frame_len > remaining {
+        return Ok(Frame::Torn);
+    }
+    let mut payload = vec![0; len as usize];
+    if read_full(reader, &mut payload)? < payload.len() {
+        return Ok(Frame::Torn);
+    }
+    if crc32fast::hash(&payload) != checksum {
+        return Ok(if frame_len == remaining {
+            Frame::Torn
+        } else {
+            Frame::Corrupted
+        });
+    }
+    Ok(Frame::Record(payload))
+}
+
+/// Decodes a little-endian `u32` from the first 4 bytes of `bytes`.
+pub(super) fn le_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+/// Decodes a little-endian `u64` from the first 8 bytes of `bytes`.
+pub(super) fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Reads until `buf` is full or the reader is exhausted and returns the bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Struct representing a command
+///
+/// In the binary format, a command is encoded as a header of its op type, key length
+/// and value length followed by the raw bytes of the key and the value. A `Set` command
+/// with an expiry time has it between the header and the key. A command of a keyspace
+/// other than the default one has `KEYSPACE_FLAG` set in its op type and the id of the
+/// keyspace right after the header.
+#[derive(Debug)]
+enum Command {
+    Set {
+        keyspace: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+    },
+    Remove {
+        keyspace: u32,
+        key: Vec<u8>,
+    },
+    // `Set` and `Remove` commands of a keyspace applied atomically
+    Batch(Vec<Command>),
+    // sequence number of the first write in the log it starts
+    Sequence(u64),
+}
+
+/// A command written in the legacy JSON format, which only supports string keys and values.
+#[derive(Deserialize, Debug)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, value } => Command::set(
+                DEFAULT_KEYSPACE_ID,
+                key.into_bytes(),
+                value.into_bytes(),
+                None,
+            ),
+            LegacyCommand::Remove { key } => Command::remove(DEFAULT_KEYSPACE_ID, key.into_bytes()),
+        }
+    }
+}
+
+impl Command {
+    fn set(keyspace: u32, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            keyspace,
+            key,
+            value,
+            expires_at,
+        }
+    }
+
+    fn remove(keyspace: u32, key: Vec<u8>) -> Command {
+        Command::Remove { keyspace, key }
+    }
+
+    /// Returns the id of the keyspace of the command. The commands of a batch all belong
+    /// to the same keyspace.
+    fn keyspace(&self) -> Option<u32> {
+        match self {
+            Command::Set { keyspace, .. } | Command::Remove { keyspace, .. } => Some(*keyspace),
+            Command::Batch(cmds) => cmds.first().and_then(Command::keyspace),
+            Command::Sequence(_) => None,
+        }
+    }
+
+    /// Returns the length of the command in the binary format.
+    fn encoded_len(&self) -> usize {
+        match self {
+            Command::Set {
+                keyspace,
+                key,
+                value,
+                expires_at,
+            } => {
+                let expiry_len = if expires_at.is_some() { 8 } else { 0 };
+                COMMAND_HEADER_LEN
+                    + keyspace_id_len(*keyspace)
+                    + expiry_len
+                    + key.len()
+                    + value.len()
+            }
+            Command::Remove { keyspace, key } => {
+                COMMAND_HEADER_LEN + keyspace_id_len(*keyspace) + key.len()
+            }
+            Command::Batch(cmds) => {
+                BATCH_HEADER_LEN + cmds.iter().map(|cmd| 4 + cmd.encoded_len()).sum::<usize>()
+            }
+            Command::Sequence(_) => SEQUENCE_LEN,
+        }
+    }
+
+    /// Encodes the command in the binary format.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        let (op, keyspace, key, value, expires_at) = match self {
+            Command::Set {
+                keyspace,
+                key,
+                value,
+                expires_at: None,
+            } => (OP_SET, *keyspace, key, value.as_slice(), None),
+            Command::Set {
+                keyspace,
+                key,
+                value,
+                expires_at,
+            } => (
+                OP_SET_WITH_EXPIRY,
+                *keyspace,
+                key,
+                value.as_slice(),
+                *expires_at,
+            ),
+            Command::Remove { keyspace, key } => (OP_REMOVE, *keyspace, key, &[][..], None),
+            Command::Batch(cmds) => {
+                buf.push(OP_BATCH);
+                buf.extend_from_slice(&(cmds.len() as u32).to_le_bytes());
+                for cmd in cmds {
+                    buf.extend_from_slice(&(cmd.encoded_len() as u32).to_le_bytes());
+                    buf.extend_from_slice(&cmd.encode());
+                }
+                return buf;
+            }
+            Command::Sequence(seq) => {
+                buf.push(OP_SEQUENCE);
+                buf.extend_from_slice(&seq.to_le_bytes());
+                return buf;
+            }
+        };
+        if keyspace == DEFAULT_KEYSPACE_ID {
+            buf.push(op);
+        } else {
+            buf.push(op | KEYSPACE_FLAG);
+        }
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if keyspace != DEFAULT_KEYSPACE_ID {
+            buf.extend_from_slice(&keyspace.to_le_bytes());
+        }
+        if let Some(expires_at) = expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    /// Decodes a command in either the binary or the legacy JSON format.
+    ///
+    /// Returns `None` if the payload is not a valid command.
+    fn decode(payload: &[u8]) -> Option<Command> {
+        // A JSON-serialized command is an object, while a binary one starts with its op type.
+        if payload.first() == Some(&b'{') {
+            return serde_json::from_slice::<LegacyCommand>(payload)
+                .ok()
+                .map(Command::from);
+        }
+        if payload.first() == Some(&OP_BATCH) {
+            return Command::decode_batch(payload);
+        }
+        if payload.first() == Some(&OP_SEQUENCE) {
+            return if payload.len() == SEQUENCE_LEN {
+                Some(Command::Sequence(le_u64(&payload[1..])))
+            } else {
+                None
+            };
+        }
+        if payload.len() < COMMAND_HEADER_LEN {
+            return None;
+        }
+        let op = payload[0] & !KEYSPACE_FLAG;
+        let key_len = le_u32(&payload[1..5]) as usize;
+        let value_len = le_u32(&payload[5..9]) as usize;
+        let mut body = &payload[COMMAND_HEADER_LEN..];
+        let keyspace = if payload[0] & KEYSPACE_FLAG != 0 {
+            if body.len() < 4 {
+                return None;
+            }
+            let keyspace = le_u32(body);
+            body = &body[4..];
+            keyspace
+        } else {
+            DEFAULT_KEYSPACE_ID
+        };
+        let expires_at = if op == OP_SET_WITH_EXPIRY {
+            if body.len() < 8 {
+                return None;
+            }
+            let expires_at = le_u64(body);
+            body = &body[8..];
+            Some(expires_at)
+        } else {
+            None
+        };
+        if body.len() != key_len + value_len {
+            return None;
+        }
+        let (key, value) = body.split_at(key_len);
+        match op {
+            OP_SET | OP_SET_WITH_EXPIRY => Some(Command::set(
+                keyspace,
+                key.to_vec(),
+                value.to_vec(),
+                expires_at,
+            )),
+            OP_REMOVE if value.is_empty() => Some(Command::remove(keyspace, key.to_vec())),
+            _ => None,
+        }
+    }
+
+    /// Returns the change of a key made by a `Set` or `Remove` command.
+    fn into_event(self) -> Option<WatchEvent> {
+        match self {
+            Command::Set { key, value, .. } => Some(WatchEvent::Set { key, value }),
+            Command::Remove { key, .. } => Some(WatchEvent::Remove { key }),
+            Command::Batch(_) | Command::Sequence(_) => None,
+        }
+    }
+
+    /// Decodes a batch of commands in the binary format.
+    fn decode_batch(payload: &[u8]) -> Option<Command> {
+        if payload.len() < BATCH_HEADER_LEN {
+            return None;
+        }
+        let count = le_u32(&payload[1..5]);
+        let mut rest = &payload[BATCH_HEADER_LEN..];
+        let mut cmds = Vec::new();
+        for _ in 0..count {
+            if rest.len() < 4 {
+                return None;
+            }
+            let len = le_u32(rest) as usize;
+            if rest.len() < 4 + len {
+                return None;
+            }
+            match Command::decode(&rest[4..4 + len])? {
+                Command::Batch(_) | Command::Sequence(_) => return None,
+                cmd => cmds.push(cmd),
+            }
+            rest = &rest[4 + len..];
+        }
+        if rest.is_empty() {
+            Some(Command::Batch(cmds))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the length of the keyspace id in a command of the given keyspace.
+fn keyspace_id_len(keyspace: u32) -> usize {
+    if keyspace == DEFAULT_KEYSPACE_ID {
+        0
+    } else {
+        4
+    }
+}
+
+/// Represents the position and length of a framed command in the log, or of a command
+/// inside a batch record
+#[derive(Debug, Clone, Copy)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // expiry time of the key set by the command
+    expires_at: Option<u64>,
+    // whether the command has no frame of its own, because it is in a batch record or in
+    // a legacy log
+    unframed: bool,
+    // sequence number of the write, which orders the versions of a key
+    seq: u64,
+}
+
+impl CommandPos {
+    fn expiring_at(self, expires_at: Option<u64>) -> CommandPos {
+        CommandPos { expires_at, ..self }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+
+    /// Returns whether both point at the same command in the log.
+    fn is_at(&self, other: CommandPos) -> bool {
+        self.gen == other.gen && self.pos == other.pos
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -469,6 +3589,9 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
+            unframed: false,
+            seq: 0,
         }
     }
 }
@@ -0,0 +1,1896 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::iter::{self, Peekable};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crossbeam_skiplist::SkipMap;
+use memmap::Mmap;
+use serde::{Deserialize, Serialize};
+use tokio::prelude::*;
+
+use super::keyspace::{
+    after_start, before_end, internal_key, keyspace_of, keyspace_range, Catalog, KeyRange,
+    Watchers, KEYSPACE_ID_LEN,
+};
+use super::kvs::{
+    le_u64, lock_dir, read_frame, read_keyspaces, write_frame, write_keyspaces, Frame,
+    DEFAULT_KEYSPACE_ID, RECORD_HEADER_LEN,
+};
+use super::{
+    check_keyspace_name, create_checkpoint_dir, expiry_time, now_millis, owned_range, put_bytes,
+    run_in_pool, spawn_periodic, take, take_bytes, Durability, EngineStats, GroupCommit, KvPairs,
+    KvsEngine, WatchEvent, DEFAULT_KEYSPACE,
+};
+use crate::batch::BatchOp;
+use crate::thread_pool::ThreadPool;
+use crate::transaction::run_transaction;
+use crate::{KvsError, Result, Transaction, WriteBatch};
+
+// size of the memtable at which it is frozen and flushed to a table by default
+const MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+// size at which a compaction starts a new table by default
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+// bytes counted for each memtable entry besides its key and value
+const MEMTABLE_ENTRY_OVERHEAD: u64 = 32;
+
+// size of the data blocks of a table before they are framed
+const BLOCK_SIZE: usize = 4096;
+// index offset, index length, filter offset, filter length (u64 each) and magic number (u64)
+const FOOTER_LEN: usize = 40;
+const TABLE_MAGIC: u64 = 0x6b76_735f_6c73_6d31;
+
+// number of level 0 tables that triggers a compaction into level 1
+const L0_COMPACTION_TRIGGER: usize = 4;
+// numbers of level 0 tables and of frozen memtables at which writes wait for the
+// compactions and the flushes to catch up
+const L0_STALL_TRIGGER: usize = 12;
+const MAX_FROZEN_MEMTABLES: usize = 2;
+// number of flushes or compactions in a row that may fail before writes are refused
+const MAX_BACKGROUND_FAILURES: u32 = 3;
+// size of level 1 that triggers a compaction into level 2; each deeper level may be ten
+// times larger than the one above it
+const L1_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+const MAX_LEVELS: usize = 7;
+
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_PROBES: u64 = 7;
+
+// key length (u32), key, entry type (u8), then the value length (u32) and the value
+const ENTRY_VALUE: u8 = 1;
+// followed by the expiry time (u64) before the value length and the value
+const ENTRY_VALUE_WITH_EXPIRY: u8 = 2;
+const ENTRY_TOMBSTONE: u8 = 3;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const WAL_EXTENSION: &str = "wal";
+const TABLE_EXTENSION: &str = "sst";
+
+/// A log-structured merge-tree storage engine.
+///
+/// Writes go to a write-ahead log and to the memtable, a skip list in memory. Every write
+/// or batch is one framed record of the log, so a batch is replayed whole or not at all.
+/// When the memtable exceeds its size, it is frozen and a new memtable and log are
+/// started. Frozen memtables are flushed in the background to sorted table files on
+/// level 0, and their logs are deleted.
+///
+/// A table holds its entries in checksummed blocks of about 4 KiB, followed by an index
+/// with the first key of every block and a bloom filter of its keys. Tables are read
+/// through memory maps, and the bloom filter lets a read skip the tables that cannot
+/// have the key.
+///
+/// Level 0 tables may overlap. When there are four of them, they are compacted with the
+/// overlapping tables of level 1 into new level 1 tables. The tables of the deeper levels
+/// are disjoint, and a level that grows larger than its limit has one of its tables
+/// compacted into the next level. A compaction drops the entries shadowed by newer ones,
+/// and removals and expired keys once no deeper level can hold an older value.
+///
+/// The tables of each level are listed in a `MANIFEST` file, which is replaced
+/// atomically after every flush and compaction. Reads work on an immutable version of the
+/// memtables and the tables, so they never wait for a flush or a compaction.
+///
+/// A write that finds two frozen memtables or twelve level 0 tables flushes and compacts
+/// them itself before it goes on, so writes cannot outrun the background work. If the
+/// flushes and compactions fail three times in a row, writes fail with
+/// `KvsError::BackgroundWorkFailed` until a retry, which each such write makes, succeeds.
+///
+/// Keys are prefixed with the id of their keyspace. A dropped keyspace is removed from
+/// the list of keyspaces at once. The tables that hold only keys of dropped keyspaces are
+/// deleted in the background, and the keys of such keyspaces in other tables are dropped
+/// by later compactions.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store: LsmKvsEngine<RayonThreadPool> = LsmKvsEngine::open(current_dir()?, 2)?;
+/// store.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = store.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmKvsEngine<P: ThreadPool> {
+    pool: P,
+    // id of the keyspace the handle works on
+    keyspace: u32,
+    shared: Arc<Shared>,
+    writer: Arc<Mutex<LsmWriter>>,
+    // stops the background work when the last handle is dropped
+    _guard: Arc<CloseGuard>,
+}
+
+impl<P: ThreadPool> LsmKvsEngine<P> {
+    /// Opens an `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if another process has the data directory
+    /// open.
+    ///
+    /// It returns `KvsError::CorruptedLog` if a record of a write-ahead log fails its
+    /// checksum and it is not the torn tail of the log, and `KvsError::CorruptedTable` if
+    /// a table file is damaged.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        LsmKvsEngine::open_with(path, concurrency, LsmOptions::default())
+    }
+
+    /// Opens an `LsmKvsEngine` with the given path and options.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: LsmOptions,
+    ) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path, true)?;
+
+        let manifest = read_manifest(&path)?;
+        if manifest.levels.len() > MAX_LEVELS {
+            return Err(KvsError::StringError(format!(
+                "Invalid manifest with {} levels",
+                manifest.levels.len()
+            )));
+        }
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut live_tables = HashSet::new();
+        for (level, ids) in manifest.levels.iter().enumerate() {
+            for &id in ids {
+                levels[level].push(Arc::new(Table::open(&path, id)?));
+                live_tables.insert(id);
+            }
+        }
+
+        // Logs older than the log number have been flushed, and tables not in the
+        // manifest are the output of an interrupted flush or compaction.
+        let mut next_file = cmp::max(manifest.next_file, 1);
+        let mut wal_ids = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let file_path = entry?.path();
+            let (id, extension) = match parse_file_name(&file_path) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            next_file = cmp::max(next_file, id + 1);
+            if extension == WAL_EXTENSION && id >= manifest.log_number {
+                wal_ids.push(id);
+            } else if extension == WAL_EXTENSION
+                || extension == TABLE_EXTENSION && !live_tables.contains(&id)
+            {
+                fs::remove_file(&file_path)?;
+            }
+        }
+        wal_ids.sort_unstable();
+
+        // The memtable holds the writes of the old logs and of the new one, so the old
+        // logs are kept until it is flushed.
+        let wal_id = next_file;
+        let mem = Memtable::new(wal_ids.first().cloned().unwrap_or(wal_id));
+        for &id in &wal_ids {
+            replay_wal(&path, id, &mem)?;
+        }
+        let wal = create_wal(&path, wal_id)?;
+
+        let (next_keyspace_id, names) = read_keyspaces(&path)?;
+        let shared = Arc::new(Shared {
+            path,
+            options: options.clone(),
+            version: RwLock::new(Arc::new(Version {
+                mem: Arc::new(mem),
+                frozen: Vec::new(),
+                levels,
+            })),
+            catalog: RwLock::new(Catalog {
+                next_id: next_keyspace_id,
+                names,
+            }),
+            next_file: AtomicU64::new(wal_id + 1),
+            maintaining: AtomicBool::new(false),
+            running: Mutex::new(()),
+            closing: AtomicBool::new(false),
+            // tables of keyspaces dropped before a crash may be left
+            dropped_tables: AtomicBool::new(true),
+            failures: Mutex::new((0, None)),
+            compact_pointers: Mutex::new(vec![Vec::new(); MAX_LEVELS]),
+            compactions: AtomicU64::new(0),
+            last_compaction: Mutex::new(None),
+        });
+
+        let active_wal = Arc::new(Mutex::new(wal.try_clone()?));
+        if let Durability::Periodic(interval) = options.durability {
+            spawn_periodic(Arc::downgrade(&active_wal), interval, "sync", |wal| {
+                Ok(wal.lock().unwrap().sync_data()?)
+            });
+        }
+        let writer = LsmWriter {
+            wal,
+            active_wal,
+            durability: options.durability,
+            group_commit: Arc::new(GroupCommit::default()),
+            watchers: Watchers::default(),
+        };
+
+        let pool = P::new(concurrency)?;
+        schedule_maintenance(&pool, &shared);
+        Ok(LsmKvsEngine {
+            pool,
+            keyspace: DEFAULT_KEYSPACE_ID,
+            _guard: Arc::new(CloseGuard {
+                shared: Arc::clone(&shared),
+                _lock: lock,
+            }),
+            shared,
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Runs `f` with the writer in the thread pool, after checking that the keyspace of
+    /// the handle still exists. Frozen memtables are flushed afterwards.
+    fn run_write<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut LsmWriter, &Shared, u32) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let shared = Arc::clone(&self.shared);
+        let writer = Arc::clone(&self.writer);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            make_room_for_write(&shared)?;
+            let res = with_writer(&writer, |writer| {
+                shared.check_keyspace(keyspace)?;
+                f(writer, &shared, keyspace)
+            })?;
+            schedule_maintenance(&pool, &shared);
+            Ok(res)
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for LsmKvsEngine<P> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let entry = Entry::Value {
+            value,
+            expires_at: None,
+        };
+        self.run_write(move |writer, shared, keyspace| {
+            writer.write(shared, keyspace, vec![(key, entry)])
+        })
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            shared.check_keyspace(keyspace)?;
+            shared.value(keyspace, &key)
+        })
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |writer, shared, keyspace| {
+            if shared.value(keyspace, &key)?.is_none() {
+                return Err(KvsError::KeyNotFound);
+            }
+            writer.write(shared, keyspace, vec![(key, Entry::Tombstone)])
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let entry = Entry::Value {
+            value,
+            expires_at: Some(expiry_time(ttl)),
+        };
+        self.run_write(move |writer, shared, keyspace| {
+            writer.write(shared, keyspace, vec![(key, entry)])
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |writer, shared, keyspace| {
+            let current = shared.value(keyspace, &key)?;
+            if current != expected {
+                return Err(KvsError::ConditionFailed(current));
+            }
+            let entry = match new {
+                Some(value) => Entry::Value {
+                    value,
+                    expires_at: None,
+                },
+                None if current.is_none() => return Ok(()),
+                None => Entry::Tombstone,
+            };
+            writer.write(shared, keyspace, vec![(key, entry)])
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |writer, shared, keyspace| {
+            writer.write(shared, keyspace, batch_entries(batch))
+        })
+    }
+
+    /// Runs `f` in an optimistic transaction and returns its result.
+    ///
+    /// The keys the transaction has read are validated and its writes are applied as one
+    /// record while holding the writer, so it is serialized with all other writes.
+    fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let shared = Arc::clone(&self.shared);
+        let writer = Arc::clone(&self.writer);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            shared.check_keyspace(keyspace)?;
+            make_room_for_write(&shared)?;
+            let read = |key: &[u8]| shared.value(keyspace, key);
+            let res = run_transaction(f, read, |reads, batch| {
+                with_writer(&writer, |writer| {
+                    shared.check_keyspace(keyspace)?;
+                    for (key, value) in reads {
+                        if shared.value(keyspace, &key)? != value {
+                            return Ok(false);
+                        }
+                    }
+                    writer.write(&shared, keyspace, batch_entries(batch))?;
+                    Ok(true)
+                })
+            })?;
+            schedule_maintenance(&pool, &shared);
+            Ok(res)
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        let range = owned_range(&range);
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            shared.check_keyspace(keyspace)?;
+            let limit = limit.unwrap_or(usize::max_value());
+            let version = shared.current();
+            let now = now_millis();
+            let mut pairs = Vec::new();
+            for res in merged_entries(&version, &keyspace_range(keyspace, range)) {
+                if pairs.len() >= limit {
+                    break;
+                }
+                let (key, entry) = res?;
+                if let Some(value) = entry.live_value(now) {
+                    pairs.push((key[KEYSPACE_ID_LEN..].to_vec(), value));
+                }
+            }
+            Ok(pairs)
+        })
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`.
+    ///
+    /// The memtables are written as level 0 tables of the copy and the tables are copied
+    /// as they are. Writes are blocked only while the active memtable is copied in memory.
+    fn checkpoint(&self, dest: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let writer = Arc::clone(&self.writer);
+        run_in_pool(&self.pool, move || {
+            create_checkpoint_dir(&dest)?;
+            let (version, active, catalog) = {
+                let _writer = writer.lock().unwrap();
+                let version = shared.current();
+                let active: Vec<_> = version.mem.iter().collect();
+                let catalog = shared.catalog.read().unwrap().clone();
+                (version, active, catalog)
+            };
+
+            let mut levels = vec![Vec::new(); MAX_LEVELS];
+            let mut next_file = 1;
+            let memtables = iter::once(active).chain(
+                version
+                    .frozen
+                    .iter()
+                    .map(|mem| mem.iter().collect::<Vec<_>>()),
+            );
+            for entries in memtables {
+                if entries.is_empty() {
+                    continue;
+                }
+                let mut builder = TableBuilder::create(&table_path(&dest, next_file))?;
+                for (key, entry) in &entries {
+                    builder.add(key, entry)?;
+                }
+                builder.finish()?;
+                levels[0].push(next_file);
+                next_file += 1;
+            }
+            for (level, tables) in version.levels.iter().enumerate() {
+                for table in tables {
+                    fs::copy(
+                        table_path(&shared.path, table.id),
+                        table_path(&dest, next_file),
+                    )?;
+                    levels[level].push(next_file);
+                    next_file += 1;
+                }
+            }
+            let manifest = Manifest {
+                next_file,
+                log_number: next_file,
+                levels,
+            };
+            write_manifest(&dest, &manifest)?;
+            write_keyspaces(&dest, catalog.next_id, &catalog.names)
+        })
+    }
+
+    /// Returns the size of the keyspace and the compactions finished since the store was
+    /// opened.
+    ///
+    /// The keys and live bytes are counted by scanning the keyspace.
+    fn stats(&self) -> Box<dyn Future<Item = EngineStats, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            shared.check_keyspace(keyspace)?;
+            let version = shared.current();
+            let now = now_millis();
+            let (mut keys, mut live_bytes) = (0, 0);
+            let range = keyspace_range(keyspace, (Bound::Unbounded, Bound::Unbounded));
+            for res in merged_entries(&version, &range) {
+                let (key, entry) = res?;
+                if let Some(value) = entry.live_value(now) {
+                    keys += 1;
+                    live_bytes += (key.len() - KEYSPACE_ID_LEN + value.len()) as u64;
+                }
+            }
+            let mut disk_size = 0;
+            for entry in fs::read_dir(&shared.path)? {
+                disk_size += entry?.metadata()?.len();
+            }
+            Ok(EngineStats {
+                keys,
+                live_bytes: Some(live_bytes),
+                compactions: Some(shared.compactions.load(Ordering::SeqCst)),
+                last_compaction: *shared.last_compaction.lock().unwrap(),
+                disk_size: Some(disk_size),
+                ..EngineStats::default()
+            })
+        })
+    }
+
+    /// Returns a stream of the changes of the keys starting with `prefix`.
+    ///
+    /// The events are sent by the writer after each write, so they are in the order of
+    /// the writes. Keys that expire are not reported. A watch that falls behind the writes
+    /// is ended with `KvsError::WatchLagged`.
+    fn watch(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = self.shared.check_keyspace(self.keyspace) {
+            return Box::new(stream::once(Err(e)));
+        }
+        writer.watchers.add(self.keyspace, prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let keyspace = self
+            .shared
+            .catalog
+            .read()
+            .unwrap()
+            .id(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        Ok(LsmKvsEngine {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let writer = Arc::clone(&self.writer);
+        run_in_pool(&self.pool, move || {
+            check_keyspace_name(&name)?;
+            let _writer = writer.lock().unwrap();
+            let mut catalog = shared.catalog.write().unwrap();
+            if catalog.id(&name).is_some() {
+                return Err(KvsError::KeyspaceExists(name));
+            }
+            let keyspace = catalog.next_id;
+            let mut names = catalog.names.clone();
+            names.insert(keyspace, name);
+            write_keyspaces(&shared.path, keyspace + 1, &names)?;
+            *catalog = Catalog {
+                next_id: keyspace + 1,
+                names,
+            };
+            Ok(())
+        })
+    }
+
+    /// Drops a keyspace with all its keys.
+    ///
+    /// The keyspace is removed from the list of keyspaces. The tables that hold only its
+    /// keys are deleted in the background, and its keys in other tables are dropped by
+    /// later compactions.
+    fn drop_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let pool = self.pool.clone();
+        let shared = Arc::clone(&self.shared);
+        let writer = Arc::clone(&self.writer);
+        run_in_pool(&self.pool, move || {
+            if name == DEFAULT_KEYSPACE {
+                return Err(KvsError::StringError(
+                    "The default keyspace cannot be dropped".to_owned(),
+                ));
+            }
+            let mut writer = writer.lock().unwrap();
+            let mut catalog = shared.catalog.write().unwrap();
+            let keyspace = catalog
+                .id(&name)
+                .ok_or_else(|| KvsError::KeyspaceNotFound(name))?;
+            let mut names = catalog.names.clone();
+            names.remove(&keyspace);
+            write_keyspaces(&shared.path, catalog.next_id, &names)?;
+            catalog.names = names;
+            writer.watchers.remove_keyspace(keyspace);
+            drop(catalog);
+            shared.dropped_tables.store(true, Ordering::SeqCst);
+            schedule_maintenance(&pool, &shared);
+            Ok(())
+        })
+    }
+
+    fn list_keyspaces(&self) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            let catalog = shared.catalog.read().unwrap();
+            let mut names: Vec<String> = catalog.names.values().cloned().collect();
+            names.push(DEFAULT_KEYSPACE.to_owned());
+            names.sort();
+            Ok(names)
+        })
+    }
+}
+
+/// Options of an `LsmKvsEngine`, set in the builder style.
+///
+/// ```rust
+/// # use kvs::LsmOptions;
+/// let options = LsmOptions::new()
+///     .memtable_size(64 * 1024 * 1024)
+///     .table_size(8 * 1024 * 1024);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    memtable_size: u64,
+    table_size: u64,
+    durability: Durability,
+}
+
+impl Default for LsmOptions {
+    fn default() -> LsmOptions {
+        LsmOptions {
+            memtable_size: MEMTABLE_SIZE,
+            table_size: TABLE_SIZE,
+            durability: Durability::None,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Creates the default options.
+    ///
+    /// The memtable is flushed once it takes 4 MiB, and compactions write tables of about
+    /// 2 MiB. Syncing the write-ahead log is left to the operating system.
+    pub fn new() -> LsmOptions {
+        LsmOptions::default()
+    }
+
+    /// Sets the size in bytes at which the memtable is frozen and flushed to a table.
+    pub fn memtable_size(mut self, size: u64) -> LsmOptions {
+        self.memtable_size = size;
+        self
+    }
+
+    /// Sets the size in bytes at which a compaction starts a new table.
+    pub fn table_size(mut self, size: u64) -> LsmOptions {
+        self.table_size = size;
+        self
+    }
+
+    /// Sets when the writes are synced to disk.
+    pub fn durability(mut self, durability: Durability) -> LsmOptions {
+        self.durability = durability;
+        self
+    }
+}
+
+/// The state shared by the handles of an engine and its background work.
+struct Shared {
+    // directory for the logs, the tables and the manifest
+    path: PathBuf,
+    options: LsmOptions,
+    version: RwLock<Arc<Version>>,
+    catalog: RwLock<Catalog>,
+    // id of the next log or table file
+    next_file: AtomicU64,
+    // whether a flush or a compaction is scheduled or running
+    maintaining: AtomicBool,
+    // held while flushes and compactions are running
+    running: Mutex<()>,
+    // set when the last handle is dropped, to stop the background work
+    closing: AtomicBool,
+    // set when tables may hold only keys of dropped keyspaces
+    dropped_tables: AtomicBool,
+    // number of runs of the flushes and compactions in a row that have failed, and the
+    // last error
+    failures: Mutex<(u32, Option<String>)>,
+    // the last key of the last table compacted from each level, so that the tables of a
+    // level are compacted in turns
+    compact_pointers: Mutex<Vec<Vec<u8>>>,
+    compactions: AtomicU64,
+    // how long the last finished compaction took
+    last_compaction: Mutex<Option<Duration>>,
+}
+
+impl Shared {
+    fn current(&self) -> Arc<Version> {
+        Arc::clone(&*self.version.read().unwrap())
+    }
+
+    /// Returns `KvsError::KeyspaceDropped` if the keyspace has been dropped.
+    fn check_keyspace(&self, keyspace: u32) -> Result<()> {
+        if self.catalog.read().unwrap().contains(keyspace) {
+            Ok(())
+        } else {
+            Err(KvsError::KeyspaceDropped)
+        }
+    }
+
+    /// Returns the value of a key of the keyspace, unless it is removed or has expired.
+    fn value(&self, keyspace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let entry = self.current().get(&internal_key(keyspace, key))?;
+        Ok(entry.and_then(|entry| entry.live_value(now_millis())))
+    }
+
+    fn needs_maintenance(&self) -> bool {
+        let version = self.current();
+        !version.frozen.is_empty()
+            || self.dropped_tables.load(Ordering::SeqCst)
+            || needs_compaction(&version)
+    }
+
+    /// Returns whether writes have to wait for the flushes or the compactions.
+    fn stalled(&self) -> bool {
+        let version = self.current();
+        version.frozen.len() >= MAX_FROZEN_MEMTABLES || version.levels[0].len() >= L0_STALL_TRIGGER
+    }
+
+    /// Flushes the frozen memtables and deletes the tables of dropped keyspaces, then
+    /// compacts the levels until none exceeds its limit.
+    fn maintain(&self) -> Result<()> {
+        while !self.closing.load(Ordering::SeqCst) {
+            let version = self.current();
+            if let Some(mem) = version.frozen.last() {
+                self.flush(mem)?;
+            } else if self.dropped_tables.swap(false, Ordering::SeqCst) {
+                if let Err(e) = self.remove_dropped_tables(&version) {
+                    self.dropped_tables.store(true, Ordering::SeqCst);
+                    return Err(e);
+                }
+            } else if let Some(compaction) = self.pick_compaction(&version) {
+                self.compact(compaction)?;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the oldest frozen memtable to a level 0 table.
+    fn flush(&self, mem: &Memtable) -> Result<()> {
+        let table = if mem.entries.is_empty() {
+            None
+        } else {
+            let id = self.next_file.fetch_add(1, Ordering::SeqCst);
+            let mut builder = TableBuilder::create(&table_path(&self.path, id))?;
+            for (key, entry) in mem.iter() {
+                builder.add(&key, &entry)?;
+            }
+            builder.finish()?;
+            Some(Arc::new(Table::open(&self.path, id)?))
+        };
+        self.install(|version| {
+            version.frozen.pop();
+            if let Some(table) = table {
+                version.levels[0].insert(0, table);
+            }
+        })
+    }
+
+    /// Deletes the tables whose keys all belong to dropped keyspaces.
+    fn remove_dropped_tables(&self, version: &Version) -> Result<()> {
+        let dropped: Vec<Arc<Table>> = {
+            let catalog = self.catalog.read().unwrap();
+            version
+                .levels
+                .iter()
+                .flatten()
+                .filter(|table| {
+                    let keyspaces = keyspace_of(table.first_key())..=keyspace_of(&table.last_key);
+                    !catalog.contains_any(keyspaces)
+                })
+                .cloned()
+                .collect()
+        };
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        let ids: HashSet<u64> = dropped.iter().map(|table| table.id).collect();
+        self.install(|version| {
+            for tables in &mut version.levels {
+                tables.retain(|table| !ids.contains(&table.id));
+            }
+        })?;
+        // the files are deleted once no version uses them
+        for table in dropped {
+            table.obsolete.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Counts the runs of the flushes and compactions that have failed in a row.
+    fn record_maintenance(&self, res: &Result<()>) {
+        let mut failures = self.failures.lock().unwrap();
+        *failures = match res {
+            Ok(()) => (0, None),
+            Err(e) => (failures.0 + 1, Some(format!("{}", e))),
+        };
+    }
+
+    /// Picks the tables to compact: all level 0 tables if there are enough of them, or a
+    /// table of the shallowest level that exceeds its limit, with the tables they overlap
+    /// in the next level.
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction> {
+        if version.levels[0].len() >= L0_COMPACTION_TRIGGER {
+            return Some(Compaction::new(version, 0, version.levels[0].clone()));
+        }
+        for level in 1..MAX_LEVELS - 1 {
+            let tables = &version.levels[level];
+            if level_size(tables) <= max_level_size(level) {
+                continue;
+            }
+            let mut pointers = self.compact_pointers.lock().unwrap();
+            let table = tables
+                .iter()
+                .find(|table| table.first_key() > pointers[level].as_slice())
+                .unwrap_or(&tables[0]);
+            pointers[level] = table.last_key.clone();
+            return Some(Compaction::new(version, level, vec![Arc::clone(table)]));
+        }
+        None
+    }
+
+    /// Merges the tables of a compaction into new tables of the next level.
+    ///
+    /// Entries of dropped keyspaces are dropped. Expired values become removals, and
+    /// removals are dropped if no deeper level can hold an older value of their keys.
+    fn compact(&self, compaction: Compaction) -> Result<()> {
+        let start = Instant::now();
+        let output_level = compaction.level + 1;
+        let bottom = self.current().levels[output_level + 1..]
+            .iter()
+            .all(Vec::is_empty);
+        let catalog = self.catalog.read().unwrap().clone();
+        let now = now_millis();
+
+        let full_range = (Bound::Unbounded, Bound::Unbounded);
+        let sources = compaction
+            .inputs
+            .iter()
+            .chain(&compaction.overlapping)
+            .map(|table| Box::new(TableIter::new(Arc::clone(table), &full_range)) as EntryIter)
+            .collect();
+        let mut outputs = Vec::new();
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        for res in MergeIter::new(sources) {
+            let (key, entry) = res?;
+            if !catalog.contains(keyspace_of(&key)) {
+                continue;
+            }
+            let entry = if entry.expired(now) {
+                Entry::Tombstone
+            } else {
+                entry
+            };
+            if bottom && entry.is_tombstone() {
+                continue;
+            }
+            if builder.is_none() {
+                let id = self.next_file.fetch_add(1, Ordering::SeqCst);
+                builder = Some((id, TableBuilder::create(&table_path(&self.path, id))?));
+            }
+            if let Some((id, mut table)) = builder.take() {
+                table.add(&key, &entry)?;
+                if table.estimated_size() < self.options.table_size {
+                    builder = Some((id, table));
+                } else {
+                    table.finish()?;
+                    outputs.push(Arc::new(Table::open(&self.path, id)?));
+                }
+            }
+        }
+        if let Some((id, table)) = builder {
+            table.finish()?;
+            outputs.push(Arc::new(Table::open(&self.path, id)?));
+        }
+
+        let compacted: HashSet<u64> = compaction
+            .inputs
+            .iter()
+            .chain(&compaction.overlapping)
+            .map(|table| table.id)
+            .collect();
+        self.install(|version| {
+            version.levels[compaction.level].retain(|table| !compacted.contains(&table.id));
+            let level = &mut version.levels[output_level];
+            level.retain(|table| !compacted.contains(&table.id));
+            level.extend(outputs);
+            level.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        })?;
+        // the files are deleted once no version uses them
+        for table in compaction.inputs.iter().chain(&compaction.overlapping) {
+            table.obsolete.store(true, Ordering::SeqCst);
+        }
+
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+        *self.last_compaction.lock().unwrap() = Some(start.elapsed());
+        Ok(())
+    }
+
+    /// Makes the version changed by `edit` the current one and records its tables in the
+    /// manifest. The logs whose writes are all in tables afterwards are deleted.
+    fn install<F>(&self, edit: F) -> Result<()>
+    where
+        F: FnOnce(&mut Version),
+    {
+        let mut current = self.version.write().unwrap();
+        let mut version = Version::clone(&current);
+        edit(&mut version);
+        let manifest = Manifest {
+            next_file: self.next_file.load(Ordering::SeqCst),
+            log_number: version.log_number(),
+            levels: version
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        write_manifest(&self.path, &manifest)?;
+        let flushed = current.log_number()..manifest.log_number;
+        *current = Arc::new(version);
+        drop(current);
+
+        for id in flushed {
+            if let Err(e) = fs::remove_file(wal_path(&self.path, id)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flushes the frozen memtables and compacts the levels in the thread pool if they need
+/// it, unless that is already being done.
+fn schedule_maintenance<P: ThreadPool>(pool: &P, shared: &Arc<Shared>) {
+    if shared.closing.load(Ordering::SeqCst)
+        || !shared.needs_maintenance()
+        || shared.maintaining.swap(true, Ordering::SeqCst)
+    {
+        return;
+    }
+    let shared = Arc::clone(shared);
+    pool.spawn(move || loop {
+        let res = {
+            let _running = shared.running.lock().unwrap();
+            shared.maintain()
+        };
+        shared.maintaining.store(false, Ordering::SeqCst);
+        shared.record_maintenance(&res);
+        if let Err(e) = res {
+            error!("Failed to flush or compact tables: {}", e);
+            return;
+        }
+        // a memtable may have been frozen after the last check
+        if shared.closing.load(Ordering::SeqCst)
+            || !shared.needs_maintenance()
+            || shared.maintaining.swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+    });
+}
+
+/// Flushes and compacts in the calling thread while there are too many frozen memtables
+/// or level 0 tables, so that writes cannot run ahead of the background work without
+/// bound. The work is done here instead of being waited for, since the thread pool it is
+/// scheduled in may be busy with stalled writes.
+///
+/// It fails with `KvsError::BackgroundWorkFailed` if the flushes and compactions have
+/// failed too many times in a row. They are retried first, and the write goes on if the
+/// retry succeeds.
+fn make_room_for_write(shared: &Shared) -> Result<()> {
+    let mut retried = false;
+    loop {
+        let (failures, error) = shared.failures.lock().unwrap().clone();
+        if failures >= MAX_BACKGROUND_FAILURES {
+            if retried {
+                return Err(KvsError::BackgroundWorkFailed(error.unwrap_or_default()));
+            }
+            retried = true;
+        } else if !shared.stalled() {
+            return Ok(());
+        }
+        let res = {
+            let _running = shared.running.lock().unwrap();
+            shared.maintain()
+        };
+        shared.record_maintenance(&res);
+    }
+}
+
+/// Stops the background work when the last handle of the engine is dropped, and holds
+/// the lock of the data directory until it has stopped.
+struct CloseGuard {
+    shared: Arc<Shared>,
+    _lock: File,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        self.shared.closing.store(true, Ordering::SeqCst);
+        // a flush or compaction that has started stops at its next step
+        let _running = self.shared.running.lock().unwrap();
+    }
+}
+
+/// The write-ahead log with the watches, used by one write at a time.
+struct LsmWriter {
+    wal: File,
+    // a handle of the log file for syncing outside the writer lock, replaced when a new
+    // log is started
+    active_wal: Arc<Mutex<File>>,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    watchers: Watchers,
+}
+
+impl LsmWriter {
+    /// Appends the entries of the keyspace to the log as one record and inserts them into
+    /// the memtable. The memtable is frozen if it exceeds its size afterwards.
+    fn write(
+        &mut self,
+        shared: &Shared,
+        keyspace: u32,
+        entries: Vec<(Vec<u8>, Entry)>,
+    ) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, entry)| (internal_key(keyspace, &key), entry))
+            .collect();
+        let mut payload = Vec::new();
+        for (key, entry) in &entries {
+            encode_entry(&mut payload, key, entry);
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        write_frame(&mut record, &payload)?;
+        self.wal.write_all(&record)?;
+        match self.durability {
+            Durability::EveryWrite => self.wal.sync_data()?,
+            Durability::GroupCommit => {
+                self.group_commit.record_write();
+            }
+            _ => {}
+        }
+
+        let mem = Arc::clone(&shared.current().mem);
+        for (key, entry) in entries {
+            let user_key = &key[KEYSPACE_ID_LEN..];
+            if self.watchers.watches(keyspace, user_key) {
+                let event = match &entry {
+                    Entry::Value { value, .. } => WatchEvent::Set {
+                        key: user_key.to_vec(),
+                        value: value.clone(),
+                    },
+                    Entry::Tombstone => WatchEvent::Remove {
+                        key: user_key.to_vec(),
+                    },
+                };
+                self.watchers.send(keyspace, &event);
+            }
+            mem.insert(key, entry);
+        }
+        if mem.size() >= shared.options.memtable_size {
+            self.freeze(shared)?;
+        }
+        Ok(())
+    }
+
+    /// Freezes the memtable and starts a new one with a new log.
+    fn freeze(&mut self, shared: &Shared) -> Result<()> {
+        // a write waiting for a group commit may be in the old log, which is not synced
+        // by the commit
+        if self.durability != Durability::None {
+            self.wal.sync_data()?;
+        }
+        let wal_id = shared.next_file.fetch_add(1, Ordering::SeqCst);
+        let wal = create_wal(&shared.path, wal_id)?;
+        *self.active_wal.lock().unwrap() = wal.try_clone()?;
+        self.wal = wal;
+
+        let mut current = shared.version.write().unwrap();
+        let mut version = Version::clone(&current);
+        let frozen = mem::replace(&mut version.mem, Arc::new(Memtable::new(wal_id)));
+        version.frozen.insert(0, frozen);
+        *current = Arc::new(version);
+        Ok(())
+    }
+}
+
+/// Runs `f` with the writer, then waits for a group commit to sync the write if the
+/// durability mode asks for it. The sync is done without holding the writer lock.
+fn with_writer<F, R>(writer: &Mutex<LsmWriter>, f: F) -> Result<R>
+where
+    F: FnOnce(&mut LsmWriter) -> Result<R>,
+{
+    let mut guard = writer.lock().unwrap();
+    let res = f(&mut *guard)?;
+    if guard.durability != Durability::GroupCommit {
+        return Ok(res);
+    }
+    let ticket = guard.group_commit.last_write();
+    let group_commit = Arc::clone(&guard.group_commit);
+    let active_wal = Arc::clone(&guard.active_wal);
+    drop(guard);
+    group_commit.commit(ticket, || Ok(active_wal.lock().unwrap().sync_data()?))?;
+    Ok(res)
+}
+
+/// The memtables and the tables a read sees.
+///
+/// A version is never changed once it is current. Writers install a changed copy, so a
+/// read works on the same tables from start to end, and the files of the tables it uses
+/// are kept until it is done.
+#[derive(Clone)]
+struct Version {
+    mem: Arc<Memtable>,
+    // memtables waiting to be flushed, newest first
+    frozen: Vec<Arc<Memtable>>,
+    // level 0 tables are newest first and may overlap; the tables of the other levels
+    // are sorted by key and disjoint
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    /// Returns the newest entry of an internal key.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        for mem in iter::once(&self.mem).chain(&self.frozen) {
+            if let Some(entry) = mem.get(key) {
+                return Ok(Some(entry));
+            }
+        }
+        for table in &self.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for tables in &self.levels[1..] {
+            // the only table that may have the key is the first one ending at or after it
+            let i = match tables.binary_search_by(|table| table.last_key.as_slice().cmp(key)) {
+                Ok(i) | Err(i) => i,
+            };
+            if let Some(table) = tables.get(i) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the id of the oldest log whose writes are not all in tables.
+    fn log_number(&self) -> u64 {
+        self.frozen.last().unwrap_or(&self.mem).wal_id
+    }
+}
+
+/// Returns the entries of a range of internal keys in key order, merged from the
+/// memtables and the tables of a version.
+fn merged_entries(version: &Version, range: &KeyRange) -> MergeIter {
+    let mut sources: Vec<EntryIter> = Vec::new();
+    for mem in iter::once(&version.mem).chain(&version.frozen) {
+        sources.push(Box::new(MemIter::new(Arc::clone(mem), range)));
+    }
+    for table in &version.levels[0] {
+        if table.overlaps(range) {
+            sources.push(Box::new(TableIter::new(Arc::clone(table), range)));
+        }
+    }
+    // the tables of a deeper level are disjoint, so they are read one after another
+    for tables in &version.levels[1..] {
+        let tables: Vec<Arc<Table>> = tables
+            .iter()
+            .filter(|table| table.overlaps(range))
+            .cloned()
+            .collect();
+        if !tables.is_empty() {
+            let range = range.clone();
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .flat_map(move |table| TableIter::new(table, &range)),
+            ));
+        }
+    }
+    MergeIter::new(sources)
+}
+
+/// A write of a key, or its removal.
+#[derive(Debug, Clone)]
+enum Entry {
+    Value {
+        value: Vec<u8>,
+        // expiry time in milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+    },
+    Tombstone,
+}
+
+impl Entry {
+    /// Returns the value unless the entry is a removal or has expired at `now`.
+    fn live_value(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Entry::Value { value, expires_at } => match expires_at {
+                Some(expires_at) if expires_at <= now => None,
+                _ => Some(value),
+            },
+            Entry::Tombstone => None,
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        match self {
+            Entry::Value {
+                expires_at: Some(expires_at),
+                ..
+            } => *expires_at <= now,
+            _ => false,
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        match self {
+            Entry::Tombstone => true,
+            Entry::Value { .. } => false,
+        }
+    }
+}
+
+/// Appends an internal key with its entry to `buf`.
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], entry: &Entry) {
+    put_bytes(buf, key);
+    match entry {
+        Entry::Value {
+            value,
+            expires_at: None,
+        } => {
+            buf.push(ENTRY_VALUE);
+            put_bytes(buf, value);
+        }
+        Entry::Value {
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            buf.push(ENTRY_VALUE_WITH_EXPIRY);
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+            put_bytes(buf, value);
+        }
+        Entry::Tombstone => buf.push(ENTRY_TOMBSTONE),
+    }
+}
+
+/// Decodes the entries encoded one after another in `data`, or returns `None` if it is
+/// malformed.
+fn decode_entries(mut data: &[u8]) -> Option<Vec<(Vec<u8>, Entry)>> {
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let key = take_bytes(&mut data)?.to_vec();
+        let entry = match take(&mut data, 1)?[0] {
+            ENTRY_VALUE => Entry::Value {
+                value: take_bytes(&mut data)?.to_vec(),
+                expires_at: None,
+            },
+            ENTRY_VALUE_WITH_EXPIRY => {
+                let expires_at = le_u64(take(&mut data, 8)?);
+                Entry::Value {
+                    value: take_bytes(&mut data)?.to_vec(),
+                    expires_at: Some(expires_at),
+                }
+            }
+            ENTRY_TOMBSTONE => Entry::Tombstone,
+            _ => return None,
+        };
+        entries.push((key, entry));
+    }
+    Some(entries)
+}
+
+/// The writes not yet in tables, sorted by internal key.
+struct Memtable {
+    // id of the oldest log holding the writes
+    wal_id: u64,
+    entries: SkipMap<Vec<u8>, Entry>,
+    // approximate bytes taken by the entries, including overwritten ones
+    size: AtomicU64,
+}
+
+impl Memtable {
+    fn new(wal_id: u64) -> Memtable {
+        Memtable {
+            wal_id,
+            entries: SkipMap::new(),
+            size: AtomicU64::new(0),
+        }
+    }
+
+    fn insert(&self, key: Vec<u8>, entry: Entry) {
+        let value_len = match &entry {
+            Entry::Value { value, .. } => value.len(),
+            Entry::Tombstone => 0,
+        };
+        let size = (key.len() + value_len) as u64 + MEMTABLE_ENTRY_OVERHEAD;
+        self.size.fetch_add(size, Ordering::SeqCst);
+        self.entries.insert(key, entry);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.entries.get(key).map(|entry| entry.value().clone())
+    }
+
+    fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (Vec<u8>, Entry)> + 'a {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+    }
+}
+
+// a sorted source of entries for a merge
+type EntryIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>>>;
+
+/// Merges sorted sources of entries into one sorted stream. Of the entries of a key in
+/// several sources, only the one of the first source is returned, so the sources are
+/// given newest first.
+struct MergeIter {
+    sources: Vec<Peekable<EntryIter>>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<EntryIter>) -> MergeIter {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<(usize, Vec<u8>)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) => {
+                    if smallest
+                        .as_ref()
+                        .map_or(true, |(_, smallest)| key < smallest)
+                    {
+                        smallest = Some((i, key.clone()));
+                    }
+                }
+                Some(Err(_)) => return source.next(),
+                None => {}
+            }
+        }
+        let (i, key) = smallest?;
+        let next = self.sources[i].next();
+        // the entries of the key in the later sources are older
+        for source in &mut self.sources[i + 1..] {
+            let shadowed = match source.peek() {
+                Some(Ok((other, _))) => *other == key,
+                _ => false,
+            };
+            if shadowed {
+                source.next();
+            }
+        }
+        next
+    }
+}
+
+/// Iterates over the entries of a memtable in a key range. The memtable may be written
+/// meanwhile, so each step looks up the entry after the last one returned.
+struct MemIter {
+    mem: Arc<Memtable>,
+    range: KeyRange,
+}
+
+impl MemIter {
+    fn new(mem: Arc<Memtable>, range: &KeyRange) -> MemIter {
+        MemIter {
+            mem,
+            range: range.clone(),
+        }
+    }
+}
+
+impl Iterator for MemIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, entry) = {
+            let entry = self.mem.entries.range(self.range.clone()).next()?;
+            (entry.key().clone(), entry.value().clone())
+        };
+        self.range.0 = Bound::Excluded(key.clone());
+        Some(Ok((key, entry)))
+    }
+}
+
+/// An immutable table file, mapped into memory.
+///
+/// It is made of framed data blocks of entries sorted by key, then a framed index with
+/// the last key of the table and the first key, offset and length of every block, a
+/// framed bloom filter of the keys, and a fixed-size footer locating the index and the
+/// filter.
+struct Table {
+    id: u64,
+    path: PathBuf,
+    mmap: Mmap,
+    // first key, offset and length of every block
+    index: Vec<(Vec<u8>, u64, u64)>,
+    last_key: Vec<u8>,
+    filter: BloomFilter,
+    // set when a compaction has replaced the table, which is deleted once it is dropped
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    fn open(dir: &Path, id: u64) -> Result<Table> {
+        let path = table_path(dir, id);
+        let file = File::open(&path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let corrupted = || KvsError::CorruptedTable(id);
+        if mmap.len() < FOOTER_LEN {
+            return Err(corrupted());
+        }
+        let footer = &mmap[mmap.len() - FOOTER_LEN..];
+        if le_u64(&footer[32..]) != TABLE_MAGIC {
+            return Err(corrupted());
+        }
+
+        let index_data =
+            read_block_at(&mmap, le_u64(footer), le_u64(&footer[8..])).ok_or_else(corrupted)?;
+        let mut data = index_data.as_slice();
+        let last_key = take_bytes(&mut data).ok_or_else(corrupted)?.to_vec();
+        let mut index = Vec::new();
+        while !data.is_empty() {
+            let first_key = take_bytes(&mut data).ok_or_else(corrupted)?.to_vec();
+            let location = take(&mut data, 16).ok_or_else(corrupted)?;
+            index.push((first_key, le_u64(location), le_u64(&location[8..])));
+        }
+        if index.is_empty() {
+            return Err(corrupted());
+        }
+        let bits = read_block_at(&mmap, le_u64(&footer[16..]), le_u64(&footer[24..]))
+            .ok_or_else(corrupted)?;
+        if bits.is_empty() {
+            return Err(corrupted());
+        }
+
+        Ok(Table {
+            id,
+            path,
+            mmap,
+            index,
+            last_key,
+            filter: BloomFilter { bits },
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    fn first_key(&self) -> &[u8] {
+        &self.index[0].0
+    }
+
+    fn size(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    fn overlaps(&self, range: &KeyRange) -> bool {
+        after_start(&self.last_key, &range.0) && before_end(self.first_key(), &range.1)
+    }
+
+    /// Returns the entry of an internal key.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if key < self.first_key() || key > self.last_key.as_slice() || !self.filter.may_contain(key)
+        {
+            return Ok(None);
+        }
+        let entries = self.read_block(self.block_of(key))?;
+        Ok(entries
+            .into_iter()
+            .find(|(other, _)| other.as_slice() == key)
+            .map(|(_, entry)| entry))
+    }
+
+    /// Returns the index of the block that may hold the key.
+    fn block_of(&self, key: &[u8]) -> usize {
+        match self
+            .index
+            .binary_search_by(|(first_key, _, _)| first_key.as_slice().cmp(key))
+        {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),
+        }
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let (_, offset, len) = &self.index[i];
+        read_block_at(&self.mmap, *offset, *len)
+            .and_then(|block| decode_entries(&block))
+            .ok_or(KvsError::CorruptedTable(self.id))
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Failed to remove compacted table {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Reads the framed block at `offset` of a table, or returns `None` if it is out of
+/// bounds or fails its checksum.
+fn read_block_at(data: &[u8], offset: u64, len: u64) -> Option<Vec<u8>> {
+    let end = offset.checked_add(len)?;
+    if end > data.len() as u64 {
+        return None;
+    }
+    let mut block = &data[offset as usize..end as usize];
+    match read_frame(&mut block, len) {
+        Ok(Frame::Record(payload)) => Some(payload),
+        _ => None,
+    }
+}
+
+/// Iterates over the entries of a table in a key range, one block at a time.
+struct TableIter {
+    table: Arc<Table>,
+    range: KeyRange,
+    // the next block to read
+    block: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, Entry)>,
+}
+
+impl TableIter {
+    fn new(table: Arc<Table>, range: &KeyRange) -> TableIter {
+        let block = match &range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => table.block_of(start),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table,
+            range: range.clone(),
+            block,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, entry)) = self.entries.next() {
+                if !after_start(&key, &self.range.0) {
+                    continue;
+                }
+                if !before_end(&key, &self.range.1) {
+                    self.block = self.table.index.len();
+                    self.entries = Vec::new().into_iter();
+                    return None;
+                }
+                return Some(Ok((key, entry)));
+            }
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.block += 1;
+                }
+                Err(e) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Writes a table file from entries added in key order.
+struct TableBuilder {
+    writer: BufWriter<File>,
+    // bytes written so far
+    offset: u64,
+    // entries of the block being built
+    block: Vec<u8>,
+    block_first_key: Vec<u8>,
+    index: Vec<(Vec<u8>, u64, u64)>,
+    last_key: Vec<u8>,
+    // hashes of the keys for the bloom filter
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    fn create(path: &Path) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            writer: BufWriter::new(File::create(path)?),
+            offset: 0,
+            block: Vec::new(),
+            block_first_key: Vec::new(),
+            index: Vec::new(),
+            last_key: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    fn add(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        if self.block.is_empty() {
+            self.block_first_key = key.to_vec();
+        }
+        encode_entry(&mut self.block, key, entry);
+        self.hashes.push(bloom_hash(key));
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn estimated_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let mut block = mem::replace(&mut self.block, Vec::new());
+        let len = self.write_block(&block)?;
+        let first_key = mem::replace(&mut self.block_first_key, Vec::new());
+        self.index.push((first_key, self.offset - len, len));
+        block.clear();
+        self.block = block;
+        Ok(())
+    }
+
+    /// Writes a framed block and returns its length.
+    fn write_block(&mut self, payload: &[u8]) -> Result<u64> {
+        write_frame(&mut self.writer, payload)?;
+        let len = (RECORD_HEADER_LEN + payload.len()) as u64;
+        self.offset += len;
+        Ok(len)
+    }
+
+    /// Writes the index, the bloom filter and the footer, and syncs the file.
+    fn finish(mut self) -> Result<()> {
+        if !self.block.is_empty() {
+            self.finish_block()?;
+        }
+        let mut index = Vec::new();
+        put_bytes(&mut index, &self.last_key);
+        for (first_key, offset, len) in &self.index {
+            put_bytes(&mut index, first_key);
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&len.to_le_bytes());
+        }
+        let index_offset = self.offset;
+        let index_len = self.write_block(&index)?;
+        let filter = BloomFilter::build(&self.hashes);
+        let filter_offset = self.offset;
+        let filter_len = self.write_block(&filter.bits)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        for field in &[
+            index_offset,
+            index_len,
+            filter_offset,
+            filter_len,
+            TABLE_MAGIC,
+        ] {
+            footer.extend_from_slice(&field.to_le_bytes());
+        }
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// A bloom filter of the keys of a table, probed with double hashing.
+struct BloomFilter {
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    fn build(hashes: &[u64]) -> BloomFilter {
+        let bits_len = cmp::max(64, hashes.len() * BLOOM_BITS_PER_KEY);
+        let mut bits = vec![0; (bits_len + 7) / 8];
+        let bits_len = bits.len() as u64 * 8;
+        for &hash in hashes {
+            for bit in bloom_probes(hash, bits_len) {
+                bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        BloomFilter { bits }
+    }
+
+    /// Returns `false` if the key is certainly not in the table.
+    fn may_contain(&self, key: &[u8]) -> bool {
+        let bits_len = self.bits.len() as u64 * 8;
+        bloom_probes(bloom_hash(key), bits_len)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Returns the bits probed for a key hash.
+fn bloom_probes(hash: u64, bits_len: u64) -> impl Iterator<Item = u64> {
+    let delta = hash.rotate_left(32) | 1;
+    (0..BLOOM_PROBES).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % bits_len)
+}
+
+/// 64-bit FNV-1a, which unlike the hasher of the standard library is the same across
+/// builds, as the filters on disk require.
+fn bloom_hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The tables of a compaction.
+struct Compaction {
+    // the level of the input tables
+    level: usize,
+    inputs: Vec<Arc<Table>>,
+    // the tables of the next level overlapping the inputs
+    overlapping: Vec<Arc<Table>>,
+}
+
+impl Compaction {
+    fn new(version: &Version, level: usize, inputs: Vec<Arc<Table>>) -> Compaction {
+        let first_key = inputs.iter().map(|table| table.first_key()).min();
+        let last_key = inputs.iter().map(|table| table.last_key.as_slice()).max();
+        let overlapping = match (first_key, last_key) {
+            (Some(first_key), Some(last_key)) => version.levels[level + 1]
+                .iter()
+                .filter(|table| {
+                    table.first_key() <= last_key && table.last_key.as_slice() >= first_key
+                })
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        Compaction {
+            level,
+            inputs,
+            overlapping,
+        }
+    }
+}
+
+fn needs_compaction(version: &Version) -> bool {
+    version.levels[0].len() >= L0_COMPACTION_TRIGGER
+        || (1..MAX_LEVELS - 1)
+            .any(|level| level_size(&version.levels[level]) > max_level_size(level))
+}
+
+fn level_size(tables: &[Arc<Table>]) -> u64 {
+    tables.iter().map(|table| table.size()).sum()
+}
+
+fn max_level_size(level: usize) -> u64 {
+    L1_MAX_SIZE * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1)
+}
+
+/// The tables of each level, saved after every flush and compaction.
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    // id of the next log or table file
+    next_file: u64,
+    // id of the oldest log whose writes are not all in tables
+    log_number: u64,
+    // ids of the tables of each level, in the order of `Version::levels`
+    levels: Vec<Vec<u64>>,
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    match File::open(path.join(MANIFEST_FILE)) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the manifest atomically.
+fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = path.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, manifest)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path.join(MANIFEST_FILE))?;
+    Ok(())
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, WAL_EXTENSION))
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, TABLE_EXTENSION))
+}
+
+/// Returns the id and the extension name of a log or table file.
+fn parse_file_name(path: &Path) -> Option<(u64, String)> {
+    let extension = path.extension()?.to_str()?;
+    if extension != WAL_EXTENSION && extension != TABLE_EXTENSION {
+        return None;
+    }
+    let id = path.file_stem()?.to_str()?.parse().ok()?;
+    Some((id, extension.to_owned()))
+}
+
+fn create_wal(dir: &Path, id: u64) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(wal_path(dir, id))?)
+}
+
+/// Inserts the writes of a log into the memtable.
+///
+/// A torn record at the end of the log is what an interrupted write leaves behind, and
+/// it is cut off.
+fn replay_wal(dir: &Path, id: u64, mem: &Memtable) -> Result<()> {
+    let path = wal_path(dir, id);
+    let len = fs::metadata(&path)?.len();
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut offset = 0;
+    loop {
+        match read_frame(&mut reader, len - offset)? {
+            Frame::Record(payload) => {
+                let entries =
+                    decode_entries(&payload).ok_or(KvsError::CorruptedLog { gen: id, offset })?;
+                for (key, entry) in entries {
+                    mem.insert(key, entry);
+                }
+                offset += (RECORD_HEADER_LEN + payload.len()) as u64;
+            }
+            Frame::Eof => return Ok(()),
+            Frame::Torn => {
+                warn!("Truncating torn record at offset {} of log {}", offset, id);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(offset)?;
+                return Ok(());
+            }
+            Frame::Corrupted => return Err(KvsError::CorruptedLog { gen: id, offset }),
+        }
+    }
+}
+
+/// Returns the entries written by a batch.
+fn batch_entries(batch: WriteBatch) -> Vec<(Vec<u8>, Entry)> {
+    batch
+        .ops
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => (
+                key,
+                Entry::Value {
+                    value,
+                    expires_at: None,
+                },
+            ),
+            BatchOp::Remove { key } => (key, Entry::Tombstone),
+        })
+        .collect()
+}
@@ -0,0 +1,824 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tokio::prelude::*;
+
+use super::keyspace::{
+    before_end, internal_key, keyspace_of, keyspace_range, Catalog, KeyRange, Watchers,
+    KEYSPACE_ID_LEN,
+};
+use super::kvs::{
+    le_u64, lock_dir, read_frame, read_keyspaces, write_frame, write_keyspaces, Frame,
+    DEFAULT_KEYSPACE_ID, RECORD_HEADER_LEN,
+};
+use super::{
+    check_keyspace_name, create_checkpoint_dir, expiry_time, now_millis, owned_range, put_bytes,
+    run_in_pool, spawn_periodic, take, take_bytes, EngineStats, KvPairs, KvsEngine, WatchEvent,
+    DEFAULT_KEYSPACE,
+};
+use crate::batch::BatchOp;
+use crate::thread_pool::ThreadPool;
+use crate::transaction::run_transaction;
+use crate::{KvsError, Result, Transaction, WriteBatch};
+
+const SNAPSHOT_FILE: &str = "memory.snapshot";
+// The first record of a snapshot holds the magic number, and each following one holds
+// entries up to about this size.
+const SNAPSHOT_BLOCK_SIZE: usize = 64 * 1024;
+const SNAPSHOT_MAGIC: u64 = 0x6b76_735f_6d65_6d31;
+
+// key length (u32), key, flags (u8), then the value length (u32) and the value
+// flag of an entry whose flags are followed by its expiry time (u64)
+const ENTRY_EXPIRES: u8 = 1;
+
+// how often expired keys are removed from the map
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// keys looked at with one lock of the map, so that writes do not wait for long
+const EXPIRY_SWEEP_CHUNK: usize = 1000;
+
+/// A storage engine keeping all the keys in memory.
+///
+/// The keys of all keyspaces are kept in a single sorted map, prefixed with the id of
+/// their keyspace. Reads share a lock of the map that writes take exclusively, so a
+/// write, a batch and a transaction are each seen as a whole.
+///
+/// An engine created with `MemoryKvsEngine::new` keeps nothing on disk. One opened with
+/// a path loads the snapshot in it, and writes a new snapshot when the last handle is
+/// dropped and, if `MemoryOptions::snapshot_interval` is set, at that interval. The writes
+/// since the last snapshot are lost if the process crashes.
+///
+/// A snapshot is written to a temporary file renamed over the previous one, so a crash
+/// while writing it leaves the previous one. The entries are written straight from the
+/// map while holding its lock, so writes wait while the file is written, but not while it
+/// is synced.
+///
+/// Expired keys are skipped by reads and left out of snapshots. A thread removes them from
+/// the map every second, and the removals are watched like any other write.
+///
+/// ```rust
+/// # use kvs::{MemoryKvsEngine, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// let store: MemoryKvsEngine<RayonThreadPool> = MemoryKvsEngine::new(2)?;
+/// store.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = store.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MemoryKvsEngine<P: ThreadPool> {
+    pool: P,
+    // id of the keyspace the handle works on
+    keyspace: u32,
+    shared: Arc<Shared>,
+    // writes the last snapshot when the last handle is dropped
+    _guard: Arc<CloseGuard>,
+}
+
+impl<P: ThreadPool> MemoryKvsEngine<P> {
+    /// Creates an empty `MemoryKvsEngine` that keeps nothing on disk.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    pub fn new(concurrency: u32) -> Result<Self> {
+        let catalog = Catalog {
+            next_id: DEFAULT_KEYSPACE_ID + 1,
+            names: BTreeMap::new(),
+        };
+        let shared = Arc::new(Shared::new(None, catalog, BTreeMap::new()));
+        spawn_periodic(
+            Arc::downgrade(&shared),
+            EXPIRY_SWEEP_INTERVAL,
+            "expiry sweep",
+            Shared::remove_expired,
+        );
+        Ok(MemoryKvsEngine {
+            pool: P::new(concurrency)?,
+            keyspace: DEFAULT_KEYSPACE_ID,
+            _guard: Arc::new(CloseGuard {
+                shared: Arc::clone(&shared),
+                _lock: None,
+            }),
+            shared,
+        })
+    }
+
+    /// Opens a `MemoryKvsEngine` with the given path, loading the snapshot in it.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if another process has the data directory
+    /// open.
+    ///
+    /// It returns `KvsError::CorruptedSnapshot` if the snapshot fails its checksums or
+    /// cannot be decoded.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        MemoryKvsEngine::open_with(path, concurrency, MemoryOptions::default())
+    }
+
+    /// Opens a `MemoryKvsEngine` with the given path and options, loading the snapshot in
+    /// it.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: MemoryOptions,
+    ) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path, true)?;
+
+        let (next_id, names) = read_keyspaces(&path)?;
+        let catalog = Catalog { next_id, names };
+        // the keys of keyspaces dropped after the snapshot was written are left out
+        let now = now_millis();
+        let data = read_snapshot(&path.join(SNAPSHOT_FILE))?
+            .into_iter()
+            .filter(|(key, entry)| catalog.contains(keyspace_of(key)) && !entry.expired(now))
+            .collect();
+        let shared = Arc::new(Shared::new(Some(path), catalog, data));
+
+        spawn_periodic(
+            Arc::downgrade(&shared),
+            EXPIRY_SWEEP_INTERVAL,
+            "expiry sweep",
+            Shared::remove_expired,
+        );
+        if let Some(interval) = options.snapshot_interval {
+            spawn_periodic(
+                Arc::downgrade(&shared),
+                interval,
+                "snapshot",
+                Shared::snapshot,
+            );
+        }
+        Ok(MemoryKvsEngine {
+            pool: P::new(concurrency)?,
+            keyspace: DEFAULT_KEYSPACE_ID,
+            _guard: Arc::new(CloseGuard {
+                shared: Arc::clone(&shared),
+                _lock: Some(lock),
+            }),
+            shared,
+        })
+    }
+
+    /// Runs `f` on the map in the thread pool, after checking that the keyspace of the
+    /// handle still exists.
+    fn run_write<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut Writer) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || shared.write(keyspace, f))
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for MemoryKvsEngine<P> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |writer| {
+            writer.set(key, value, None);
+            Ok(())
+        })
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || shared.value(keyspace, &key))
+    }
+
+    /// Removes a given key.
+    ///
+    /// An expired key is removed too, but `KvsError::KeyNotFound` is returned for it.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let removed = self.run_write(move |writer| {
+            let entry = writer.remove(&key);
+            Ok(entry.map_or(false, |entry| !entry.expired(now_millis())))
+        });
+        Box::new(removed.and_then(|removed| {
+            if removed {
+                Ok(())
+            } else {
+                Err(KvsError::KeyNotFound)
+            }
+        }))
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = expiry_time(ttl);
+        self.run_write(move |writer| {
+            writer.set(key, value, Some(expires_at));
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |writer| {
+            let current = writer.value(&key);
+            if current != expected {
+                return Err(KvsError::ConditionFailed(current));
+            }
+            match new {
+                Some(value) => writer.set(key, value, None),
+                None => {
+                    writer.remove(&key);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.run_write(move |writer| {
+            writer.apply(batch);
+            Ok(())
+        })
+    }
+
+    /// Runs `f` in an optimistic transaction and returns its result.
+    ///
+    /// The keys the transaction has read are validated and its writes are applied while
+    /// holding the lock of the map, so it is serialized with all other writes.
+    fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            let read = |key: &[u8]| shared.value(keyspace, key);
+            run_transaction(f, read, |reads, batch| {
+                shared.write(keyspace, |writer| {
+                    for (key, value) in reads {
+                        if writer.value(&key) != value {
+                            return Ok(false);
+                        }
+                    }
+                    writer.apply(batch);
+                    Ok(true)
+                })
+            })
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        let range = owned_range(&range);
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            let data = shared.data.read().unwrap();
+            shared.check_keyspace(keyspace)?;
+            let now = now_millis();
+            let pairs = entries(&data, &keyspace_range(keyspace, range))
+                .filter(|(_, entry)| !entry.expired(now))
+                .take(limit.unwrap_or(usize::max_value()))
+                .map(|(key, entry)| (key[KEYSPACE_ID_LEN..].to_vec(), entry.value.clone()))
+                .collect();
+            Ok(pairs)
+        })
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`.
+    ///
+    /// The copy is a snapshot that `MemoryKvsEngine::open` loads. Writes wait while it is
+    /// written from the map, but not while it is synced.
+    fn checkpoint(&self, dest: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            create_checkpoint_dir(&dest)?;
+            let (file, catalog) = {
+                let data = shared.data.read().unwrap();
+                let catalog = shared.catalog.read().unwrap().clone();
+                (write_snapshot(&dest, &data)?, catalog)
+            };
+            install_snapshot(&dest, file)?;
+            write_keyspaces(&dest, catalog.next_id, &catalog.names)
+        })
+    }
+
+    /// Returns the size of the keyspace, and of the snapshot if the engine has one.
+    ///
+    /// The keys and live bytes are counted by scanning the keyspace.
+    fn stats(&self) -> Box<dyn Future<Item = EngineStats, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        let keyspace = self.keyspace;
+        run_in_pool(&self.pool, move || {
+            let (keys, live_bytes) = {
+                let data = shared.data.read().unwrap();
+                shared.check_keyspace(keyspace)?;
+                let now = now_millis();
+                let range = keyspace_range(keyspace, (Bound::Unbounded, Bound::Unbounded));
+                entries(&data, &range)
+                    .filter(|(_, entry)| !entry.expired(now))
+                    .fold((0, 0), |(keys, bytes), (key, entry)| {
+                        let len = key.len() - KEYSPACE_ID_LEN + entry.value.len();
+                        (keys + 1, bytes + len as u64)
+                    })
+            };
+            let disk_size = match &shared.path {
+                Some(path) => match fs::metadata(path.join(SNAPSHOT_FILE)) {
+                    Ok(metadata) => Some(metadata.len()),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Some(0),
+                    Err(e) => return Err(e.into()),
+                },
+                None => None,
+            };
+            Ok(EngineStats {
+                keys,
+                live_bytes: Some(live_bytes),
+                disk_size,
+                ..EngineStats::default()
+            })
+        })
+    }
+
+    /// Returns a stream of the changes of the keys starting with `prefix`.
+    ///
+    /// The events are sent while holding the lock of the map, so they are in the order of
+    /// the writes. Keys that expire are not reported until they are removed. A watch that
+    /// falls behind the writes is ended with `KvsError::WatchLagged`.
+    fn watch(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send> {
+        let _data = self.shared.data.read().unwrap();
+        if let Err(e) = self.shared.check_keyspace(self.keyspace) {
+            return Box::new(stream::once(Err(e)));
+        }
+        self.shared
+            .watchers
+            .lock()
+            .unwrap()
+            .add(self.keyspace, prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let keyspace = self
+            .shared
+            .catalog
+            .read()
+            .unwrap()
+            .id(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        Ok(MemoryKvsEngine {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            check_keyspace_name(&name)?;
+            let mut catalog = shared.catalog.write().unwrap();
+            if catalog.id(&name).is_some() {
+                return Err(KvsError::KeyspaceExists(name));
+            }
+            let keyspace = catalog.next_id;
+            let mut names = catalog.names.clone();
+            names.insert(keyspace, name);
+            if let Some(path) = &shared.path {
+                write_keyspaces(path, keyspace + 1, &names)?;
+            }
+            *catalog = Catalog {
+                next_id: keyspace + 1,
+                names,
+            };
+            Ok(())
+        })
+    }
+
+    /// Drops a keyspace with all its keys.
+    ///
+    /// The keys are removed from the map at once. The next snapshot leaves them out, and
+    /// a snapshot written before is loaded without them.
+    fn drop_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            if name == DEFAULT_KEYSPACE {
+                return Err(KvsError::StringError(
+                    "The default keyspace cannot be dropped".to_owned(),
+                ));
+            }
+            let mut data = shared.data.write().unwrap();
+            let mut catalog = shared.catalog.write().unwrap();
+            let keyspace = catalog
+                .id(&name)
+                .ok_or_else(|| KvsError::KeyspaceNotFound(name))?;
+            let mut names = catalog.names.clone();
+            names.remove(&keyspace);
+            if let Some(path) = &shared.path {
+                write_keyspaces(path, catalog.next_id, &names)?;
+            }
+            catalog.names = names;
+            shared.watchers.lock().unwrap().remove_keyspace(keyspace);
+
+            let range = keyspace_range(keyspace, (Bound::Unbounded, Bound::Unbounded));
+            let keys: Vec<Vec<u8>> = entries(&data, &range).map(|(key, _)| key.clone()).collect();
+            for key in keys {
+                data.remove(&key);
+            }
+            shared.writes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+    }
+
+    fn list_keyspaces(&self) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let shared = Arc::clone(&self.shared);
+        run_in_pool(&self.pool, move || {
+            let catalog = shared.catalog.read().unwrap();
+            let mut names: Vec<String> = catalog.names.values().cloned().collect();
+            names.push(DEFAULT_KEYSPACE.to_owned());
+            names.sort();
+            Ok(names)
+        })
+    }
+}
+
+/// Options of a `MemoryKvsEngine` opened with a path, set in the builder style.
+///
+/// ```rust
+/// # use kvs::MemoryOptions;
+/// # use std::time::Duration;
+/// let options = MemoryOptions::new().snapshot_interval(Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOptions {
+    snapshot_interval: Option<Duration>,
+}
+
+impl MemoryOptions {
+    /// Creates the default options.
+    ///
+    /// A snapshot is written only when the last handle of the engine is dropped.
+    pub fn new() -> MemoryOptions {
+        MemoryOptions::default()
+    }
+
+    /// Sets the interval at which a snapshot is written, besides the one written when the
+    /// last handle is dropped. It is skipped if nothing has been written since the last
+    /// one.
+    pub fn snapshot_interval(mut self, interval: Duration) -> MemoryOptions {
+        self.snapshot_interval = Some(interval);
+        self
+    }
+}
+
+/// The state shared by the handles of an engine.
+struct Shared {
+    // directory of the snapshot and the list of keyspaces, if the engine has one
+    path: Option<PathBuf>,
+    // entries by internal key
+    data: RwLock<BTreeMap<Vec<u8>, Entry>>,
+    catalog: RwLock<Catalog>,
+    watchers: Mutex<Watchers>,
+    // number of writes that have changed the map
+    writes: AtomicU64,
+    // number of writes in the last snapshot, held while a snapshot is written
+    snapshot_writes: Mutex<u64>,
+}
+
+impl Shared {
+    fn new(path: Option<PathBuf>, catalog: Catalog, data: BTreeMap<Vec<u8>, Entry>) -> Shared {
+        Shared {
+            path,
+            data: RwLock::new(data),
+            catalog: RwLock::new(catalog),
+            watchers: Mutex::new(Watchers::default()),
+            writes: AtomicU64::new(0),
+            snapshot_writes: Mutex::new(0),
+        }
+    }
+
+    /// Returns `KvsError::KeyspaceDropped` if the keyspace has been dropped.
+    fn check_keyspace(&self, keyspace: u32) -> Result<()> {
+        if self.catalog.read().unwrap().contains(keyspace) {
+            Ok(())
+        } else {
+            Err(KvsError::KeyspaceDropped)
+        }
+    }
+
+    /// Returns the value of a key of the keyspace, unless it has expired.
+    fn value(&self, keyspace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let data = self.data.read().unwrap();
+        self.check_keyspace(keyspace)?;
+        Ok(live_value(&data, &internal_key(keyspace, key)))
+    }
+
+    /// Runs `f` on the map with the lock held, after checking that the keyspace still
+    /// exists, and sends the events of its writes. `f` may only fail before it has
+    /// written anything.
+    fn write<F, T>(&self, keyspace: u32, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Writer) -> Result<T>,
+    {
+        let mut data = self.data.write().unwrap();
+        self.check_keyspace(keyspace)?;
+        let mut writer = Writer {
+            data: &mut *data,
+            keyspace,
+            watchers: &self.watchers,
+            changed: false,
+        };
+        let res = f(&mut writer)?;
+        if writer.changed {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(res)
+    }
+
+    /// Writes a snapshot to the directory of the engine, unless nothing has been written
+    /// since the last one or the engine has no directory.
+    fn snapshot(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut snapshot_writes = self.snapshot_writes.lock().unwrap();
+        let (file, writes) = {
+            let data = self.data.read().unwrap();
+            let writes = self.writes.load(Ordering::SeqCst);
+            if writes == *snapshot_writes {
+                return Ok(());
+            }
+            (write_snapshot(path, &data)?, writes)
+        };
+        install_snapshot(path, file)?;
+        *snapshot_writes = writes;
+        Ok(())
+    }
+
+    /// Removes the expired keys from the map, looking at up to `EXPIRY_SWEEP_CHUNK` keys
+    /// with each lock of it. The removals are sent to the watches of the keys.
+    fn remove_expired(&self) -> Result<()> {
+        let mut start = Bound::Unbounded;
+        loop {
+            let now = now_millis();
+            let (expired, last) = {
+                let data = self.data.read().unwrap();
+                let chunk: Vec<(&Vec<u8>, &Entry)> = data
+                    .range((start, Bound::Unbounded))
+                    .take(EXPIRY_SWEEP_CHUNK)
+                    .collect();
+                let expired: Vec<Vec<u8>> = chunk
+                    .iter()
+                    .filter(|(_, entry)| entry.expired(now))
+                    .map(|(key, _)| (*key).clone())
+                    .collect();
+                (expired, chunk.last().map(|(key, _)| (*key).clone()))
+            };
+            if !expired.is_empty() {
+                let mut data = self.data.write().unwrap();
+                let mut watchers = self.watchers.lock().unwrap();
+                for key in expired {
+                    // the key may have been set again since
+                    if !data.get(&key).map_or(false, |entry| entry.expired(now)) {
+                        continue;
+                    }
+                    data.remove(&key);
+                    let keyspace = keyspace_of(&key);
+                    let key = &key[KEYSPACE_ID_LEN..];
+                    if watchers.watches(keyspace, key) {
+                        watchers.send(keyspace, &WatchEvent::Remove { key: key.to_vec() });
+                    }
+                }
+                self.writes.fetch_add(1, Ordering::SeqCst);
+            }
+            start = match last {
+                Some(key) => Bound::Excluded(key),
+                None => return Ok(()),
+            };
+        }
+    }
+}
+
+/// Writes the last snapshot when the last handle of the engine is dropped, and holds the
+/// lock of the data directory until it is written.
+struct CloseGuard {
+    shared: Arc<Shared>,
+    _lock: Option<File>,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.shared.snapshot() {
+            error!("Failed to write the snapshot: {}", e);
+        }
+    }
+}
+
+/// Writes to the map of a keyspace, with the lock of the map held.
+struct Writer<'a> {
+    data: &'a mut BTreeMap<Vec<u8>, Entry>,
+    keyspace: u32,
+    watchers: &'a Mutex<Watchers>,
+    // whether the map has been changed
+    changed: bool,
+}
+
+impl<'a> Writer<'a> {
+    /// Returns the value of a key of the keyspace, unless it has expired.
+    fn value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        live_value(self.data, &internal_key(self.keyspace, key))
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.watches(self.keyspace, &key) {
+            let event = WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            watchers.send(self.keyspace, &event);
+        }
+        let entry = Entry { value, expires_at };
+        self.data.insert(internal_key(self.keyspace, &key), entry);
+        self.changed = true;
+    }
+
+    /// Removes a key of the keyspace and returns its entry, or `None` if it does not
+    /// exist.
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.data.remove(&internal_key(self.keyspace, key))?;
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.watches(self.keyspace, key) {
+            watchers.send(self.keyspace, &WatchEvent::Remove { key: key.to_vec() });
+        }
+        self.changed = true;
+        Some(entry)
+    }
+
+    fn apply(&mut self, batch: WriteBatch) {
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => self.set(key, value, None),
+                BatchOp::Remove { key } => {
+                    self.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// A value with its expiry time.
+#[derive(Clone)]
+struct Entry {
+    value: Vec<u8>,
+    // expiry time in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+fn live_value(data: &BTreeMap<Vec<u8>, Entry>, key: &[u8]) -> Option<Vec<u8>> {
+    data.get(key)
+        .filter(|entry| !entry.expired(now_millis()))
+        .map(|entry| entry.value.clone())
+}
+
+/// Returns the entries of a range of internal keys in key order.
+fn entries<'a>(
+    data: &'a BTreeMap<Vec<u8>, Entry>,
+    range: &'a KeyRange,
+) -> impl Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a {
+    // `BTreeMap::range` panics on a range ending before it starts, so only the start is
+    // given to it
+    data.range((range.0.clone(), Bound::Unbounded))
+        .take_while(move |(key, _)| before_end(key, &range.1))
+}
+
+/// Writes the unexpired entries of the map to the temporary file of the snapshot of the
+/// directory, and returns the file for `install_snapshot`.
+fn write_snapshot(path: &Path, data: &BTreeMap<Vec<u8>, Entry>) -> Result<File> {
+    let tmp_path = path.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(&file);
+    write_frame(&mut writer, &SNAPSHOT_MAGIC.to_le_bytes())?;
+    let now = now_millis();
+    let mut block = Vec::new();
+    for (key, entry) in data.iter().filter(|(_, entry)| !entry.expired(now)) {
+        encode_entry(&mut block, key, entry);
+        if block.len() >= SNAPSHOT_BLOCK_SIZE {
+            write_frame(&mut writer, &block)?;
+            block.clear();
+        }
+    }
+    if !block.is_empty() {
+        write_frame(&mut writer, &block)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    Ok(file)
+}
+
+/// Syncs the temporary file of a snapshot and renames it over the snapshot of the
+/// directory.
+fn install_snapshot(path: &Path, file: File) -> Result<()> {
+    file.sync_all()?;
+    let tmp_path = path.join(format!("{}.tmp", SNAPSHOT_FILE));
+    fs::rename(&tmp_path, path.join(SNAPSHOT_FILE))?;
+    Ok(())
+}
+
+/// Reads the entries of a snapshot file, or none if it does not exist.
+fn read_snapshot(path: &Path) -> Result<BTreeMap<Vec<u8>, Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut offset = 0;
+    let mut data = BTreeMap::new();
+    loop {
+        let payload = match read_frame(&mut reader, len - offset)? {
+            Frame::Record(payload) => payload,
+            Frame::Eof if offset > 0 => return Ok(data),
+            // a snapshot is renamed into place once complete, so anything else is damage
+            _ => return Err(KvsError::CorruptedSnapshot),
+        };
+        if offset == 0 {
+            if payload.len() != 8 || le_u64(&payload) != SNAPSHOT_MAGIC {
+                return Err(KvsError::CorruptedSnapshot);
+            }
+        } else {
+            decode_entries(&payload, &mut data).ok_or(KvsError::CorruptedSnapshot)?;
+        }
+        offset += (RECORD_HEADER_LEN + payload.len()) as u64;
+    }
+}
+
+/// Appends an internal key with its entry to `buf`.
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], entry: &Entry) {
+    put_bytes(buf, key);
+    match entry.expires_at {
+        Some(expires_at) => {
+            buf.push(ENTRY_EXPIRES);
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+    put_bytes(buf, &entry.value);
+}
+
+/// Decodes the entries encoded one after another in `block` into `data`, or returns
+/// `None` if it is malformed.
+fn decode_entries(mut block: &[u8], data: &mut BTreeMap<Vec<u8>, Entry>) -> Option<()> {
+    while !block.is_empty() {
+        let key = take_bytes(&mut block)?.to_vec();
+        let expires_at = match take(&mut block, 1)?[0] {
+            ENTRY_EXPIRES => Some(le_u64(take(&mut block, 8)?)),
+            0 => None,
+            _ => return None,
+        };
+        let value = take_bytes(&mut block)?.to_vec();
+        data.insert(key, Entry { value, expires_at });
+    }
+    Some(())
+}
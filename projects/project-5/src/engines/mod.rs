@@ -1,28 +1,547 @@
-pub use self::kvs::KvStore;
+pub use self::btree::{BTreeKvsEngine, BTreeOptions};
+use self::kvs::le_u32;
+pub use self::kvs::{
+    CacheStats, Change, CompactionTrigger, GenerationStats, KvStore, KvStoreOptions,
+    KvStoreSnapshot,
+};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::{MemoryKvsEngine, MemoryOptions};
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result, Transaction, WriteBatch};
 
-use tokio::prelude::Future;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::prelude::*;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
 
+mod btree;
+mod keyspace;
 mod kvs;
+mod lsm;
+mod memory;
 mod sled;
 
+// items a watch or a change feed can fall behind the writes by before it is ended
+const WATCH_BUFFER: usize = 1024;
+
+/// The name of the keyspace that every engine has, which the handle an engine is opened
+/// with works on.
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+/// Key/value pairs in key order, as returned by a scan.
+pub type KvPairs = Vec<(Vec<u8>, Vec<u8>)>;
+
 /// Trait for a key value storage engine.
+///
+/// Keys and values are arbitrary bytes. The methods taking and returning `String`s
+/// are thin wrappers over their byte-oriented counterparts.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// An expired key behaves as if it has been removed.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key to `new` if its current value equals `expected`, atomically.
+    ///
+    /// `None` stands for an absent key, so the key is removed if `new` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` with the current value if it does not equal
+    /// `expected`.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key only if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` with the current value if the key exists.
+    fn set_if_absent_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Applies all the writes in `batch` atomically.
+    ///
+    /// If the process crashes, either all of them or none of them are persisted.
+    fn write_batch(&self, batch: WriteBatch)
+        -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Runs `f` in an optimistic transaction and returns its result.
+    ///
+    /// The writes in the transaction are committed atomically if none of the keys it has
+    /// read are changed before it commits. Otherwise `f` is run again.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error returned by `f`, and nothing is written.
+    ///
+    /// It returns `KvsError::TransactionConflict` if the transaction keeps conflicting.
+    fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static;
+
+    /// Returns the key/value pairs whose keys are in the given range, in key order.
+    ///
+    /// At most `limit` pairs are returned if it is not `None`.
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send>;
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// At most `limit` pairs are returned if it is not `None`.
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        self.scan(prefix_range(prefix), limit)
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`, which is created
+    /// if it does not exist.
+    ///
+    /// The copy can be opened as a store of the same engine. The store keeps accepting
+    /// writes while the copy is made.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if `dest` is not empty.
+    fn checkpoint(&self, dest: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Returns the size of the store and the work done to keep it compact.
+    fn stats(&self) -> Box<dyn Future<Item = EngineStats, Error = KvsError> + Send>;
+
+    /// Returns a stream of the changes of the keys starting with `prefix`, in the order
+    /// they are made.
+    ///
+    /// Dropping the stream stops watching.
+    fn watch(&self, prefix: Vec<u8>)
+        -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send>;
+
+    /// Returns a handle of the engine that works on the keyspace `name`.
+    ///
+    /// Keyspaces share the storage of the engine, but each has keys of its own. The
+    /// methods reading and writing keys, `stats` and `watch` work on the keyspace of the
+    /// handle, while `checkpoint` copies all the keyspaces.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if there is no keyspace with that name.
+    fn keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Creates an empty keyspace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceExists` if there is already a keyspace with that name.
+    fn create_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Drops a keyspace with all its keys.
+    ///
+    /// Handles of the keyspace must not be used afterwards.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if there is no keyspace with that name, and
+    /// `KvsError::StringError` for the default keyspace, which cannot be dropped.
+    fn drop_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Returns the names of the keyspaces in name order, including `DEFAULT_KEYSPACE`.
+    fn list_keyspaces(&self) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send>;
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| -> Result<Option<String>> {
+                    Ok(value.map(String::from_utf8).transpose()?)
+                }),
+        )
+    }
 
-    /// Removes a given key.
+    /// Removes a given string key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Sets the value of a string key to `new` if its current value equals `expected`,
+    /// atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` with the current value if it does not equal
+    /// `expected`.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets the value of a string key only if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` with the current value if the key exists.
+    fn set_if_absent(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+}
+
+/// When the writes to an engine are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leaves syncing to the operating system. Acknowledged writes may be lost if the
+    /// machine crashes.
+    None,
+    /// Syncs every write before acknowledging it.
+    EveryWrite,
+    /// Syncs at the given interval. Writes acknowledged since the last sync may be lost
+    /// if the machine crashes.
+    Periodic(Duration),
+    /// Syncs every write before acknowledging it, and concurrent writes share a sync.
+    GroupCommit,
+}
+
+/// Statistics of a storage engine.
+///
+/// The fields an engine cannot tell are `None`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of keys, including expired keys not removed yet.
+    pub keys: u64,
+    /// The bytes taken by the live keys and values.
+    pub live_bytes: Option<u64>,
+    /// The bytes taken by overwritten or removed data that compactions have not
+    /// reclaimed yet.
+    pub uncompacted_bytes: Option<u64>,
+    /// The number of log generations.
+    pub generations: Option<u64>,
+    /// The number of compactions finished since the store was opened.
+    pub compactions: Option<u64>,
+    /// How long the last finished compaction took.
+    pub last_compaction: Option<Duration>,
+    /// The total size of the files of the store.
+    pub disk_size: Option<u64>,
+}
+
+/// A change of a key reported by `KvsEngine::watch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The key is set to a value.
+    Set {
+        /// The key
+        key: Vec<u8>,
+        /// The new value
+        value: Vec<u8>,
+    },
+    /// The key is removed, or it has expired.
+    Remove {
+        /// The key
+        key: Vec<u8>,
+    },
+}
+
+impl WatchEvent {
+    /// Returns the key that has changed.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// Lets concurrent writers share a single sync.
+///
+/// Every write takes a ticket once it is written. A writer waiting for its ticket to be
+/// synced either finds that another writer's sync has covered it, or syncs all the
+/// writes so far itself while the writers behind it wait.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    // ticket of the last write
+    written: AtomicU64,
+    // ticket of the last synced write
+    synced: Mutex<u64>,
+}
+
+impl GroupCommit {
+    /// Records a write and returns its ticket.
+    pub(crate) fn record_write(&self) -> u64 {
+        self.written.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Returns the ticket of the last write.
+    pub(crate) fn last_write(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    /// Returns once the writes up to `ticket` are synced, calling `sync` if they are not.
+    pub(crate) fn commit<F>(&self, ticket: u64, sync: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= ticket {
+            return Ok(());
+        }
+        let written = self.last_write();
+        sync()?;
+        *synced = written;
+        Ok(())
+    }
+}
+
+/// The sending end of a watch or a change feed.
+///
+/// Its channel holds at most `WATCH_BUFFER` items, so a reader that falls behind cannot
+/// make the engine buffer writes without bound. Once the channel is full, the watch is
+/// ended, and its stream fails with `KvsError::WatchLagged` after the items already in it.
+pub(crate) struct WatchSender<T> {
+    sender: Sender<T>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl<T> WatchSender<T> {
+    /// Sends an item without blocking.
+    ///
+    /// Returns `false` if the watch has ended, because its stream has been dropped or it
+    /// has fallen behind. The sender should be dropped then.
+    pub(crate) fn send(&mut self, item: T) -> bool {
+        match self.sender.try_send(item) {
+            Ok(()) => true,
+            Err(e) => {
+                if e.is_full() {
+                    self.lagged.store(true, Ordering::SeqCst);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Returns the sender and the stream of a new watch or change feed.
+pub(crate) fn watch_channel<T: Send + 'static>() -> (
+    WatchSender<T>,
+    Box<dyn Stream<Item = T, Error = KvsError> + Send>,
+) {
+    let (sender, receiver) = channel(WATCH_BUFFER);
+    let lagged = Arc::new(AtomicBool::new(false));
+    let has_lagged = Arc::clone(&lagged);
+    // the stream ends once the sender is dropped, and the reason is told then
+    let end = stream::poll_fn(move || {
+        if has_lagged.load(Ordering::SeqCst) {
+            Err(KvsError::WatchLagged)
+        } else {
+            Ok(Async::Ready(None))
+        }
+    });
+    let items = receiver
+        .map_err(|e| KvsError::StringError(format!("{}", e)))
+        .chain(end);
+    (WatchSender { sender, lagged }, Box::new(items))
+}
+
+/// Calls `task` on `target` at the given interval until it is dropped. `name` tells
+/// which task failed in the log.
+pub(crate) fn spawn_periodic<T, F>(target: Weak<T>, interval: Duration, name: &'static str, task: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn(&T) -> Result<()> + Send + 'static,
+{
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let target = match target.upgrade() {
+            Some(target) => target,
+            None => return,
+        };
+        if let Err(e) = task(&target) {
+            error!("Periodic {} failed: {}", name, e);
+        }
+    });
+}
+
+/// Appends `bytes` prefixed with their length.
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Splits the first `len` bytes off `data`.
+pub(crate) fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Some(head)
+}
+
+/// Splits bytes prefixed with their length off `data`.
+pub(crate) fn take_bytes<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = le_u32(take(data, 4)?) as usize;
+    take(data, len)
+}
+
+/// Creates the directory of a checkpoint, which must be empty if it exists.
+pub(crate) fn create_checkpoint_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "Checkpoint directory {:?} is not empty",
+            dest
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that a keyspace name is not empty and has no control characters.
+pub(crate) fn check_keyspace_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(char::is_control) {
+        return Err(KvsError::StringError(format!(
+            "Invalid keyspace name {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the range of keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // The smallest key greater than all keys with the prefix is the prefix with its last
+    // byte that is not 0xff incremented and the bytes after it dropped.
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+/// Returns the expiry time of a key set now with the given time-to-live.
+pub(crate) fn expiry_time(ttl: Duration) -> u64 {
+    now_millis() + ttl.as_secs() * 1000 + u64::from(ttl.subsec_millis())
+}
+
+/// Copies the bounds of a key range so that it can be moved to another thread.
+pub(crate) fn owned_range<R: RangeBounds<Vec<u8>>>(range: &R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let owned = |bound: Bound<&Vec<u8>>| match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (owned(range.start_bound()), owned(range.end_bound()))
+}
+
+/// Runs `job` in the thread pool and returns a future of its result.
+///
+/// The engines run all their blocking work this way. The result is sent back through a
+/// oneshot channel, so if `job` panics, its sender is dropped and the future fails with
+/// a `KvsError::StringError` about the canceled channel.
+fn run_in_pool<P, F, T>(pool: &P, job: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+where
+    P: ThreadPool,
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(move || {
+        if tx.send(job()).is_err() {
+            error!("Receiving end is dropped");
+        }
+    });
+    Box::new(
+        rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+            .flatten(),
+    )
 }
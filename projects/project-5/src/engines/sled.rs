@@ -1,84 +1,783 @@
+use super::keyspace::Watchers;
+use super::kvs::DEFAULT_KEYSPACE_ID;
+use super::{
+    check_keyspace_name, create_checkpoint_dir, expiry_time, now_millis, owned_range, run_in_pool,
+    spawn_periodic, Durability, EngineStats, GroupCommit, KvPairs, WatchEvent, DEFAULT_KEYSPACE,
+};
+use crate::batch::BatchOp;
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use crate::transaction::run_transaction;
+use crate::{KvsEngine, KvsError, Result, Transaction, WriteBatch};
+use sled::{Db, Tree};
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
 use tokio::prelude::*;
-use tokio::sync::oneshot;
+
+// name of the tree mapping keys of the default keyspace with a time-to-live to their
+// expiry time
+const EXPIRY_TREE: &[u8] = b"kvs_expiry";
+
+// name of the tree listing the keyspaces other than the default one
+const KEYSPACES_TREE: &[u8] = b"kvs_keyspaces";
+// prefixes of the names of the trees of the pairs and the expiry times of such a keyspace
+const KEYSPACE_TREE_PREFIX: &str = "kvs_keyspace:";
+const KEYSPACE_EXPIRY_PREFIX: &str = "kvs_expiry:";
+
+// name of the tree holding the batch being applied
+const BATCH_TREE: &[u8] = b"kvs_batch";
+// key of a batch of the default keyspace, and prefix of the keys of the batches of others
+const PENDING_BATCH_KEY: &[u8] = b"pending";
+
+// how often expired keys are removed from the database
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Wrapper of `sled::Db`
+///
+/// The expiry times of keys set with a time-to-live are kept in a separate tree.
+///
+/// The default keyspace is the default tree of the database, and every other keyspace is
+/// a pair of trees named after it, listed in another tree.
+///
+/// Every write holds the write lock of the engine, so writes are serialized with each
+/// other. Reads do not take it.
+///
+/// This version of sled has no atomic batches, so a `WriteBatch` is saved in another
+/// tree before it is applied and removed afterwards. A batch left there by a crash is
+/// applied again when the engine is created, before any later write. A batch is atomic
+/// for writes and across crashes, but not for reads: a read made while a batch is being
+/// applied may see some of its writes and not the others.
+///
+/// It has no transactional trees either. A transaction is validated and committed while
+/// holding the write lock, so no other write can come between them.
+///
+/// A checkpoint copies the keys of all the keyspaces to a new database while holding the
+/// write lock too, so it is a copy of the database at a single point in time. Creating
+/// and dropping keyspaces holds it as well.
+///
+/// The handles of an engine share the keyspaces they have opened, so a dropped keyspace
+/// is seen as such by all of them. They share the watches of the keyspaces as well, which
+/// are sent the events of each write while it holds the write lock.
+///
+/// Writes are flushed as the durability mode requires. Batches are always flushed, since
+/// the saved batch has to be on disk before it is applied.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
-    db: Db,
+    // the keyspace the handle works on
+    keyspace: Arc<Keyspace>,
+    catalog: Arc<Tree>,
+    open_keyspaces: Arc<OpenKeyspaces>,
+    batches: Arc<Tree>,
+    // held by every write, so that only one batch can be pending at a time and no write
+    // can come between the writes of a batch
+    write_lock: Arc<Mutex<()>>,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
 }
 
+// the keyspaces opened by the handles of an engine, by name
+type OpenKeyspaces = Mutex<HashMap<String, Arc<Keyspace>>>;
+
 impl<P: ThreadPool> SledKvsEngine<P> {
     /// Creates a `SledKvsEngine` from `sled::Db`.
     ///
     /// Operations are run in the given thread pool. `concurrency` specifies the number of
     /// threads in the thread pool.
+    ///
+    /// Every write is flushed before it is acknowledged.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
+        SledKvsEngine::with_durability(db, concurrency, Durability::EveryWrite)
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db` which flushes writes as `durability`
+    /// requires.
+    pub fn with_durability(db: Db, concurrency: u32, durability: Durability) -> Result<Self> {
         let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        let db = Arc::new(db);
+        let catalog = db.open_tree(KEYSPACES_TREE.to_vec())?;
+        let batches = db.open_tree(BATCH_TREE.to_vec())?;
+        let keyspace = Arc::new(Keyspace::open(Arc::clone(&db), DEFAULT_KEYSPACE)?);
+        let mut open_keyspaces = HashMap::new();
+        open_keyspaces.insert(DEFAULT_KEYSPACE.to_owned(), Arc::clone(&keyspace));
+        for res in batches.iter() {
+            let (key, pending) = res?;
+            warn!("Applying the batch interrupted by a crash");
+            let name = match AsRef::<[u8]>::as_ref(&key).get(PENDING_BATCH_KEY.len() + 1..) {
+                Some(name) => String::from_utf8(name.to_vec())?,
+                None => DEFAULT_KEYSPACE.to_owned(),
+            };
+            let batch: WriteBatch = serde_json::from_slice(AsRef::<[u8]>::as_ref(&pending))?;
+            let space = open_keyspace(&mut open_keyspaces, &db, &catalog, &name)?;
+            apply_batch(&space, &batches, batch)?;
+        }
+        let open_keyspaces = Arc::new(Mutex::new(open_keyspaces));
+        let write_lock = Arc::new(Mutex::new(()));
+        spawn_expiry_sweeper(
+            Arc::downgrade(&open_keyspaces),
+            catalog.clone(),
+            Arc::clone(&write_lock),
+            pool.clone(),
+        );
+        if let Durability::Periodic(interval) = durability {
+            spawn_periodic(Arc::downgrade(&db), interval, "sync", |db| {
+                db.flush()?;
+                Ok(())
+            });
+        }
+        Ok(SledKvsEngine {
+            pool,
+            keyspace,
+            catalog,
+            open_keyspaces,
+            batches,
+            write_lock,
+            durability,
+            group_commit: Arc::new(GroupCommit::default()),
+        })
     }
 }
 
-impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+/// The trees of a keyspace.
+struct Keyspace {
+    db: Arc<Db>,
+    // the pairs of a keyspace other than the default one, whose pairs are in the default
+    // tree of the database
+    tree: Option<Arc<Tree>>,
+    expiry: Arc<Tree>,
+    // the key its pending batch is saved under
+    pending_key: Vec<u8>,
+    dropped: AtomicBool,
+    // the watches of the keyspace, all under the id of the default keyspace since each
+    // keyspace has watchers of its own
+    watchers: Mutex<Watchers>,
+}
+
+impl Keyspace {
+    /// Opens the trees of a keyspace, creating them if they do not exist.
+    fn open(db: Arc<Db>, name: &str) -> Result<Keyspace> {
+        let (tree, expiry, pending_key) = if name == DEFAULT_KEYSPACE {
+            let expiry = db.open_tree(EXPIRY_TREE.to_vec())?;
+            (None, expiry, PENDING_BATCH_KEY.to_vec())
+        } else {
+            let tree = db.open_tree(keyspace_tree_name(KEYSPACE_TREE_PREFIX, name))?;
+            let expiry = db.open_tree(keyspace_tree_name(KEYSPACE_EXPIRY_PREFIX, name))?;
+            let mut pending_key = PENDING_BATCH_KEY.to_vec();
+            pending_key.push(b':');
+            pending_key.extend_from_slice(name.as_bytes());
+            (Some(tree), expiry, pending_key)
+        };
+        Ok(Keyspace {
+            db,
+            tree,
+            expiry,
+            pending_key,
+            dropped: AtomicBool::new(false),
+            watchers: Mutex::new(Watchers::default()),
+        })
+    }
+
+    /// Sets a key to a value and tells the watches of the key.
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let event = if self.watches(&key) {
+            Some(WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+        } else {
+            None
+        };
+        self.pairs().set(key, value)?;
+        if let Some(event) = event {
+            self.send(&event);
+        }
+        Ok(())
+    }
+
+    /// Removes a key and tells the watches of the key if it existed.
+    ///
+    /// Returns the value it had.
+    fn del(&self, key: &[u8]) -> Result<Option<sled::IVec>> {
+        let old = self.pairs().del(key)?;
+        if old.is_some() && self.watches(key) {
+            self.send(&WatchEvent::Remove { key: key.to_vec() });
+        }
+        Ok(old)
+    }
+
+    /// Returns whether any watch is interested in the key.
+    fn watches(&self, key: &[u8]) -> bool {
+        let watchers = self.watchers.lock().unwrap();
+        watchers.watches(DEFAULT_KEYSPACE_ID, key)
+    }
+
+    /// Sends an event to the watches of its key. Watches whose streams have been dropped
+    /// or which have fallen behind are removed.
+    fn send(&self, event: &WatchEvent) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.send(DEFAULT_KEYSPACE_ID, event);
+    }
+
+    /// Returns the tree of the pairs of the keyspace.
+    fn pairs(&self) -> &Tree {
+        match self.tree {
+            Some(ref tree) => &**tree,
+            None => &**self.db,
+        }
+    }
+
+    /// Returns `KvsError::KeyspaceDropped` if the keyspace has been dropped.
+    fn check(&self) -> Result<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            return Err(KvsError::KeyspaceDropped);
+        }
+        Ok(())
+    }
+}
+
+fn keyspace_tree_name(prefix: &str, name: &str) -> Vec<u8> {
+    format!("{}{}", prefix, name).into_bytes()
+}
+
+/// Returns the keyspace `name`, opening it if no handle has done so yet.
+fn open_keyspace(
+    open_keyspaces: &mut HashMap<String, Arc<Keyspace>>,
+    db: &Arc<Db>,
+    catalog: &Tree,
+    name: &str,
+) -> Result<Arc<Keyspace>> {
+    if let Some(space) = open_keyspaces.get(name) {
+        return Ok(Arc::clone(space));
+    }
+    if name != DEFAULT_KEYSPACE && catalog.get(name.as_bytes())?.is_none() {
+        return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+    }
+    let space = Arc::new(Keyspace::open(Arc::clone(db), name)?);
+    open_keyspaces.insert(name.to_owned(), Arc::clone(&space));
+    Ok(space)
+}
+
+/// Drops the trees of a keyspace other than the default one, if they exist.
+fn drop_keyspace_trees(db: &Db, name: &str) -> Result<()> {
+    db.drop_tree(&keyspace_tree_name(KEYSPACE_TREE_PREFIX, name))?;
+    db.drop_tree(&keyspace_tree_name(KEYSPACE_EXPIRY_PREFIX, name))?;
+    Ok(())
+}
+
+/// Returns the names of the keyspaces listed in the catalog.
+fn catalog_names(catalog: &Tree) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for res in catalog.iter() {
+        let (name, _) = res?;
+        names.push(String::from_utf8(AsRef::<[u8]>::as_ref(&name).to_vec())?);
+    }
+    Ok(names)
+}
+
+/// Makes a write durable as `durability` requires once it is done.
+fn sync_write(db: &Db, durability: Durability, group_commit: &GroupCommit) -> Result<()> {
+    let ticket = group_commit.record_write();
+    match durability {
+        Durability::EveryWrite => {
+            db.flush()?;
+        }
+        Durability::GroupCommit => group_commit.commit(ticket, || {
+            db.flush()?;
+            Ok(())
+        })?,
+        Durability::None | Durability::Periodic(_) => {}
+    }
+    Ok(())
+}
+
+/// Copies all the pairs in `src` to `dst`.
+fn copy_tree(src: &Tree, dst: &Tree) -> Result<()> {
+    for res in src.iter() {
+        let (key, value) = res?;
+        let key = AsRef::<[u8]>::as_ref(&key).to_vec();
+        dst.set(key, AsRef::<[u8]>::as_ref(&value).to_vec())?;
+    }
+    Ok(())
+}
+
+/// Reads the value of a key unless it has expired.
+fn current_value(space: &Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    if is_expired(&space.expiry, key, now_millis())? {
+        return Ok(None);
+    }
+    Ok(space
+        .pairs()
+        .get(key)?
+        .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()))
+}
+
+/// Saves a batch as the pending one of its keyspace and applies it.
+///
+/// The caller must hold the write lock.
+fn write_pending_batch(space: &Keyspace, batches: &Tree, batch: WriteBatch) -> Result<()> {
+    batches.set(space.pending_key.clone(), serde_json::to_vec(&batch)?)?;
+    space.db.flush()?;
+    apply_batch(space, batches, batch)
+}
+
+/// Applies the pending batch and removes it once its writes are flushed.
+///
+/// Applying a batch again has the same result, so it is safe to retry it after a crash.
+fn apply_batch(space: &Keyspace, batches: &Tree, batch: WriteBatch) -> Result<()> {
+    for op in batch.ops {
+        match op {
+            BatchOp::Set { key, value } => {
+                space.expiry.del(&key)?;
+                space.set(key, value)?;
             }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
-    }
-
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+            BatchOp::Remove { key } => {
+                space.expiry.del(&key)?;
+                space.del(&key)?;
             }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
-    }
-
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
-                db.flush()?;
-                Ok(())
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+        }
+    }
+    space.db.flush()?;
+    batches.del(&space.pending_key)?;
+    space.db.flush()?;
+    Ok(())
+}
+
+/// Returns whether `key` has expired at `now`.
+fn is_expired(expiry: &Tree, key: &[u8], now: u64) -> Result<bool> {
+    Ok(match expiry.get(key)? {
+        Some(expires_at) => decode_expiry(&expires_at) <= now,
+        None => false,
+    })
+}
+
+fn decode_expiry(expires_at: &sled::IVec) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(AsRef::<[u8]>::as_ref(expires_at));
+    u64::from_le_bytes(buf)
+}
+
+/// Periodically removes expired keys of all the keyspaces in the thread pool until the
+/// engine is dropped.
+fn spawn_expiry_sweeper<P: ThreadPool>(
+    open_keyspaces: Weak<OpenKeyspaces>,
+    catalog: Arc<Tree>,
+    write_lock: Arc<Mutex<()>>,
+    pool: P,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_SWEEP_INTERVAL);
+        let open_keyspaces = match open_keyspaces.upgrade() {
+            Some(open_keyspaces) => open_keyspaces,
+            None => return,
+        };
+        let (catalog, write_lock) = (catalog.clone(), write_lock.clone());
+        pool.spawn(move || {
+            if let Err(e) = remove_all_expired(&open_keyspaces, &catalog, &write_lock) {
+                error!("Failed to remove expired keys: {}", e);
             }
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+    });
+}
+
+/// Removes the expired keys of every keyspace in the catalog.
+fn remove_all_expired(
+    open_keyspaces: &OpenKeyspaces,
+    catalog: &Tree,
+    write_lock: &Mutex<()>,
+) -> Result<()> {
+    let spaces = {
+        let mut open_keyspaces = open_keyspaces.lock().unwrap();
+        let db = Arc::clone(&open_keyspaces[DEFAULT_KEYSPACE].db);
+        let mut spaces = vec![Arc::clone(&open_keyspaces[DEFAULT_KEYSPACE])];
+        for name in catalog_names(catalog)? {
+            spaces.push(open_keyspace(&mut open_keyspaces, &db, catalog, &name)?);
+        }
+        spaces
+    };
+    for space in spaces {
+        // a keyspace dropped meanwhile has no keys left to remove
+        if space.check().is_ok() {
+            remove_expired(&space, write_lock)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes expired keys and their expiry times.
+///
+/// The write lock is taken for each key, so writes are not held up by a long sweep. Both
+/// are compared and swapped, so that a key set again before the lock is taken is kept.
+fn remove_expired(space: &Keyspace, write_lock: &Mutex<()>) -> Result<()> {
+    let now = now_millis();
+    for res in space.expiry.iter() {
+        let (key, expires_at) = res?;
+        if decode_expiry(&expires_at) <= now {
+            let _guard = write_lock.lock().unwrap();
+            remove_expired_key(space, &key, &expires_at)?;
+        }
+    }
+    space.db.flush()?;
+    Ok(())
+}
+
+/// Removes an expired key and its expiry time if neither has changed.
+fn remove_expired_key(space: &Keyspace, key: &[u8], expires_at: &sled::IVec) -> Result<()> {
+    let pairs = space.pairs();
+    if let Some(value) = pairs.get(key)? {
+        let old = Some(AsRef::<[u8]>::as_ref(&value));
+        if pairs.cas(key, old, None as Option<&[u8]>)?.is_ok() && space.watches(key) {
+            space.send(&WatchEvent::Remove { key: key.to_vec() });
+        }
+    }
+    let old = Some(AsRef::<[u8]>::as_ref(expires_at));
+    let _ = space.expiry.cas(key, old, None as Option<&[u8]>)?;
+    Ok(())
+}
+
+impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        let write_lock = self.write_lock.clone();
+        let (durability, group_commit) = (self.durability, self.group_commit.clone());
+        run_in_pool(&self.pool, move || {
+            {
+                let _guard = write_lock.lock().unwrap();
+                space.check()?;
+                space.expiry.del(&key)?;
+                space.set(key, value)?;
+            }
+            sync_write(&space.db, durability, &group_commit)
+        })
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        run_in_pool(&self.pool, move || {
+            space.check()?;
+            current_value(&space, &key)
+        })
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        let write_lock = self.write_lock.clone();
+        let (durability, group_commit) = (self.durability, self.group_commit.clone());
+        run_in_pool(&self.pool, move || {
+            let expired = {
+                let _guard = write_lock.lock().unwrap();
+                space.check()?;
+                let expired = is_expired(&space.expiry, &key, now_millis())?;
+                space.expiry.del(&key)?;
+                space.del(&key)?.ok_or(KvsError::KeyNotFound)?;
+                expired
+            };
+            sync_write(&space.db, durability, &group_commit)?;
+            if expired {
+                return Err(KvsError::KeyNotFound);
+            }
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        let write_lock = self.write_lock.clone();
+        let (durability, group_commit) = (self.durability, self.group_commit.clone());
+        run_in_pool(&self.pool, move || {
+            {
+                let _guard = write_lock.lock().unwrap();
+                space.check()?;
+                // an expired key must compare as absent, so it is removed first
+                let mut expires_at = space.expiry.get(&key)?;
+                if let Some(ref t) = expires_at {
+                    if decode_expiry(t) <= now_millis() {
+                        remove_expired_key(&space, &key, t)?;
+                        expires_at = None;
+                    }
+                }
+                let event = match new {
+                    Some(ref value) if space.watches(&key) => Some(WatchEvent::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                    }),
+                    None if expected.is_some() && space.watches(&key) => {
+                        Some(WatchEvent::Remove { key: key.clone() })
+                    }
+                    _ => None,
+                };
+                if let Err(current) = space.pairs().cas(&key, expected.as_ref(), new)? {
+                    return Err(KvsError::ConditionFailed(
+                        current.map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()),
+                    ));
+                }
+                if let Some(event) = event {
+                    space.send(&event);
+                }
+                // the new value does not inherit the time-to-live
+                if expires_at.is_some() {
+                    space.expiry.del(&key)?;
+                }
+            }
+            sync_write(&space.db, durability, &group_commit)
+        })
+    }
+
+    /// Saves the batch in the batch tree, applies it and removes it.
+    ///
+    /// This version of sled has no `sled::Batch`, so the writes are applied one by one
+    /// under the write lock. Other writes cannot come between them and a crash cannot
+    /// leave half of them, but reads do not take the lock and may see some of the writes
+    /// of the batch and not the others while it is applied.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        let batches = self.batches.clone();
+        let write_lock = self.write_lock.clone();
+        run_in_pool(&self.pool, move || {
+            let _guard = write_lock.lock().unwrap();
+            space.check()?;
+            write_pending_batch(&space, &batches, batch)
+        })
+    }
+
+    /// Runs `f` in an optimistic transaction.
+    ///
+    /// This version of sled has no transactional trees, so the transaction is not a sled
+    /// transaction. `f` reads the keyspace without any lock, and the values it has read
+    /// are checked again while holding the write lock. If none has changed, its writes are
+    /// applied as a batch under the same lock. Otherwise `f` is run again.
+    fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let space = self.keyspace.clone();
+        let batches = self.batches.clone();
+        let write_lock = self.write_lock.clone();
+        run_in_pool(&self.pool, move || {
+            space.check()?;
+            let read = |key: &[u8]| current_value(&space, key);
+            run_transaction(f, read, |reads, batch| {
+                let _guard = write_lock.lock().unwrap();
+                space.check()?;
+                for (key, value) in reads {
+                    if current_value(&space, &key)? != value {
+                        return Ok(false);
+                    }
+                }
+                write_pending_batch(&space, &batches, batch)?;
+                Ok(true)
+            })
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        let expires_at = expiry_time(ttl);
+        let write_lock = self.write_lock.clone();
+        let (durability, group_commit) = (self.durability, self.group_commit.clone());
+        run_in_pool(&self.pool, move || {
+            {
+                let _guard = write_lock.lock().unwrap();
+                space.check()?;
+                space
+                    .expiry
+                    .set(key.clone(), expires_at.to_le_bytes().to_vec())?;
+                space.set(key, value)?;
+            }
+            sync_write(&space.db, durability, &group_commit)
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = KvPairs, Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        let range = owned_range(&range);
+        run_in_pool(&self.pool, move || {
+            space.check()?;
+            let now = now_millis();
+            let mut pairs = Vec::new();
+            for res in space.pairs().range(range) {
+                if pairs.len() == limit.unwrap_or(usize::max_value()) {
+                    break;
+                }
+                let (key, value) = res?;
+                let key = AsRef::<[u8]>::as_ref(&key).to_vec();
+                if !is_expired(&space.expiry, &key, now)? {
+                    pairs.push((key, AsRef::<[u8]>::as_ref(&value).to_vec()));
+                }
+            }
+            Ok(pairs)
+        })
+    }
+
+    /// Copies the pairs and the expiry times of all the keyspaces, and the catalog.
+    ///
+    /// Writes wait until the copy is done.
+    fn checkpoint(&self, dest: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.keyspace.db.clone();
+        let catalog = self.catalog.clone();
+        let write_lock = self.write_lock.clone();
+        run_in_pool(&self.pool, move || {
+            create_checkpoint_dir(&dest)?;
+            let copy = Arc::new(Db::start_default(&dest)?);
+            let _guard = write_lock.lock().unwrap();
+            let mut names = catalog_names(&catalog)?;
+            names.push(DEFAULT_KEYSPACE.to_owned());
+            for name in names {
+                let src = Keyspace::open(Arc::clone(&db), &name)?;
+                let dst = Keyspace::open(Arc::clone(&copy), &name)?;
+                copy_tree(src.pairs(), dst.pairs())?;
+                copy_tree(&src.expiry, &dst.expiry)?;
+            }
+            copy_tree(&catalog, &*copy.open_tree(KEYSPACES_TREE.to_vec())?)?;
+            copy.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Returns the number of keys of the keyspace and the bytes taken by them and their
+    /// values.
+    ///
+    /// Sled compacts its files on its own and does not tell their size, so the other
+    /// statistics are not available.
+    fn stats(&self) -> Box<dyn Future<Item = EngineStats, Error = KvsError> + Send> {
+        let space = self.keyspace.clone();
+        run_in_pool(&self.pool, move || {
+            space.check()?;
+            let mut keys = 0;
+            let mut live_bytes = 0;
+            for res in space.pairs().iter() {
+                let (key, value) = res?;
+                keys += 1;
+                let key_len = AsRef::<[u8]>::as_ref(&key).len();
+                let value_len = AsRef::<[u8]>::as_ref(&value).len();
+                live_bytes += (key_len + value_len) as u64;
+            }
+            Ok(EngineStats {
+                keys,
+                live_bytes: Some(live_bytes),
+                ..EngineStats::default()
+            })
+        })
+    }
+
+    /// Returns a stream of the changes of the keys starting with `prefix`.
+    ///
+    /// The events are sent by each write while it holds the write lock, so they are in
+    /// the order of the writes. Expired keys are reported as removed when they are swept.
+    /// A watch that falls behind the writes is ended with `KvsError::WatchLagged`.
+    fn watch(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send> {
+        // a keyspace is not dropped while the watch is added, so its watches all end
+        let _guard = self.write_lock.lock().unwrap();
+        if let Err(e) = self.keyspace.check() {
+            return Box::new(stream::once(Err(e)));
+        }
+        let mut watchers = self.keyspace.watchers.lock().unwrap();
+        watchers.add(DEFAULT_KEYSPACE_ID, prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let mut open_keyspaces = self.open_keyspaces.lock().unwrap();
+        let keyspace = open_keyspace(&mut open_keyspaces, &self.keyspace.db, &self.catalog, name)?;
+        Ok(SledKvsEngine {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    /// Creates an empty keyspace and lists it in the catalog. Its trees are created when
+    /// it is first used.
+    ///
+    /// Trees left by a drop of a keyspace with the same name that a crash interrupted are
+    /// dropped first, so the new keyspace is empty.
+    fn create_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.keyspace.db.clone();
+        let catalog = self.catalog.clone();
+        let write_lock = self.write_lock.clone();
+        run_in_pool(&self.pool, move || {
+            check_keyspace_name(&name)?;
+            let _guard = write_lock.lock().unwrap();
+            if name == DEFAULT_KEYSPACE || catalog.get(name.as_bytes())?.is_some() {
+                return Err(KvsError::KeyspaceExists(name));
+            }
+            drop_keyspace_trees(&db, &name)?;
+            catalog.set(name.into_bytes(), Vec::new())?;
+            db.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Drops a keyspace and its trees.
+    ///
+    /// The trees are dropped before the keyspace is removed from the catalog, so a crash
+    /// in between leaves the keyspace listed, and it can be dropped again. The operations
+    /// of its handles fail with `KvsError::KeyspaceDropped` afterwards.
+    fn drop_keyspace(&self, name: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.keyspace.db.clone();
+        let catalog = self.catalog.clone();
+        let open_keyspaces = self.open_keyspaces.clone();
+        let write_lock = self.write_lock.clone();
+        run_in_pool(&self.pool, move || {
+            if name == DEFAULT_KEYSPACE {
+                return Err(KvsError::StringError(
+                    "The default keyspace cannot be dropped".to_owned(),
+                ));
+            }
+            let _guard = write_lock.lock().unwrap();
+            let mut open_keyspaces = open_keyspaces.lock().unwrap();
+            if catalog.get(name.as_bytes())?.is_none() {
+                return Err(KvsError::KeyspaceNotFound(name));
+            }
+            if let Some(space) = open_keyspaces.remove(&name) {
+                space.dropped.store(true, Ordering::SeqCst);
+                // ends the streams of its watches
+                let mut watchers = space.watchers.lock().unwrap();
+                watchers.remove_keyspace(DEFAULT_KEYSPACE_ID);
+            }
+            drop_keyspace_trees(&db, &name)?;
+            catalog.del(name.as_bytes())?;
+            db.flush()?;
+            Ok(())
+        })
+    }
+
+    fn list_keyspaces(&self) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let catalog = self.catalog.clone();
+        run_in_pool(&self.pool, move || {
+            let mut names = catalog_names(&catalog)?;
+            names.push(DEFAULT_KEYSPACE.to_owned());
+            names.sort();
+            Ok(names)
+        })
     }
 }
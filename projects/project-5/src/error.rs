@@ -1,5 +1,6 @@
 use failure::Fail;
 use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 /// Error type for kvs
@@ -14,6 +15,69 @@ pub enum KvsError {
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
+    /// The current value of a key does not match the expected one in a conditional write.
+    /// It carries the current value.
+    #[fail(display = "Condition failed")]
+    ConditionFailed(Option<Vec<u8>>),
+    /// A transaction keeps conflicting with concurrent writes and has been given up.
+    #[fail(display = "Transaction aborted after too many conflicts")]
+    TransactionConflict,
+    /// A log record fails its checksum or cannot be decoded.
+    /// It indicates the log file is damaged.
+    #[fail(
+        display = "Corrupted log record in generation {} at offset {}",
+        gen, offset
+    )]
+    CorruptedLog {
+        /// Generation number of the damaged log file
+        gen: u64,
+        /// Offset of the damaged record in the log file
+        offset: u64,
+    },
+    /// A table file of the LSM-tree engine fails its checksum or cannot be decoded.
+    /// It carries the id of the table.
+    #[fail(display = "Corrupted table {}", _0)]
+    CorruptedTable(u64),
+    /// The flushes and compactions of the LSM-tree engine keep failing, so writes are
+    /// refused until they succeed again. It carries the error of the last attempt.
+    #[fail(display = "Flushing or compacting tables keeps failing: {}", _0)]
+    BackgroundWorkFailed(String),
+    /// A page of the B+tree engine fails its checksum or cannot be decoded.
+    /// It carries the page number.
+    #[fail(display = "Corrupted page {}", _0)]
+    CorruptedPage(u64),
+    /// The snapshot of the in-memory engine fails its checksum or cannot be decoded.
+    #[fail(display = "Corrupted snapshot")]
+    CorruptedSnapshot,
+    /// The data directory is locked by another process that has it open.
+    #[fail(display = "Data directory {:?} is locked by another process", _0)]
+    DirectoryLocked(PathBuf),
+    /// A watch or a change feed has fallen too far behind the writes and has been ended.
+    #[fail(display = "Watch has fallen too far behind the writes")]
+    WatchLagged,
+    /// Compaction has already dropped some of the writes a change feed asks for.
+    #[fail(
+        display = "Changes after {} are no longer available, the oldest is {}",
+        requested, oldest
+    )]
+    ChangesTooOld {
+        /// Sequence number after which the changes were asked for
+        requested: u64,
+        /// Sequence number of the oldest change still available
+        oldest: u64,
+    },
+    /// There is no keyspace with the given name.
+    #[fail(display = "Keyspace {:?} not found", _0)]
+    KeyspaceNotFound(String),
+    /// There is already a keyspace with the given name.
+    #[fail(display = "Keyspace {:?} already exists", _0)]
+    KeyspaceExists(String),
+    /// The keyspace of a handle has been dropped.
+    #[fail(display = "Keyspace has been dropped")]
+    KeyspaceDropped,
+    /// The data directory is written in a format version this build cannot read.
+    #[fail(display = "Unsupported data format version {}", _0)]
+    UnsupportedFormat(u32),
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
@@ -4,14 +4,22 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use batch::WriteBatch;
+pub use client::{KvsClient, Watch};
+pub use engines::{
+    BTreeKvsEngine, BTreeOptions, CacheStats, Change, CompactionTrigger, Durability, EngineStats,
+    GenerationStats, KvPairs, KvStore, KvStoreOptions, KvStoreSnapshot, KvsEngine, LsmKvsEngine,
+    LsmOptions, MemoryKvsEngine, MemoryOptions, SledKvsEngine, WatchEvent, DEFAULT_KEYSPACE,
+};
 pub use error::{KvsError, Result};
 pub use server::KvsServer;
+pub use transaction::Transaction;
 
+mod batch;
 mod client;
 mod common;
 mod engines;
 mod error;
 mod server;
 pub mod thread_pool;
+mod transaction;
@@ -1,20 +1,35 @@
 use crate::common::{Request, Response};
-use crate::{KvsEngine, KvsError, Result};
+use crate::{KvsEngine, KvsError, Result, WatchEvent};
 use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
 /// The server of a key value store.
+///
+/// Clients can only write checkpoints to the backup directory of the server, and only if
+/// it has one.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    backup_dir: Option<Arc<PathBuf>>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            backup_dir: None,
+        }
+    }
+
+    /// Sets the directory that the checkpoints requested by clients are written under.
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(Arc::new(dir));
+        self
     }
 
     /// Run the server listening on the given address
@@ -25,40 +40,208 @@ impl<E: KvsEngine> KvsServer<E> {
             .map_err(|e| error!("IO error: {}", e))
             .for_each(move |tcp| {
                 let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
+                let backup_dir = self.backup_dir.clone();
+                // a watching client keeps its connection open, so the others are served beside it
+                tokio::spawn(
+                    serve(engine, backup_dir, tcp)
+                        .map_err(|e| error!("Error on serving client: {}", e)),
+                );
+                Ok(())
             });
         tokio::run(server);
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+fn serve<E: KvsEngine>(
+    engine: E,
+    backup_dir: Option<Arc<PathBuf>>,
+    tcp: TcpStream,
+) -> impl Future<Item = (), Error = KvsError> {
     let (read_half, write_half) = tcp.split();
     let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-    let resp_stream = read_json
-        .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
-                    }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
-                    }
-                }
-            },
-        )
-        .then(|resp| -> Result<Response> {
-            match resp {
-                Ok(resp) => Ok(resp),
-                Err(e) => Ok(Response::Err(format!("{}", e))),
-            }
-        });
+    let responses = Responses {
+        engine,
+        backup_dir,
+        requests: read_json.map_err(KvsError::from),
+        pending: None,
+        watch: None,
+    };
+    let resp_stream = responses.then(|resp| -> Result<Response> {
+        match resp {
+            Ok(resp) => Ok(resp),
+            Err(e) => Ok(Response::Err(format!("{}", e))),
+        }
+    });
     let write_json = WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
     write_json
         .sink_map_err(KvsError::from)
         .send_all(resp_stream)
         .map(|_| ())
 }
+
+/// The responses to the requests on a connection.
+///
+/// Requests are served one at a time, each by a handle of the keyspace it names. After a
+/// `Watch` request, the events of the watch are sent as they come until an `Unwatch`
+/// request. Requests are polled before events, so no event follows the response to
+/// `Unwatch`.
+struct Responses<E: KvsEngine, S> {
+    engine: E,
+    backup_dir: Option<Arc<PathBuf>>,
+    requests: S,
+    // the response to the request being served
+    pending: Option<Box<dyn Future<Item = Response, Error = KvsError> + Send>>,
+    watch: Option<Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send>>,
+}
+
+impl<E, S> Stream for Responses<E, S>
+where
+    E: KvsEngine,
+    S: Stream<Item = Request, Error = KvsError>,
+{
+    type Item = Response;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<Option<Response>, KvsError> {
+        if let Some(mut pending) = self.pending.take() {
+            let res = pending.poll();
+            if let Ok(Async::NotReady) = res {
+                self.pending = Some(pending);
+            }
+            return res.map(|resp| resp.map(Some));
+        }
+        match self.requests.poll()? {
+            Async::Ready(Some(Request::Watch { keyspace, prefix })) => {
+                let keyspace = keyspace.as_ref().map(String::as_str);
+                let resp = match keyspace_engine(&self.engine, keyspace) {
+                    Ok(engine) => {
+                        self.watch = Some(engine.watch(prefix));
+                        Response::Watch
+                    }
+                    Err(e) => Response::Err(format!("{}", e)),
+                };
+                return Ok(Async::Ready(Some(resp)));
+            }
+            Async::Ready(Some(Request::Unwatch)) => {
+                self.watch = None;
+                return Ok(Async::Ready(Some(Response::Unwatch)));
+            }
+            Async::Ready(Some(req)) => {
+                self.pending = Some(handle(&self.engine, self.backup_dir.as_ref(), req));
+                return self.poll();
+            }
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => {}
+        }
+        if let Some(ref mut watch) = self.watch {
+            match watch.poll() {
+                Ok(Async::Ready(Some(event))) => {
+                    return Ok(Async::Ready(Some(Response::Event(event))))
+                }
+                // the engine is gone
+                Ok(Async::Ready(None)) => self.watch = None,
+                Ok(Async::NotReady) => {}
+                // the watch has fallen behind, and is not polled again
+                Err(e) => {
+                    self.watch = None;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Returns a handle of the keyspace named by a request, or of the default one.
+fn keyspace_engine<E: KvsEngine>(engine: &E, keyspace: Option<&str>) -> Result<E> {
+    match keyspace {
+        Some(name) => engine.keyspace(name),
+        None => Ok(engine.clone()),
+    }
+}
+
+/// Returns the directory under `backup_dir` that a client asks a checkpoint to be written
+/// to.
+///
+/// The directory has to be a relative path without `..` components, so that it cannot
+/// be outside of `backup_dir`.
+fn checkpoint_dir(backup_dir: Option<&Arc<PathBuf>>, dir: &Path) -> Result<PathBuf> {
+    let backup_dir = backup_dir
+        .ok_or_else(|| KvsError::StringError("The server has no backup directory".to_owned()))?;
+    let is_below = dir.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        Component::Prefix(_) | Component::RootDir | Component::ParentDir => false,
+    });
+    if dir.as_os_str().is_empty() || !is_below {
+        return Err(KvsError::StringError(format!(
+            "Invalid checkpoint directory {:?}",
+            dir
+        )));
+    }
+    Ok(backup_dir.join(dir))
+}
+
+/// Serves a request that is answered with a single response.
+fn handle<E: KvsEngine>(
+    engine: &E,
+    backup_dir: Option<&Arc<PathBuf>>,
+    req: Request,
+) -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
+    let engine = match keyspace_engine(engine, req.keyspace()) {
+        Ok(engine) => engine,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    match req {
+        Request::Get { key, .. } => Box::new(engine.get_bytes(key).map(Response::Get)),
+        Request::Set {
+            key,
+            value,
+            ttl: None,
+            ..
+        } => Box::new(engine.set_bytes(key, value).map(|_| Response::Set)),
+        Request::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+            ..
+        } => Box::new(engine.set_with_ttl(key, value, ttl).map(|_| Response::Set)),
+        Request::Remove { key, .. } => Box::new(engine.remove_bytes(key).map(|_| Response::Remove)),
+        Request::Scan {
+            start, end, limit, ..
+        } => Box::new(engine.scan((start, end), limit).map(Response::Scan)),
+        Request::Batch { batch, .. } => {
+            Box::new(engine.write_batch(batch).map(|_| Response::Batch))
+        }
+        Request::Checkpoint { dir } => match checkpoint_dir(backup_dir, &dir) {
+            Ok(dir) => Box::new(engine.checkpoint(dir).map(|_| Response::Checkpoint)),
+            Err(e) => Box::new(future::err(e)),
+        },
+        Request::Stats { .. } => Box::new(engine.stats().map(Response::Stats)),
+        Request::CreateKeyspace { name } => Box::new(
+            engine
+                .create_keyspace(name)
+                .map(|_| Response::CreateKeyspace),
+        ),
+        Request::DropKeyspace { name } => {
+            Box::new(engine.drop_keyspace(name).map(|_| Response::DropKeyspace))
+        }
+        Request::ListKeyspaces => Box::new(engine.list_keyspaces().map(Response::ListKeyspaces)),
+        Request::CompareAndSwap {
+            key, expected, new, ..
+        } => Box::new(
+            engine
+                .compare_and_swap_bytes(key, expected, new)
+                .then(|res| match res {
+                    Ok(()) => Ok(Response::CompareAndSwap),
+                    Err(KvsError::ConditionFailed(current)) => {
+                        Ok(Response::ConditionFailed(current))
+                    }
+                    Err(e) => Err(e),
+                }),
+        ),
+        Request::Watch { .. } | Request::Unwatch => {
+            unreachable!("watch requests are served by `Responses`")
+        }
+    }
+}
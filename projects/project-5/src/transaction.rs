@@ -0,0 +1,111 @@
+use crate::{KvsError, Result, WriteBatch};
+use std::collections::BTreeMap;
+
+// how many times a transaction is run before giving up on conflicts
+const MAX_TRANSACTION_ATTEMPTS: usize = 100;
+
+// reads the current value of a key from the engine
+type ReadFn<'a> = dyn Fn(&[u8]) -> Result<Option<Vec<u8>>> + 'a;
+
+/// An optimistic transaction passed to the closure of `KvsEngine::transaction`.
+///
+/// Reads go to the engine and the values read are remembered. Writes are buffered and
+/// seen by later reads in the same transaction. When the closure returns, the writes are
+/// committed atomically only if none of the keys read has changed in the meantime.
+pub struct Transaction<'a> {
+    read: &'a ReadFn<'a>,
+    // values seen by the transaction, to be validated at commit time
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // buffered writes, `None` for removals
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    fn new(read: &'a ReadFn<'a>) -> Transaction<'a> {
+        Transaction {
+            read,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(key) {
+            return Ok(value.clone());
+        }
+        let value = (self.read)(key)?;
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a key when the transaction commits.
+    ///
+    /// Removing a key that does not exist is not an error in a transaction.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Sets the value of a string key to a string when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Removes a string key when the transaction commits.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+}
+
+/// Runs `f` in a transaction until it commits without conflicts.
+///
+/// `read` reads the current value of a key. `commit` validates the values read by the
+/// transaction and applies its writes atomically, or returns `false` if a value has
+/// changed.
+pub(crate) fn run_transaction<F, T, R, C>(f: F, read: R, mut commit: C) -> Result<T>
+where
+    F: Fn(&mut Transaction) -> Result<T>,
+    R: Fn(&[u8]) -> Result<Option<Vec<u8>>>,
+    C: FnMut(BTreeMap<Vec<u8>, Option<Vec<u8>>>, WriteBatch) -> Result<bool>,
+{
+    for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+        let mut txn = Transaction::new(&read);
+        let value = f(&mut txn)?;
+        let mut batch = WriteBatch::new();
+        for (key, value) in txn.writes {
+            match value {
+                Some(value) => batch.set_bytes(key, value),
+                None => batch.remove_bytes(key),
+            }
+        }
+        if commit(txn.reads, batch)? {
+            return Ok(value);
+        }
+        debug!("Transaction conflicted, retrying");
+    }
+    Err(KvsError::TransactionConflict)
+}
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{KvsClient, WatchEvent};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -6,6 +7,7 @@ use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
+use tokio::prelude::*;
 
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
@@ -322,6 +324,78 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "other", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\nother\tvalue4\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["create-keyspace", "users", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "key2",
+            "value5",
+            "--keyspace",
+            "users",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--keyspace", "users", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--keyspace", "orders", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["list-keyspaces", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("default\nusers\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +409,174 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_access_server_btree_engine() {
+    cli_access_server("btree", "127.0.0.1:4007");
+}
+
+// The memory engine should keep nothing once the server stops, unless it writes
+// snapshots, which it loads when the server starts again.
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let start_server = |args: &[&str]| {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "memory", "--addr", addr])
+            .args(args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let mut child = start_server(&[]);
+    client(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+
+    let mut child = start_server(&["--snapshot-interval", "100"]);
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["set", "key2", "value2"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+
+    let mut child = start_server(&["--snapshot-interval", "100"]);
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    child.kill().expect("server exited before killed");
+}
+
+// Backups should only be written under the backup directory of the server.
+#[test]
+fn cli_backup() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let start_server = |args: &[&str]| {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let mut child = start_server(&[]);
+    client(&["backup", "nightly"])
+        .assert()
+        .failure()
+        .stderr(contains("no backup directory"));
+    child.kill().expect("server exited before killed");
+
+    let mut child = start_server(&["--backup-dir", "backups"]);
+    client(&["set", "key1", "value1"]).assert().success();
+    let outside = temp_dir.path().join("outside");
+    for dir in &[
+        "../outside",
+        "backups/../../outside",
+        outside.to_str().unwrap(),
+    ] {
+        client(&["backup", dir])
+            .assert()
+            .failure()
+            .stderr(contains("Invalid checkpoint directory"));
+    }
+    assert!(!outside.exists());
+    client(&["backup", "nightly"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert!(temp_dir.path().join("backups").join("nightly").is_dir());
+    child.kill().expect("server exited before killed");
+}
+
+// A watch over the server should get the changes of the keys with its prefix until it
+// is unwatched, and the connection should serve requests again afterwards.
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let watch = KvsClient::connect(addr.parse().unwrap())
+        .and_then(|client| client.watch(b"key".to_vec()))
+        .wait()
+        .unwrap();
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "other", "value2"]).assert().success();
+    client(&["rm", "key1"]).assert().success();
+
+    let (event, watch) = watch.into_future().wait().map_err(|(e, _)| e).unwrap();
+    assert_eq!(
+        event,
+        Some(WatchEvent::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+        })
+    );
+    let (event, watch) = watch.into_future().wait().map_err(|(e, _)| e).unwrap();
+    assert_eq!(
+        event,
+        Some(WatchEvent::Remove {
+            key: b"key1".to_vec(),
+        })
+    );
+
+    client(&["set", "key2", "value3"]).assert().success();
+    let client = watch.unwatch().wait().unwrap();
+    let (value, _) = client.get("other".to_owned()).wait().unwrap();
+    assert_eq!(value, Some("value2".to_owned()));
+    child.kill().expect("server exited before killed");
+}
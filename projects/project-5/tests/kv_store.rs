@@ -1,15 +1,73 @@
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use kvs::{
+    BTreeKvsEngine, BTreeOptions, Change, CompactionTrigger, Durability, KvStore, KvStoreOptions,
+    KvStoreSnapshot, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, MemoryKvsEngine, Result,
+    SledKvsEngine, WatchEvent, WriteBatch, DEFAULT_KEYSPACE,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 
+// Opens an engine at a path with the given concurrency
+type Open<E> = fn(&Path, u32) -> Result<E>;
+
+// Runs the tests every engine should pass with the engine opened by `$open`
+macro_rules! engine_tests {
+    ($engine:ident, $open:expr) => {
+        mod $engine {
+            use super::*;
+
+            engine_tests!(
+                @tests $open;
+                get_stored_value,
+                overwrite_value,
+                get_non_existent_value,
+                remove_non_existent_key,
+                remove_key,
+                binary_key_value,
+                get_invalid_utf8_value,
+                scan,
+                compare_and_swap,
+                transaction,
+                set_with_ttl,
+                watch,
+                keyspaces
+            );
+        }
+    };
+    (@tests $open:expr; $($test:ident),*) => {
+        $(
+            #[test]
+            fn $test() -> Result<()> {
+                super::$test($open)
+            }
+        )*
+    };
+}
+
+engine_tests!(kv_store, |path, concurrency| {
+    KvStore::<RayonThreadPool>::open(path, concurrency)
+});
+engine_tests!(lsm, |path, concurrency| {
+    LsmKvsEngine::<RayonThreadPool>::open(path, concurrency)
+});
+engine_tests!(btree, |path, concurrency| {
+    BTreeKvsEngine::<RayonThreadPool>::open(path, concurrency)
+});
+engine_tests!(memory, |path, concurrency| {
+    MemoryKvsEngine::<RayonThreadPool>::open(path, concurrency)
+});
+
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = open(temp_dir.path(), 1)?;
 
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     store.set("key2".to_owned(), "value2".to_owned()).wait()?;
@@ -25,7 +83,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
         Some("value1".to_owned())
@@ -39,10 +97,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = open(temp_dir.path(), 1)?;
 
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     assert_eq!(
@@ -57,7 +114,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
         Some("value2".to_owned())
@@ -72,37 +129,514 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = open(temp_dir.path(), 1)?;
 
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     assert_eq!(store.get("key2".to_owned()).wait()?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = open(temp_dir.path(), 1)?;
     assert_eq!(store.get("key2".to_owned()).wait()?, None);
 
     Ok(())
 }
 
+fn remove_non_existent_key<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+    assert!(store.remove("key1".to_owned()).wait().is_err());
+    Ok(())
+}
+
+fn remove_key<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert!(store.remove("key1".to_owned()).wait().is_ok());
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    Ok(())
+}
+
+// Keys and values that are not valid UTF-8 should be stored as they are.
+fn binary_key_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+
+    let key = vec![0, 159, 146, 150];
+    let value = vec![0xff, 0, 0xfe, b'\n'];
+    store.set_bytes(key.clone(), value.clone()).wait()?;
+    store.set_bytes(vec![0xc3], vec![0x28]).wait()?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(value.clone()));
+    assert_eq!(store.get_bytes(vec![0xc3]).wait()?, Some(vec![0x28]));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path(), 1)?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(value));
+    assert!(store.remove_bytes(key.clone()).wait().is_ok());
+    assert_eq!(store.get_bytes(key).wait()?, None);
+
+    Ok(())
+}
+
+// A value that is not valid UTF-8 cannot be read through the string API.
+fn get_invalid_utf8_value<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+    store.set_bytes(b"key1".to_vec(), vec![0xff]).wait()?;
+    match store.get("key1".to_owned()).wait() {
+        Err(KvsError::Utf8(_)) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(value) => panic!("unexpected value: {:?}", value),
+    }
+}
+
+// Should list key/value pairs in key order within the range
+fn scan<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+
+    for key in &["b", "a2", "c", "a1", "a", "a\u{ff}"] {
+        store.set(key.to_string(), format!("v{}", key)).wait()?;
+    }
+    store.remove("c".to_owned()).wait()?;
+
+    let pair = |key: &str| (key.as_bytes().to_vec(), format!("v{}", key).into_bytes());
+    assert_eq!(
+        store.scan(.., None).wait()?,
+        vec![
+            pair("a"),
+            pair("a1"),
+            pair("a2"),
+            pair("a\u{ff}"),
+            pair("b")
+        ]
+    );
+    assert_eq!(
+        store.scan(b"a1".to_vec()..b"b".to_vec(), None).wait()?,
+        vec![pair("a1"), pair("a2"), pair("a\u{ff}")]
+    );
+    assert_eq!(
+        store.scan(b"a1".to_vec().., Some(2)).wait()?,
+        vec![pair("a1"), pair("a2")]
+    );
+    assert_eq!(
+        store.scan_prefix(b"a".to_vec(), None).wait()?,
+        vec![pair("a"), pair("a1"), pair("a2"), pair("a\u{ff}")]
+    );
+    assert_eq!(
+        store.scan_prefix(b"a".to_vec(), Some(1)).wait()?,
+        vec![pair("a")]
+    );
+    assert_eq!(store.scan_prefix(b"c".to_vec(), None).wait()?, vec![]);
+    assert_eq!(store.scan_prefix(vec![], None).wait()?.len(), 5);
+
+    Ok(())
+}
+
+// Conditional writes should only apply when the current value matches
+fn compare_and_swap<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+
+    store
+        .set_if_absent("key1".to_owned(), "value1".to_owned())
+        .wait()?;
+    match store
+        .set_if_absent("key1".to_owned(), "value2".to_owned())
+        .wait()
+    {
+        Err(KvsError::ConditionFailed(current)) => assert_eq!(current, Some(b"value1".to_vec())),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    store
+        .compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+        )
+        .wait()?;
+    match store
+        .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)
+        .wait()
+    {
+        Err(KvsError::ConditionFailed(current)) => assert_eq!(current, Some(b"value2".to_vec())),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    store
+        .compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)
+        .wait()?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+
+    // Reopen and check the swapped value was persisted
+    store
+        .compare_and_swap("key2".to_owned(), None, Some("value3".to_owned()))
+        .wait()?;
+    drop(store);
+    let store = open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// A write batch should be applied as a whole, and not at all if its record is torn
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn write_batch() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert!(store.remove("key1".to_owned()).wait().is_err());
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.remove("key4".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.set("key3".to_owned(), "value4".to_owned());
+    store.write_batch(batch).wait()?;
+
+    let check = |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned()).wait()?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).wait()?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(
+            store.get("key3".to_owned()).wait()?,
+            Some("value4".to_owned())
+        );
+        Ok(())
+    };
+    check(&store)?;
+
+    // Reopen and check the batch was persisted
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check(&store)?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value5".to_owned());
+    batch.set("key5".to_owned(), "value5".to_owned());
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    // Tear the last batch record, which is in the new log started by the reopen
+    let log_path = temp_dir.path().join("2.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check(&store)?;
+    assert_eq!(store.get("key5".to_owned()).wait()?, None);
+
     Ok(())
 }
 
+// A snapshot should keep seeing the values at the time it was taken, also across compaction
 #[test]
-fn remove_key() -> Result<()> {
+fn snapshot() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert!(store.remove("key1".to_owned()).wait().is_ok());
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    store.remove("key2".to_owned()).wait()?;
+    store.set("key3".to_owned(), "value4".to_owned()).wait()?;
+
+    let check = |snapshot: &KvStoreSnapshot<RayonThreadPool>| -> Result<()> {
+        assert_eq!(
+            snapshot.get("key1".to_owned()).wait()?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            snapshot.get("key2".to_owned()).wait()?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(snapshot.get("key3".to_owned()).wait()?, None);
+        assert_eq!(
+            snapshot.scan(.., None).wait()?,
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec())
+            ]
+        );
+        Ok(())
+    };
+    check(&snapshot)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    // Overwrite a key until a compaction happens
+    let value = "v".repeat(1000);
+    for _ in 0..2000 {
+        store.set("key4".to_owned(), value.clone()).wait()?;
+    }
+    check(&snapshot)?;
+
+    // Stale log files kept for the snapshot are deleted after it is dropped
+    let stale_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("stale".as_ref()))
+            .count()
+    };
+    // Compaction runs in the background
+    let mut iter = 0;
+    while stale_files() == 0 {
+        assert!(iter < 100, "No compaction detected");
+        thread::sleep(Duration::from_millis(50));
+        iter += 1;
+    }
+    check(&snapshot)?;
+    drop(snapshot);
+    assert_eq!(stale_files(), 0);
+
+    Ok(())
+}
+
+// Concurrent transactions should not lose each other's updates
+fn transaction<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 4)?;
+    store.set("alice".to_owned(), "1000".to_owned()).wait()?;
+
+    // move 1 from alice to bob 100 times concurrently
+    let transfers = (0..100).map(|_| {
+        store.transaction(|txn| {
+            let alice: u32 = txn.get("alice")?.unwrap().parse().unwrap();
+            let bob: u32 = txn.get("bob")?.map_or(0, |bob| bob.parse().unwrap());
+            txn.set("alice".to_owned(), (alice - 1).to_string());
+            txn.set("bob".to_owned(), (bob + 1).to_string());
+            Ok(bob + 1)
+        })
+    });
+    let mut results = future::join_all(transfers).wait()?;
+    results.sort();
+    assert_eq!(results, (1..=100).collect::<Vec<_>>());
+    assert_eq!(
+        store.get("alice".to_owned()).wait()?,
+        Some("900".to_owned())
+    );
+    assert_eq!(store.get("bob".to_owned()).wait()?, Some("100".to_owned()));
+
+    // An error in the transaction discards its writes
+    let res = store
+        .transaction(|txn| {
+            txn.remove("alice".to_owned());
+            Err::<(), _>(KvsError::StringError("abort".to_owned()))
+        })
+        .wait();
+    assert!(res.is_err());
+    assert_eq!(
+        store.get("alice".to_owned()).wait()?,
+        Some("900".to_owned())
+    );
+
+    // Reopen and check the committed values
+    drop(store);
+    let store = open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("alice".to_owned()).wait()?,
+        Some("900".to_owned())
+    );
+    assert_eq!(store.get("bob".to_owned()).wait()?, Some("100".to_owned()));
+
+    Ok(())
+}
+
+// Transactions of the sled engine should not lose the single-key writes made meanwhile
+#[test]
+fn sled_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let store = SledKvsEngine::<RayonThreadPool>::new(db, 4)?;
+    store.set("counter".to_owned(), "0".to_owned()).wait()?;
+
+    // increment the counter 100 times by compare-and-swap on other threads
+    let swappers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let current = store.get("counter".to_owned()).wait()?;
+                        let next = current.as_ref().unwrap().parse::<u32>().unwrap() + 1;
+                        let res = store
+                            .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                            .wait();
+                        match res {
+                            Ok(()) => break,
+                            Err(KvsError::ConditionFailed(_)) => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    // and 100 times by transactions
+    let increments = (0..100).map(|_| {
+        store.transaction(|txn| {
+            let counter: u32 = txn.get("counter")?.unwrap().parse().unwrap();
+            txn.set("counter".to_owned(), (counter + 1).to_string());
+            Ok(())
+        })
+    });
+    future::join_all(increments).wait()?;
+    for swapper in swappers {
+        swapper.join().unwrap()?;
+    }
+    assert_eq!(
+        store.get("counter".to_owned()).wait()?,
+        Some("200".to_owned())
+    );
+
+    Ok(())
+}
+
+// Keys set with a time-to-live should disappear once it elapses, also after reopening
+fn set_with_ttl<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+
+    let ttl = Duration::from_secs(1);
+    store
+        .set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), ttl)
+        .wait()?;
+    store
+        .set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), ttl)
+        .wait()?;
+    store
+        .set_with_ttl(b"key3".to_vec(), b"value3".to_vec(), ttl * 3600)
+        .wait()?;
+    // setting a key without a ttl makes it persistent
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    drop(store);
+    let store = open(temp_dir.path(), 1)?;
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.scan(.., None).wait()?.len(), 2);
+    match store.remove("key1".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        _ => panic!("expired key should not be removed"),
+    }
+
+    Ok(())
+}
+
+// Expired keys should be removed by a logged write that shows up in the change feed
+#[test]
+fn remove_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store
+        .set_with_ttl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    thread::sleep(Duration::from_millis(2500));
+
+    let changes: Vec<Change> = store.changes_since(2).take(1).collect().wait()?;
+    assert_eq!(
+        changes[0],
+        Change {
+            seq: 3,
+            events: vec![WatchEvent::Remove {
+                key: b"key1".to_vec(),
+            }],
+        }
+    );
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let changes: Vec<Change> = store.changes_since(2).take(1).collect().wait()?;
+    assert_eq!(changes[0].seq, 3);
+    assert_eq!(store.scan(.., None).wait()?.len(), 1);
+
+    Ok(())
+}
+
+// Expired keys found by a compaction should be removed the same way, before the
+// periodic removal gets to them.
+#[test]
+fn compaction_removes_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .compaction_trigger(CompactionTrigger::StaleBytes(u64::max_value()))
+            .max_log_size(4096)
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options())?;
+    store
+        .set_with_ttl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    // the logs only hold garbage besides the expiring key
+    for key_id in 0..300 {
+        store
+            .set(format!("temp{}", key_id), "value".to_owned())
+            .wait()?;
+        store.remove(format!("temp{}", key_id)).wait()?;
+    }
+    drop(store);
+    thread::sleep(Duration::from_millis(200));
+
+    let store =
+        KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options().compact_on_open(true))?;
+    let feed = store.changes_since(601);
+    let changes: Vec<Change> = feed.take(1).collect().wait()?;
+    assert_eq!(
+        changes[0],
+        Change {
+            seq: 602,
+            events: vec![WatchEvent::Remove {
+                key: b"key1".to_vec(),
+            }],
+        }
+    );
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    let changes: Vec<Change> = store.changes_since(601).take(1).collect().wait()?;
+    assert_eq!(changes[0].seq, 602);
+
     Ok(())
 }
 
@@ -152,67 +686,940 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// The active log should roll over at its maximum size, and the logs should be compacted
+// on open if asked to.
 #[test]
-fn concurrent_set() -> Result<()> {
+fn open_with_options() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read the data directory")
+            .map(|entry| entry.expect("unable to read the data directory").path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .count()
+    };
 
-    // concurrent set in 8 threads
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for i in 0..10000 {
-            executor.spawn(
-                store
-                    .set(format!("key{}", i), format!("value{}", i))
-                    .map_err(|_| ()),
-            );
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(u64::max_value()))
+        .max_log_size(4096);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    for iter in 0..2 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            store.set(key, format!("{}", iter)).wait()?;
         }
-        future::ok::<(), KvsError>(())
-    }))?;
+    }
+    drop(store);
+    let rolled = log_files();
+    assert!(rolled > 5);
 
-    // We only check concurrent set in this test, so we check sequentially here
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    for i in 0..10000 {
-        assert_eq!(
-            store.get(format!("key{}", i)).wait()?,
-            Some(format!("value{}", i))
-        );
+    let options = KvStoreOptions::new()
+        .compact_on_open(true)
+        .reader_pool_size(1);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 4, options)?;
+    let mut iter = 0;
+    while log_files() >= rolled {
+        assert!(iter < 100, "No compaction detected");
+        thread::sleep(Duration::from_millis(50));
+        iter += 1;
     }
 
+    // There are fewer readers than threads
+    let gets = (0..1000).map(|key_id| store.get(format!("key{}", key_id)));
+    let values = future::join_all(gets).wait()?;
+    assert!(values.iter().all(|value| *value == Some("1".to_owned())));
+
     Ok(())
 }
 
+// Only the logs that are mostly garbage should be merged, and the keys removed by them
+// should stay removed even if an older log that is kept still sets them.
 #[test]
-fn concurrent_get() -> Result<()> {
+fn merge_by_garbage_ratio() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    // We only check concurrent get in this test, so we set sequentially here
-    for i in 0..100 {
+    let options = || {
+        KvStoreOptions::new()
+            .compaction_trigger(CompactionTrigger::StaleBytes(u64::max_value()))
+            .max_log_size(4096)
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options())?;
+    // a quarter of the commands in these logs become garbage
+    for key_id in 0..300 {
         store
-            .set(format!("key{}", i), format!("value{}", i))
-            .wait()
-            .unwrap();
+            .set(format!("keep{}", key_id), "value".to_owned())
+            .wait()?;
+        if key_id % 3 == 0 {
+            store
+                .set(format!("temp{}", key_id), "value".to_owned())
+                .wait()?;
+        }
     }
+    // these logs only hold garbage
+    for key_id in (0..300).step_by(3) {
+        store.remove(format!("temp{}", key_id)).wait()?;
+    }
+    let stats = store.generation_stats();
+    assert!(stats.iter().any(|stats| stats.garbage_ratio() > 0.9));
+    drop(store);
 
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                executor.spawn(
-                    store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
-                        .map_err(|_| ()),
-                );
-            }
-        }
-        future::ok::<(), KvsError>(())
-    }))?;
+    let store =
+        KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options().compact_on_open(true))?;
+    let mut iter = 0;
+    while store
+        .generation_stats()
+        .iter()
+        .any(|stats| stats.garbage_ratio() > 0.5)
+    {
+        assert!(iter < 100, "No merge detected");
+        thread::sleep(Duration::from_millis(50));
+        iter += 1;
+    }
+    // the logs with a little garbage are kept
+    let stats = store.generation_stats();
+    assert!(stats.iter().any(|stats| stats.dead() > 0));
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..300 {
+        let value = store.get(format!("keep{}", key_id)).wait()?;
+        assert_eq!(value, Some("value".to_owned()));
+        assert_eq!(store.get(format!("temp{}", key_id)).wait()?, None);
+    }
+
+    Ok(())
+}
+
+// A data directory should be opened by one writable store at a time, or by any number of
+// read-only stores.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::DirectoryLocked(_)) => {}
+        _ => panic!("The data directory is opened twice"),
+    }
+    match KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1) {
+        Err(KvsError::DirectoryLocked(_)) => {}
+        _ => panic!("The data directory is read while it is open"),
+    }
+    // a snapshot keeps the writer of the store, and with it the lock
+    let snapshot = store.snapshot();
+    drop(store);
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::DirectoryLocked(_)) => {}
+        _ => panic!("The data directory is opened while a snapshot reads it"),
+    }
+    drop(snapshot);
+
+    let read_only = KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1)?;
+    let another = KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1)?;
+    assert_eq!(
+        read_only.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        another.scan(.., None).wait()?,
+        vec![(b"key1".to_vec(), b"value1".to_vec())]
+    );
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::DirectoryLocked(_)) => {}
+        _ => panic!("The data directory is opened while it is read"),
+    }
+    drop(read_only);
+    drop(another);
+
+    KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    Ok(())
+}
+
+// Reads should find the values of a log both while it is active and once it is sealed
+// and memory-mapped, and read the merged logs after a compaction.
+#[test]
+fn sealed_log_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+        .max_log_size(4096)
+        .reader_pool_size(1);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+
+    // Every log is read while it is active, then sealed by the writes after it
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "0".to_owned()).wait()?;
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("0".to_owned())
+        );
+    }
+    assert!(store.stats().wait()?.generations.unwrap() > 3);
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("0".to_owned())
+        );
+    }
+
+    // Overwrites trigger compactions that merge the mapped logs away
+    for iter in 1..20 {
+        for key_id in 0..1000 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .wait()?;
+            let read_id = (key_id * 7) % 1000;
+            let expected = if read_id <= key_id { iter } else { iter - 1 };
+            assert_eq!(
+                store.get(format!("key{}", read_id)).wait()?,
+                Some(format!("{}", expected))
+            );
+        }
+    }
+    let mut iter = 0;
+    while store.stats().wait()?.compactions == Some(0) {
+        assert!(iter < 100, "No compaction detected");
+        thread::sleep(Duration::from_millis(50));
+        iter += 1;
+    }
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("19".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// A checkpoint should be openable and keep the contents of the store at the time it was
+// taken.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_log_size(4096);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "0".to_owned()).wait()?;
+    }
+    store
+        .checkpoint(checkpoint_dir.path().join("backup"))
+        .wait()?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "1".to_owned()).wait()?;
+    }
+
+    // The directory is not empty any more
+    assert!(store
+        .checkpoint(checkpoint_dir.path().join("backup"))
+        .wait()
+        .is_err());
+
+    let backup = KvStore::<RayonThreadPool>::open(checkpoint_dir.path().join("backup"), 1)?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            backup.get(format!("key{}", key_id)).wait()?,
+            Some("0".to_owned())
+        );
+    }
+    assert_eq!(store.get("key0".to_owned()).wait()?, Some("1".to_owned()));
+
+    Ok(())
+}
+
+// Values read repeatedly should be served by the cache, which must never return a value
+// that has been overwritten, removed or moved by a compaction.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+        .value_cache_size(1 << 20);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    for _ in 0..3 {
+        assert_eq!(
+            store.get("key1".to_owned()).wait()?,
+            Some("value1".to_owned())
+        );
+    }
+    let stats = store.cache_stats().expect("the cache is not enabled");
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    store.remove("key1".to_owned()).wait()?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+
+    // Overwriting other keys triggers compactions that move the cached values
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id + 3), format!("{}", iter))
+                .wait()?;
+        }
+        assert_eq!(
+            store.get("key2".to_owned()).wait()?,
+            Some("value2".to_owned())
+        );
+        assert_eq!(
+            store.get("key3".to_owned()).wait()?,
+            Some(format!("{}", iter))
+        );
+    }
+    assert!(store.cache_stats().unwrap().hits > 100);
+
+    // The shards of the cache are shared by concurrent readers
+    let before = store.cache_stats().unwrap();
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id + 3)).wait()?,
+                        Some("99".to_owned())
+                    );
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    let stats = store.cache_stats().unwrap();
+    assert_eq!(stats.hits + stats.misses, before.hits + before.misses + 400);
+    assert!(stats.hits > before.hits);
+    assert!(stats.size <= 1 << 20);
+    drop(store);
+
+    // A small cache evicts the least recently used values
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_cache_size(1024);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), "value".to_owned())
+            .wait()?;
+        store.get(format!("key{}", key_id)).wait()?;
+    }
+    let stats = store.cache_stats().unwrap();
+    assert!(stats.evictions > 0);
+    assert!(stats.size <= 1024);
+    assert_eq!(
+        store.get("key0".to_owned()).wait()?,
+        Some("value".to_owned())
+    );
+    assert_eq!(store.cache_stats().unwrap().misses, stats.misses + 1);
+
+    // There is no cache by default
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(store.cache_stats().is_none());
+
+    Ok(())
+}
+
+// Statistics should count the live keys and the compactions run so far.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_trigger(CompactionTrigger::StaleBytes(4096));
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    let stats = store.stats().wait()?;
+    assert_eq!(stats.keys, 0);
+    assert_eq!(stats.compactions, Some(0));
+    assert_eq!(stats.last_compaction, None);
+
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), "value".to_owned())
+            .wait()?;
+    }
+    let stats = store.stats().wait()?;
+    assert_eq!(stats.keys, 100);
+    assert_eq!(stats.uncompacted_bytes, Some(0));
+    let live_bytes = stats.live_bytes.unwrap();
+    assert!(stats.disk_size.unwrap() >= live_bytes);
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .wait()?;
+        }
+    }
+    let mut iter = 0;
+    let stats = loop {
+        let stats = store.stats().wait()?;
+        if stats.compactions > Some(0) {
+            break stats;
+        }
+        assert!(iter < 100, "No compaction detected");
+        thread::sleep(Duration::from_millis(50));
+        iter += 1;
+    };
+    assert_eq!(stats.keys, 100);
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.generations.unwrap() >= 1);
+
+    Ok(())
+}
+
+// A watch should report the writes to the keys with its prefix in order, and stop
+// when it is dropped.
+fn watch<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+    let events = store.watch(b"key".to_vec());
+    let others = store.watch(b"other".to_vec());
+
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("other".to_owned(), "value2".to_owned()).wait()?;
+    store.remove("key1".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set_bytes(b"key2".to_vec(), b"value3".to_vec());
+    batch.set_bytes(b"unwatched".to_vec(), b"value4".to_vec());
+    store.write_batch(batch).wait()?;
+    drop(others);
+    store.set("other".to_owned(), "value5".to_owned()).wait()?;
+
+    let events: Vec<WatchEvent> = events.take(3).collect().wait()?;
+    assert_eq!(
+        events,
+        vec![
+            WatchEvent::Set {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+            },
+            WatchEvent::Remove {
+                key: b"key1".to_vec(),
+            },
+            WatchEvent::Set {
+                key: b"key2".to_vec(),
+                value: b"value3".to_vec(),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// Keyspaces should have keys of their own, survive a reopen until they are dropped, and
+// fail the writes of their handles once dropped.
+fn keyspaces<E: KvsEngine>(open: Open<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), 1)?;
+    assert!(store.keyspace("users").is_err());
+    store.create_keyspace("users".to_owned()).wait()?;
+    store.create_keyspace("orders".to_owned()).wait()?;
+    match store.create_keyspace("users".to_owned()).wait() {
+        Err(KvsError::KeyspaceExists(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(
+        store.list_keyspaces().wait()?,
+        vec![
+            "default".to_owned(),
+            "orders".to_owned(),
+            "users".to_owned()
+        ]
+    );
+
+    let users = store.keyspace("users")?;
+    let orders = store.keyspace("orders")?;
+    store.set("key".to_owned(), "default".to_owned()).wait()?;
+    users.set("key".to_owned(), "users".to_owned()).wait()?;
+    orders.set("key".to_owned(), "orders".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set_bytes(b"key2".to_vec(), b"users".to_vec());
+    users.write_batch(batch).wait()?;
+    assert_eq!(
+        store.get("key".to_owned()).wait()?,
+        Some("default".to_owned())
+    );
+    assert_eq!(
+        users.get("key".to_owned()).wait()?,
+        Some("users".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(users.stats().wait()?.keys, 2);
+
+    store.drop_keyspace("orders".to_owned()).wait()?;
+    assert!(store
+        .drop_keyspace(DEFAULT_KEYSPACE.to_owned())
+        .wait()
+        .is_err());
+    match orders.set("key".to_owned(), "value".to_owned()).wait() {
+        Err(KvsError::KeyspaceDropped) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Open from disk again and check the keyspaces
+    drop(users);
+    drop(orders);
+    drop(store);
+    let store = open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.list_keyspaces().wait()?,
+        vec!["default".to_owned(), "users".to_owned()]
+    );
+    let users = store.keyspace("users")?;
+    assert_eq!(
+        users.get("key".to_owned()).wait()?,
+        Some("users".to_owned())
+    );
+    assert_eq!(
+        users.get("key2".to_owned()).wait()?,
+        Some("users".to_owned())
+    );
+    assert_eq!(
+        store.get("key".to_owned()).wait()?,
+        Some("default".to_owned())
+    );
+    match store.keyspace("orders") {
+        Err(KvsError::KeyspaceNotFound(_)) => {}
+        res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A keyspace of the sled engine created again should not see the trees a crash left
+// behind while the keyspace of the same name was dropped.
+#[test]
+fn sled_keyspace_leftover_trees() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    db.open_tree(b"kvs_keyspace:users".to_vec())?
+        .set(b"key".to_vec(), b"stale".to_vec())?;
+    let store = SledKvsEngine::<RayonThreadPool>::new(db, 1)?;
+    store.create_keyspace("users".to_owned()).wait()?;
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("key".to_owned()).wait()?, None);
+
+    users.set("key".to_owned(), "value".to_owned()).wait()?;
+    store.drop_keyspace("users".to_owned()).wait()?;
+    store.create_keyspace("users".to_owned()).wait()?;
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("key".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// Every write should get the next sequence number, and the writes after a sequence number
+// should be streamed from the logs and then as they are made, also after a reopen.
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.remove("key1".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key2".to_owned());
+    store.write_batch(batch).wait()?;
+
+    let changes: Vec<Change> = store.changes_since(0).take(3).collect().wait()?;
+    let seqs: Vec<u64> = changes.iter().map(|change| change.seq).collect();
+    assert_eq!(seqs, vec![1, 2, 3]);
+    assert_eq!(
+        changes[1].events,
+        vec![WatchEvent::Remove {
+            key: b"key1".to_vec(),
+        }]
+    );
+    assert_eq!(changes[2].events.len(), 2);
+
+    // new writes follow the ones in the logs
+    let feed = store.changes_since(2);
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    let changes: Vec<Change> = feed.take(2).collect().wait()?;
+    assert_eq!(changes[0].seq, 3);
+    assert_eq!(
+        changes[1],
+        Change {
+            seq: 4,
+            events: vec![WatchEvent::Set {
+                key: b"key3".to_vec(),
+                value: b"value3".to_vec(),
+            }],
+        }
+    );
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key4".to_owned(), "value4".to_owned()).wait()?;
+    let changes: Vec<Change> = store.changes_since(3).take(2).collect().wait()?;
+    let seqs: Vec<u64> = changes.iter().map(|change| change.seq).collect();
+    assert_eq!(seqs, vec![4, 5]);
+
+    Ok(())
+}
+
+// A change feed that falls too far behind the writes should fail after the changes it has
+// buffered, and the consumer should be able to resume from the last one it has seen.
+#[test]
+fn lagging_change_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let feed = store.changes_since(0);
+    for i in 0..2000 {
+        store.set(format!("key{}", i), "value".to_owned()).wait()?;
+    }
+
+    let mut last_seq = 0;
+    let res = feed
+        .for_each(|change| {
+            assert_eq!(change.seq, last_seq + 1);
+            last_seq = change.seq;
+            Ok(())
+        })
+        .wait();
+    match res {
+        Err(KvsError::WatchLagged) => {}
+        res => panic!("Unexpected result of a lagging feed: {:?}", res),
+    }
+    assert!(last_seq > 0 && last_seq < 2000);
+
+    let changes: Vec<Change> = store.changes_since(last_seq).take(1).collect().wait()?;
+    assert_eq!(changes[0].seq, last_seq + 1);
+
+    Ok(())
+}
+
+// Compaction should drop the writes older than the change retention, and asking for them
+// should fail.
+#[test]
+fn change_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(0))
+        .max_log_size(4096)
+        .change_retention(100);
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    for iter in 0..200 {
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .wait()?;
+        }
+    }
+
+    let mut iter = 0;
+    loop {
+        match store.changes_since(0).take(1).collect().wait() {
+            Err(KvsError::ChangesTooOld { requested, oldest }) => {
+                assert_eq!(requested, 0);
+                assert!(oldest > 1);
+                break;
+            }
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        assert!(iter < 100, "No compaction detected");
+        thread::sleep(Duration::from_millis(50));
+        iter += 1;
+    }
+
+    let changes: Vec<Change> = store.changes_since(1900).take(100).collect().wait()?;
+    assert_eq!(changes.first().map(|change| change.seq), Some(1901));
+    assert_eq!(changes.last().map(|change| change.seq), Some(2000));
+
+    Ok(())
+}
+
+// Compaction should leave a hint file that is used on open, and a damaged hint file
+// should fall back to replaying the log.
+#[test]
+fn hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let hint_files = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read the data directory")
+            .map(|entry| entry.expect("unable to read the data directory").path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+
+    let mut iter = 0;
+    while hint_files().is_empty() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value).wait()?;
+        }
+        iter += 1;
+    }
+    drop(store);
+
+    let check = || -> Result<()> {
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter - 1)));
+        }
+        Ok(())
+    };
+    check()?;
+
+    for hint_file in hint_files() {
+        let content = fs::read(&hint_file)?;
+        fs::write(&hint_file, &content[..content.len() / 2])?;
+    }
+    check()?;
+
+    Ok(())
+}
+
+// Logs written in the legacy JSON format should be readable and rewritten in the
+// current format by compaction.
+#[test]
+fn legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // JSON commands written back to back, as the store wrote them before records had
+    // frames and before there was a `FORMAT` file
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("2.log"),
+        r#"{"Remove":{"key":"key2"}}{"Set":{"key":"key3","value":"value3"}}"#,
+    )?;
+
+    let format_version = || {
+        fs::read_to_string(temp_dir.path().join("FORMAT"))
+            .ok()
+            .map(|content| content.trim().to_owned())
+    };
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(format_version(), None);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    // Reopening before a compaction reads both the legacy logs and the framed log
+    // written meanwhile.
+    store.set("key4".to_owned(), "value4".to_owned()).wait()?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(format_version(), None);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key4".to_owned()).wait()?,
+        Some("value4".to_owned())
+    );
+
+    let mut iter = 0;
+    while format_version().is_none() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id + 2);
+            store.set(key, format!("{}", iter)).wait()?;
+        }
+        iter += 1;
+    }
+    assert_eq!(format_version(), Some("6".to_owned()));
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some(format!("{}", iter - 1))
+    );
+
+    Ok(())
+}
+
+// A data directory written by a newer version should be refused.
+#[test]
+fn unsupported_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("FORMAT"), "999\n")?;
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::UnsupportedFormat(999)) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unsupported format is not detected"),
+    }
+}
+
+// A record cut short by a crash at the end of the newest log should be dropped on open.
+#[test]
+fn torn_tail_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    // half of a record header and payload
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(&[0x20, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef, b'{'])?;
+    drop(log);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// A damaged record followed by valid ones should be reported instead of silently dropped.
+#[test]
+fn mid_file_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    // flip a byte in the payload of the first record
+    content[10] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::CorruptedLog { gen: 1, offset: 0 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // concurrent set in 8 threads
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    runtime.block_on_all(future::lazy(move || {
+        for i in 0..10000 {
+            executor.spawn(
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .map_err(|_| ()),
+            );
+        }
+        future::ok::<(), KvsError>(())
+    }))?;
+
+    // We only check concurrent set in this test, so we check sequentially here
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..10000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+// Writes should be persisted in every durability mode.
+#[test]
+fn durability() -> Result<()> {
+    for &durability in &[
+        Durability::None,
+        Durability::EveryWrite,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::GroupCommit,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().durability(durability);
+        let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 8, options)?;
+        let sets = (0..1000).map(|i| store.set(format!("key{}", i), format!("value{}", i)));
+        future::join_all(sets).wait()?;
+        drop(store);
+
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", i)).wait()?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    // We only check concurrent get in this test, so we set sequentially here
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()
+            .unwrap();
+    }
+
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    runtime.block_on_all(future::lazy(move || {
+        for thread_id in 0..100 {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                executor.spawn(
+                    store
+                        .get(format!("key{}", key_id))
+                        .map(move |res| {
+                            assert_eq!(res, Some(format!("value{}", key_id)));
+                        })
+                        .map_err(|_| ()),
+                );
+            }
+        }
+        future::ok::<(), KvsError>(())
+    }))?;
 
     // reload from disk and test again
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
@@ -237,3 +1644,466 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// The LSM engine should flush its memtables to tables and compact them without losing or
+// bringing back any key, also after a reopen.
+#[test]
+fn lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions::new().memtable_size(4096).table_size(8192);
+    let store = LsmKvsEngine::<RayonThreadPool>::open_with(temp_dir.path(), 2, options)?;
+    for round in 0..4 {
+        for i in 0..500 {
+            store
+                .set(format!("key{:03}", i), format!("value{}-{}", round, i))
+                .wait()?;
+        }
+    }
+    for i in (0..500).step_by(2) {
+        store.remove(format!("key{:03}", i)).wait()?;
+    }
+    let users = {
+        store.create_keyspace("users".to_owned()).wait()?;
+        store.keyspace("users")?
+    };
+    users.set("key001".to_owned(), "users".to_owned()).wait()?;
+
+    let mut waited = 0;
+    while store.stats().wait()?.compactions == Some(0) {
+        assert!(waited < 1000, "no compaction has been run");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+
+    let check = |store: &LsmKvsEngine<RayonThreadPool>| -> Result<()> {
+        for i in 0..500 {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value3-{}", i))
+            };
+            assert_eq!(store.get(format!("key{:03}", i)).wait()?, expected);
+        }
+        let range = b"key100".to_vec()..b"key110".to_vec();
+        let keys: Vec<Vec<u8>> = store
+            .scan(range, None)
+            .wait()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<Vec<u8>> = (101..110)
+            .step_by(2)
+            .map(|i| format!("key{}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(store.stats().wait()?.keys, 250);
+        assert_eq!(
+            store.keyspace("users")?.get("key001".to_owned()).wait()?,
+            Some("users".to_owned())
+        );
+        Ok(())
+    };
+    check(&store)?;
+    match store.remove("key000".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Open from disk again and check persistent data
+    drop(users);
+    drop(store);
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// A record of the LSM engine's log torn by a crash should be dropped, with the writes
+// before it kept.
+#[test]
+fn lsm_torn_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    let wal_path = temp_dir.path().join("1.wal");
+    let len = fs::metadata(&wal_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&wal_path)?
+        .set_len(len - 3)?;
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+    store.set("key4".to_owned(), "value4".to_owned()).wait()?;
+    drop(store);
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key4".to_owned()).wait()?,
+        Some("value4".to_owned())
+    );
+
+    Ok(())
+}
+
+// A log of the LSM engine left behind by a crash after its memtable was recorded as
+// flushed should not be replayed over the newer writes.
+#[test]
+fn lsm_crash_before_wal_deletion() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions::new().memtable_size(4096);
+    let store = LsmKvsEngine::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    store.set("key".to_owned(), "old".to_owned()).wait()?;
+    let wal_path = temp_dir.path().join("1.wal");
+    let saved_wal = fs::read(&wal_path)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "v".repeat(100)).wait()?;
+    }
+    store.set("key".to_owned(), "new".to_owned()).wait()?;
+
+    // the log is deleted once its memtable is flushed
+    let mut waited = 0;
+    while wal_path.exists() {
+        assert!(waited < 1000, "the memtable has not been flushed");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    drop(store);
+    fs::write(&wal_path, saved_wal)?;
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key".to_owned()).wait()?, Some("new".to_owned()));
+    assert_eq!(store.stats().wait()?.keys, 101);
+    assert!(!wal_path.exists());
+
+    Ok(())
+}
+
+// The tables of the LSM engine that only hold keys of a dropped keyspace should be
+// deleted.
+#[test]
+fn lsm_dropped_keyspace_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions::new().memtable_size(16 * 1024);
+    let store = LsmKvsEngine::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    store.create_keyspace("logs".to_owned()).wait()?;
+    let logs = store.keyspace("logs")?;
+    for i in 0..1000 {
+        logs.set(format!("key{:04}", i), "v".repeat(100)).wait()?;
+    }
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+
+    let tables = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    let mut waited = 0;
+    while tables() == 0 {
+        assert!(waited < 1000, "no memtable has been flushed");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    store.drop_keyspace("logs".to_owned()).wait()?;
+    let mut waited = 0;
+    while tables() > 0 {
+        assert!(waited < 1000, "the tables of the dropped keyspace are left");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    assert_eq!(
+        store.get("key".to_owned()).wait()?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Writes to the LSM engine should fail while its flushes keep failing, and go on once a
+// flush succeeds again.
+#[test]
+fn lsm_flush_failures() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions::new().memtable_size(1024);
+    let store = LsmKvsEngine::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+    // the manifest cannot be replaced while a directory has the name of its temporary file
+    let blocker = temp_dir.path().join("MANIFEST.tmp");
+    fs::create_dir(&blocker)?;
+
+    let mut written = 0;
+    loop {
+        assert!(written < 1000, "writes go on while the flushes fail");
+        match store.set(format!("key{}", written), "v".repeat(100)).wait() {
+            Ok(()) => written += 1,
+            Err(KvsError::BackgroundWorkFailed(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    fs::remove_dir(&blocker)?;
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+    assert_eq!(store.stats().wait()?.keys, written + 1);
+    drop(store);
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.stats().wait()?.keys, written + 1);
+
+    Ok(())
+}
+
+// The B+tree engine should split and merge its pages and store long values in overflow
+// pages without losing or bringing back any key, also after a reopen.
+#[test]
+fn btree_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeOptions::new().cache_size(64 * 1024);
+    let store = BTreeKvsEngine::<RayonThreadPool>::open_with(temp_dir.path(), 2, options)?;
+    for round in 0..2 {
+        for i in 0..2000 {
+            store
+                .set(format!("key{:04}", i), format!("value{}-{}", round, i))
+                .wait()?;
+        }
+    }
+    for i in (0..2000).step_by(2) {
+        store.remove(format!("key{:04}", i)).wait()?;
+    }
+    let large = vec![b'x'; 10_000];
+    store.set_bytes(b"large".to_vec(), large.clone()).wait()?;
+    let users = {
+        store.create_keyspace("users".to_owned()).wait()?;
+        store.keyspace("users")?
+    };
+    users.set("key0001".to_owned(), "users".to_owned()).wait()?;
+
+    let check = |store: &BTreeKvsEngine<RayonThreadPool>| -> Result<()> {
+        for i in 0..2000 {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value1-{}", i))
+            };
+            assert_eq!(store.get(format!("key{:04}", i)).wait()?, expected);
+        }
+        assert_eq!(
+            store.get_bytes(b"large".to_vec()).wait()?,
+            Some(large.clone())
+        );
+        let range = b"key1000".to_vec()..b"key1010".to_vec();
+        let keys: Vec<Vec<u8>> = store
+            .scan(range, None)
+            .wait()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<Vec<u8>> = (1001..1010)
+            .step_by(2)
+            .map(|i| format!("key{}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(store.stats().wait()?.keys, 1001);
+        assert_eq!(
+            store.keyspace("users")?.get("key0001".to_owned()).wait()?,
+            Some("users".to_owned())
+        );
+        Ok(())
+    };
+    check(&store)?;
+    match store.remove("key0000".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Open from disk again and check persistent data
+    drop(users);
+    drop(store);
+    let store = BTreeKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check(&store)?;
+
+    // Removing all keys should leave an empty tree
+    for i in (1..2000).step_by(2) {
+        store.remove(format!("key{:04}", i)).wait()?;
+    }
+    store.remove("large".to_owned()).wait()?;
+    assert_eq!(store.scan(.., None).wait()?, Vec::new());
+
+    Ok(())
+}
+
+// Reads of the B+tree engine should see whole trees while the pages freed by the writes
+// beside them are reused.
+#[test]
+fn btree_reads_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // without a page cache, every node is read from the file
+    let options = BTreeOptions::new().cache_size(0);
+    let store = BTreeKvsEngine::<RayonThreadPool>::open_with(temp_dir.path(), 4, options)?;
+    for i in 0..500 {
+        store
+            .set(format!("key{:03}", i), format!("key{:03}-0", i))
+            .wait()?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..20 {
+                for i in 0..500 {
+                    store
+                        .set(format!("key{:03}", i), format!("key{:03}-{}", i, round))
+                        .wait()?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    let pairs = store.scan(.., None).wait()?;
+                    assert_eq!(pairs.len(), 500);
+                    for (key, value) in pairs {
+                        assert!(value.starts_with(&key));
+                    }
+                    store
+                        .checkpoint(TempDir::new()?.path().join("backup"))
+                        .wait()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    // the pages of the old trees are reused instead of growing the file
+    let disk_size = store.stats().wait()?.disk_size.unwrap();
+    assert!(disk_size < 1024 * 1024, "file of {} bytes", disk_size);
+    Ok(())
+}
+
+// The memory engine should keep its keys without a directory, and one opened with a path
+// should load the snapshot written when it was dropped.
+#[test]
+fn memory_engine() -> Result<()> {
+    let store = MemoryKvsEngine::<RayonThreadPool>::new(2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_millis(0),
+        )
+        .wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    match store.remove("key2".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(store.stats().wait()?.disk_size, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    for i in 0..1000 {
+        store
+            .set(format!("key{:03}", i), format!("value{}", i))
+            .wait()?;
+    }
+    store.remove("key000".to_owned()).wait()?;
+    for name in &["users", "orders"] {
+        store.create_keyspace((*name).to_owned()).wait()?;
+        let space = store.keyspace(name)?;
+        space.set("key001".to_owned(), (*name).to_owned()).wait()?;
+    }
+    drop(store);
+
+    let store = MemoryKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    assert_eq!(store.get("key000".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key999".to_owned()).wait()?,
+        Some("value999".to_owned())
+    );
+    assert_eq!(store.stats().wait()?.keys, 999);
+    assert_eq!(
+        store.keyspace("users")?.get("key001".to_owned()).wait()?,
+        Some("users".to_owned())
+    );
+
+    // A keyspace dropped after the snapshot is not brought back
+    store.drop_keyspace("orders".to_owned()).wait()?;
+    let backup = temp_dir.path().join("backup");
+    store.checkpoint(backup.clone()).wait()?;
+    drop(store);
+    let store = MemoryKvsEngine::<RayonThreadPool>::open(&backup, 1)?;
+    assert_eq!(
+        store.list_keyspaces().wait()?,
+        vec![DEFAULT_KEYSPACE.to_owned(), "users".to_owned()]
+    );
+    assert_eq!(store.stats().wait()?.keys, 999);
+
+    Ok(())
+}
+
+// The memory engine should remove expired keys by itself and report their removal to
+// the watches, but keep the keys set again without an expiry time.
+#[test]
+fn memory_expiry_sweep() -> Result<()> {
+    let store = MemoryKvsEngine::<RayonThreadPool>::new(1)?;
+    let events = store.watch(b"key".to_vec());
+    for i in 0..3 {
+        store
+            .set_with_ttl(
+                format!("key{}", i).into_bytes(),
+                b"value".to_vec(),
+                Duration::from_millis(100),
+            )
+            .wait()?;
+    }
+    store.set("key1".to_owned(), "kept".to_owned()).wait()?;
+
+    let removed: Vec<WatchEvent> = events
+        .filter(|event| match event {
+            WatchEvent::Remove { .. } => true,
+            _ => false,
+        })
+        .take(2)
+        .collect()
+        .wait()?;
+    assert_eq!(
+        removed,
+        vec![
+            WatchEvent::Remove {
+                key: b"key0".to_vec(),
+            },
+            WatchEvent::Remove {
+                key: b"key2".to_vec(),
+            },
+        ]
+    );
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("kept".to_owned())
+    );
+
+    Ok(())
+}